@@ -0,0 +1,273 @@
+//! Join time and snapshot latency of a tree of simulated processes
+//!
+//! ```sh
+//! cargo run --release --example tree_bench -- [LEAVES] [SNAPSHOTS] [PLACEMENT] [ARITY]
+//! ```
+//!
+//! The benchmark is the root of the tree and launches LEAVES copies of itself
+//! (128 by default) standing for the processes of a job. They join the tree as
+//! snapped processes do but answer snapshots with a fixed set of stacks instead
+//! of driving a debugger, the root then takes SNAPSHOTS snapshots (20 by
+//! default). PLACEMENT (flat by default) and ARITY (24) shape the tree as
+//! `--placement` and `--arity` do, set `GDBW_UNIX_SOCKETS=0` to measure TCP.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::env;
+use std::process::{Child, Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use gdb_machine::auth;
+use gdb_machine::debugger::Debugger;
+use gdb_machine::metadata::{
+    BacktraceState, DebugFrame, ProcessOutput, ProgramSnapshot, RunState, SnapshotOptions,
+    SymbolTable, WatchKind,
+};
+use gdb_machine::topology::TopologyOptions;
+use gdb_machine::GdbMachine;
+
+/* Set by the root for the processes it launches */
+const ENV_ROOT_SERVER: &str = "GDBW_ROOT_SERVER";
+
+/// Threads of each simulated process
+const THREADS: u32 = 4;
+/// Frames of each stack
+const DEPTH: u32 = 24;
+/// Distinct innermost frames across the processes
+const GROUPS: u64 = 4;
+
+/// Process answering with made up stacks
+struct SimulatedProcess {
+    id: u64,
+}
+
+impl SimulatedProcess {
+    fn frame(level: u32, func: String) -> DebugFrame {
+        DebugFrame {
+            level,
+            addr: format!("0x{:x}", 0x401000 + level * 0x40),
+            func,
+            file: Some("solver.c".to_string()),
+            fullname: Some("/home/user/app/solver.c".to_string()),
+            line: Some(100 + level),
+            from: None,
+            arch: None,
+            args: None,
+            locals: None,
+        }
+    }
+
+    fn stack(&self, thread: u32) -> Vec<DebugFrame> {
+        let group = (std::process::id() as u64 + thread as u64) % GROUPS;
+
+        (0..DEPTH)
+            .map(|level| match level {
+                0 => SimulatedProcess::frame(level, format!("wait_{}", group)),
+                l => SimulatedProcess::frame(l, format!("solve_level_{}", DEPTH - l)),
+            })
+            .collect()
+    }
+}
+
+impl Debugger for SimulatedProcess {
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn set_id(&mut self, id: u64) {
+        self.id = id;
+    }
+
+    fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    fn count(&mut self) -> Result<u64> {
+        Ok(1)
+    }
+
+    fn start(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn cont(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn detach(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_breakpoint(&mut self, _location: &str) -> Result<u32> {
+        Ok(1)
+    }
+
+    fn set_watchpoint(&mut self, _expr: &str, _kind: WatchKind) -> Result<u32> {
+        Ok(1)
+    }
+
+    fn delete_breakpoint(&mut self, _num: u32) -> Result<()> {
+        Ok(())
+    }
+
+    fn state(&mut self) -> Result<HashMap<u64, RunState>> {
+        Ok(HashMap::from([(
+            self.id,
+            RunState::Running("running".to_string()),
+        )]))
+    }
+
+    fn snapshot(
+        &mut self,
+        opts: &SnapshotOptions,
+    ) -> Result<HashMap<u64, (u64, Vec<BacktraceState>)>> {
+        let snap = ProgramSnapshot {
+            state: (0..THREADS).map(|t| (t + 1, self.stack(t))).collect(),
+            stop_state: None,
+            inferior: None,
+            fault: None,
+            threads: HashMap::new(),
+        };
+
+        Ok(ProgramSnapshot::generate_components(
+            HashMap::from([(self.id, snap)]),
+            opts,
+        ))
+    }
+
+    fn symbols(&mut self) -> Result<SymbolTable> {
+        Ok(SymbolTable::default())
+    }
+
+    fn output(&mut self) -> Result<HashMap<u64, ProcessOutput>> {
+        Ok(HashMap::new())
+    }
+}
+
+/// Threads of this process (Linux only)
+fn thread_count() -> Option<u64> {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find_map(|l| l.strip_prefix("Threads:"))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Leaves killed when the benchmark ends
+struct Leaves(Vec<Child>);
+
+impl Drop for Leaves {
+    fn drop(&mut self) {
+        for c in self.0.iter_mut() {
+            let _ = c.kill();
+            let _ = c.wait();
+        }
+    }
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+fn run_root(leaves: usize, snapshots: usize, opts: TopologyOptions) -> Result<()> {
+    let (srv, mut root) = GdbMachine::run_as_root(&opts)?;
+
+    env::set_var(ENV_ROOT_SERVER, srv.url()?);
+    auth::export_token()?;
+
+    let exe = env::current_exe()?;
+
+    let start = Instant::now();
+
+    let _leaves = Leaves(
+        (0..leaves)
+            .map(|_| {
+                Command::new(&exe)
+                    .stdout(Stdio::null())
+                    .spawn()
+                    .map_err(|e| anyhow!("Failed to launch a leaf: {}", e))
+            })
+            .collect::<Result<_>>()?,
+    );
+
+    /* Placed processes are only part of the tree once their parent connected to them */
+    while srv.tree_count() != Some(leaves) || root.count()? != leaves as u64 {
+        sleep(Duration::from_millis(1));
+    }
+
+    let join = start.elapsed();
+
+    let snap_opts = SnapshotOptions::default();
+    let mut latencies = Vec::new();
+
+    for _ in 0..snapshots {
+        let start = Instant::now();
+        let snap = root.snapshot(&snap_opts)?;
+        latencies.push(start.elapsed());
+
+        let stacks: u64 = snap.values().map(|(cnt, _)| cnt).sum();
+
+        if stacks != leaves as u64 * THREADS as u64 {
+            return Err(anyhow!("Snapshot holds {} stacks", stacks));
+        }
+    }
+
+    latencies.sort();
+
+    let (depth, fan_out) = root.topology().map(|(_, shape)| shape).unwrap_or((0, 0));
+
+    println!(
+        "{} leaves ({:?} placement, depth {}, fan-out {})",
+        leaves, opts.placement, depth, fan_out
+    );
+    println!("join      {:>10.1} ms", millis(join));
+
+    if let (Some(min), Some(max)) = (latencies.first(), latencies.last()) {
+        println!(
+            "snapshot  {:>10.1} ms median ({:.1} min, {:.1} max over {})",
+            millis(latencies[latencies.len() / 2]),
+            millis(*min),
+            millis(*max),
+            latencies.len()
+        );
+    }
+
+    if let Some(threads) = thread_count() {
+        println!("root threads {:>7}", threads);
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+
+    if let Ok(root) = env::var(ENV_ROOT_SERVER) {
+        return GdbMachine::run_as_leaf_with_debugger(root, Box::new(SimulatedProcess { id: 0 }));
+    }
+
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let leaves = args.first().map(|a| a.parse()).transpose()?.unwrap_or(128);
+    let snapshots = args.get(1).map(|a| a.parse()).transpose()?.unwrap_or(20);
+
+    let mut opts = TopologyOptions {
+        placement: args.get(2).map(|a| a.as_str()).unwrap_or("flat").parse()?,
+        size: leaves as u64,
+        ..Default::default()
+    };
+
+    if let Some(arity) = args.get(3) {
+        opts.arity = arity.parse()?;
+    }
+
+    run_root(leaves, snapshots, opts)
+}
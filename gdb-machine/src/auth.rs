@@ -0,0 +1,80 @@
+//! Authentication of the nodes of a tree
+//!
+//! The root draws a random session token which is given to the processes it
+//! launches through the environment next to `GDBW_ROOT_SERVER`. When a link
+//! is set up both sides prove that they know the token by answering a random
+//! challenge of the other side with an HMAC-SHA256, the token itself never
+//! goes on the wire.
+
+use std::env;
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/* Token shared by all the nodes of a session */
+pub const ENV_TOKEN: &str = "GDBW_TOKEN";
+
+/// Size of tokens and challenges in bytes
+const TOKEN_SIZE: usize = 32;
+
+/* Proofs are bound to the side answering to avoid reflecting a challenge */
+pub(crate) const ROLE_CLIENT: &str = "client";
+pub(crate) const ROLE_SERVER: &str = "server";
+
+static TOKEN: OnceLock<String> = OnceLock::new();
+
+fn random_hex() -> Result<String> {
+    let mut bytes = [0; TOKEN_SIZE];
+    getrandom::fill(&mut bytes).map_err(|e| anyhow!("Failed to get random bytes: {}", e))?;
+
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Token of the session, taken from the environment
+/// or drawn at random on first use
+pub fn session_token() -> Result<&'static str> {
+    if let Some(token) = TOKEN.get() {
+        return Ok(token);
+    }
+
+    let token = match env::var(ENV_TOKEN) {
+        Ok(t) if !t.is_empty() => t,
+        _ => random_hex()?,
+    };
+
+    Ok(TOKEN.get_or_init(|| token))
+}
+
+/// Export the token for the processes launched by the root
+pub fn export_token() -> Result<()> {
+    env::set_var(ENV_TOKEN, session_token()?);
+    Ok(())
+}
+
+/// New challenge to be answered by a peer
+pub(crate) fn challenge() -> Result<String> {
+    random_hex()
+}
+
+fn mac(role: &str, challenge: &str) -> Result<Hmac<Sha256>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(session_token()?.as_bytes())?;
+    mac.update(role.as_bytes());
+    mac.update(b":");
+    mac.update(challenge.as_bytes());
+
+    Ok(mac)
+}
+
+/// Answer to a challenge proving the knowledge of the token
+pub(crate) fn proof(role: &str, challenge: &str) -> Result<Vec<u8>> {
+    Ok(mac(role, challenge)?.finalize().into_bytes().to_vec())
+}
+
+/// Check the answer of a peer to our challenge (in constant time)
+pub(crate) fn verify(role: &str, challenge: &str, proof: &[u8]) -> Result<()> {
+    mac(role, challenge)?
+        .verify_slice(proof)
+        .map_err(|_| anyhow!("Authentication failed, the peer does not share the session token ({} must be the one of the root)", ENV_TOKEN))
+}
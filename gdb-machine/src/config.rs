@@ -0,0 +1,311 @@
+//! Configuration of the underlying debugger
+//!
+//! The configuration is given on the command line of the root process and
+//! propagated to leaves through the environment as they are launched by the
+//! command run by the root (see [DebuggerConfig::export_env]).
+
+use std::env;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+const ENV_FOLLOW_FORK: &str = "GDBW_FOLLOW_FORK";
+const ENV_KEEP_FORKS: &str = "GDBW_KEEP_FORKS";
+const ENV_FOLLOW_EXEC: &str = "GDBW_FOLLOW_EXEC";
+const ENV_DEBUGGER: &str = "GDBW_DEBUGGER";
+const ENV_GDB: &str = "GDBW_GDB";
+const ENV_LLDB: &str = "GDBW_LLDB";
+const ENV_INIT_SCRIPT: &str = "GDBW_INIT_SCRIPT";
+const ENV_SYSROOT: &str = "GDBW_SYSROOT";
+const ENV_SOLIB_SEARCH_PATH: &str = "GDBW_SOLIB_SEARCH_PATH";
+const ENV_DEBUG_FILE_DIRECTORY: &str = "GDBW_DEBUG_FILE_DIRECTORY";
+/* Rules are given as FROM=TO separated by ; */
+const ENV_SUBSTITUTE_PATH: &str = "GDBW_SUBSTITUTE_PATH";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DebuggerConfig {
+    /// Process debugged after a fork (GDB `follow-fork-mode` parent or child)
+    pub follow_fork: Option<String>,
+    /// Keep debugging both processes after a fork (GDB `detach-on-fork off`)
+    pub keep_forks: bool,
+    /// Inferior used after an exec (GDB `follow-exec-mode` same or new)
+    pub follow_exec: Option<String>,
+    /// Debugger driving the processes (gdb by default or lldb)
+    pub debugger: Option<String>,
+    /// GDB executable (`gdb` from the `PATH` by default)
+    pub gdb: Option<String>,
+    /// LLDB executable (`lldb` from the `PATH` by default)
+    pub lldb: Option<String>,
+    /// Debugger script sourced at startup
+    pub init_script: Option<String>,
+    /// GDB `sysroot`
+    pub sysroot: Option<String>,
+    /// GDB `solib-search-path`
+    pub solib_search_path: Option<String>,
+    /// GDB `debug-file-directory`
+    pub debug_file_directory: Option<String>,
+    /// GDB `substitute-path` rules (from, to)
+    pub substitute_path: Vec<(String, String)>,
+}
+
+fn env_opt(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|v| !v.is_empty())
+}
+
+fn export_opt(name: &str, value: &Option<String>) {
+    if let Some(v) = value {
+        env::set_var(name, v);
+    }
+}
+
+/// Value of a setting as a single debugger command argument, quoted if it
+/// holds spaces or quotes, newlines would end the command and are refused
+fn setting_arg(value: &str) -> Result<String> {
+    if value.contains(['\n', '\r']) {
+        return Err(anyhow!(
+            "Debugger settings cannot contain newlines: {:?}",
+            value
+        ));
+    }
+
+    if !value.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        return Ok(value.to_string());
+    }
+
+    Ok(format!(
+        "\"{}\"",
+        value.replace('\\', "\\\\").replace('"', "\\\"")
+    ))
+}
+
+/// Parse a `FROM=TO` substitution rule
+pub fn parse_substitution(rule: &str) -> Option<(String, String)> {
+    rule.split_once('=')
+        .map(|(from, to)| (from.to_string(), to.to_string()))
+}
+
+impl DebuggerConfig {
+    /// Load the configuration exported by a root process
+    pub fn from_env() -> DebuggerConfig {
+        let substitute_path = env_opt(ENV_SUBSTITUTE_PATH)
+            .map(|v| v.split(';').filter_map(parse_substitution).collect())
+            .unwrap_or_default();
+
+        DebuggerConfig {
+            follow_fork: env_opt(ENV_FOLLOW_FORK),
+            keep_forks: env::var(ENV_KEEP_FORKS).is_ok_and(|v| v == "1"),
+            follow_exec: env_opt(ENV_FOLLOW_EXEC),
+            debugger: env_opt(ENV_DEBUGGER),
+            gdb: env_opt(ENV_GDB),
+            lldb: env_opt(ENV_LLDB),
+            init_script: env_opt(ENV_INIT_SCRIPT),
+            sysroot: env_opt(ENV_SYSROOT),
+            solib_search_path: env_opt(ENV_SOLIB_SEARCH_PATH),
+            debug_file_directory: env_opt(ENV_DEBUG_FILE_DIRECTORY),
+            substitute_path,
+        }
+    }
+
+    /// Export the configuration for the processes launched by the root
+    pub fn export_env(&self) {
+        export_opt(ENV_FOLLOW_FORK, &self.follow_fork);
+
+        if self.keep_forks {
+            env::set_var(ENV_KEEP_FORKS, "1");
+        }
+
+        export_opt(ENV_FOLLOW_EXEC, &self.follow_exec);
+        export_opt(ENV_DEBUGGER, &self.debugger);
+        export_opt(ENV_GDB, &self.gdb);
+        export_opt(ENV_LLDB, &self.lldb);
+        export_opt(ENV_INIT_SCRIPT, &self.init_script);
+        export_opt(ENV_SYSROOT, &self.sysroot);
+        export_opt(ENV_SOLIB_SEARCH_PATH, &self.solib_search_path);
+        export_opt(ENV_DEBUG_FILE_DIRECTORY, &self.debug_file_directory);
+
+        if !self.substitute_path.is_empty() {
+            let rules: Vec<String> = self
+                .substitute_path
+                .iter()
+                .map(|(from, to)| format!("{}={}", from, to))
+                .collect();
+            env::set_var(ENV_SUBSTITUTE_PATH, rules.join(";"));
+        }
+    }
+
+    /// Are processes driven with LLDB instead of GDB
+    pub fn use_lldb(&self) -> bool {
+        self.debugger.as_deref() == Some("lldb")
+    }
+
+    /// GDB executable to launch
+    pub(crate) fn gdb_path(&self) -> String {
+        self.gdb.clone().unwrap_or("gdb".to_string())
+    }
+
+    /// LLDB executable to launch
+    pub(crate) fn lldb_path(&self) -> String {
+        self.lldb.clone().unwrap_or("lldb".to_string())
+    }
+
+    /// GDB command line arguments matching this configuration
+    ///
+    /// Search paths are set before the program is loaded for
+    /// its symbols and debug information to be found
+    pub(crate) fn gdb_args(&self) -> Result<Vec<String>> {
+        let mut settings = Vec::new();
+
+        if let Some(path) = &self.sysroot {
+            settings.push(format!("set sysroot {}", setting_arg(path)?));
+        }
+
+        if let Some(path) = &self.solib_search_path {
+            settings.push(format!("set solib-search-path {}", setting_arg(path)?));
+        }
+
+        if let Some(path) = &self.debug_file_directory {
+            settings.push(format!("set debug-file-directory {}", setting_arg(path)?));
+        }
+
+        for (from, to) in self.substitute_path.iter() {
+            settings.push(format!(
+                "set substitute-path {} {}",
+                setting_arg(from)?,
+                setting_arg(to)?
+            ));
+        }
+
+        let mut ret = Vec::new();
+
+        for s in settings {
+            ret.push("-iex".to_string());
+            ret.push(s);
+        }
+
+        if let Some(script) = &self.init_script {
+            ret.push("-x".to_string());
+            ret.push(script.clone());
+        }
+
+        Ok(ret)
+    }
+
+    /// LLDB command line arguments matching this configuration
+    ///
+    /// As for GDB search paths are set before the program is loaded,
+    /// forks are followed but LLDB keeps a single process
+    pub(crate) fn lldb_args(&self) -> Result<Vec<String>> {
+        let mut settings = Vec::new();
+
+        if let Some(path) = &self.sysroot {
+            settings.push(format!(
+                "platform select --sysroot {} host",
+                setting_arg(path)?
+            ));
+        }
+
+        if let Some(paths) = &self.solib_search_path {
+            for path in paths.split(':').filter(|p| !p.is_empty()) {
+                settings.push(format!(
+                    "settings append target.exec-search-paths {}",
+                    setting_arg(path)?
+                ));
+            }
+        }
+
+        if let Some(path) = &self.debug_file_directory {
+            settings.push(format!(
+                "settings append target.debug-file-search-paths {}",
+                setting_arg(path)?
+            ));
+        }
+
+        for (from, to) in self.substitute_path.iter() {
+            settings.push(format!(
+                "settings append target.source-map {} {}",
+                setting_arg(from)?,
+                setting_arg(to)?
+            ));
+        }
+
+        if let Some(mode) = &self.follow_fork {
+            settings.push(format!(
+                "settings set target.process.follow-fork-mode {}",
+                mode
+            ));
+        }
+
+        let mut ret = vec!["--no-use-colors".to_string()];
+
+        for s in settings {
+            ret.push("-O".to_string());
+            ret.push(s);
+        }
+
+        if let Some(script) = &self.init_script {
+            ret.push("-s".to_string());
+            ret.push(script.clone());
+        }
+
+        Ok(ret)
+    }
+
+    /// GDB settings matching this configuration
+    pub(crate) fn gdb_settings(&self) -> Vec<String> {
+        let mut ret = Vec::new();
+
+        if let Some(mode) = &self.follow_fork {
+            ret.push(format!("follow-fork-mode {}", mode));
+        }
+
+        if self.keep_forks {
+            ret.push("detach-on-fork off".to_string());
+            /* Resume all inferiors and not only the current one */
+            ret.push("schedule-multiple on".to_string());
+        }
+
+        if let Some(mode) = &self.follow_exec {
+            ret.push(format!("follow-exec-mode {}", mode));
+        }
+
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_search_paths() {
+        let config = DebuggerConfig {
+            sysroot: Some("/opt/cross root".to_string()),
+            debug_file_directory: Some("/usr/lib/debug".to_string()),
+            substitute_path: vec![("/build/a \"b\"".to_string(), "/src".to_string())],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            config.gdb_args().unwrap(),
+            vec![
+                "-iex",
+                "set sysroot \"/opt/cross root\"",
+                "-iex",
+                "set debug-file-directory /usr/lib/debug",
+                "-iex",
+                "set substitute-path \"/build/a \\\"b\\\"\" /src",
+            ]
+        );
+    }
+
+    #[test]
+    fn newlines_are_refused() {
+        let config = DebuggerConfig {
+            sysroot: Some("/opt\nshell id".to_string()),
+            ..Default::default()
+        };
+
+        assert!(config.gdb_args().is_err());
+        assert!(config.lldb_args().is_err());
+    }
+}
@@ -5,10 +5,37 @@ use std::{
 };
 
 use crate::{
-    metadata::{BacktraceState, RunState, SymbolTable},
+    metadata::{
+        BacktraceState, ProcessOutput, ProgramSnapshot, RunState, SnapshotOptions, SymbolTable,
+        WatchKind, INFERIOR_ID_SLOTS,
+    },
     TreeState,
 };
 use anyhow::{anyhow, Result};
+use rayon::prelude::*;
+use regex::Regex;
+
+/// Check that debuggers agree on the number of a breakpoint
+///
+/// Debuggers number breakpoints in sequence, setting the same breakpoints
+/// in the same order everywhere thus leads to the same numbers. A number
+/// of 0 is returned by debuggers without any process and is ignored.
+pub(crate) fn merge_breakpoint_numbers(nums: impl IntoIterator<Item = u32>) -> Result<u32> {
+    let mut ret = 0;
+
+    for n in nums.into_iter().filter(|n| *n != 0) {
+        if ret != 0 && ret != n {
+            return Err(anyhow!(
+                "Breakpoint numbers diverged between debuggers ({} and {})",
+                ret,
+                n
+            ));
+        }
+        ret = n;
+    }
+
+    Ok(ret)
+}
 
 pub trait Debugger: Send + Any {
     /// Number of attached debuggers
@@ -26,19 +53,26 @@ pub trait Debugger: Send + Any {
     fn stop(&mut self) -> Result<()>;
     /// Continue a stopped program
     fn cont(&mut self) -> Result<()>;
+    /// Detach from the program leaving it running
+    fn detach(&mut self) -> Result<()>;
+
+    /// Set a breakpoint at `location` (as understood by GDB) returns its number
+    fn set_breakpoint(&mut self, location: &str) -> Result<u32>;
+    /// Set a watchpoint on `expr` returns its number
+    fn set_watchpoint(&mut self, expr: &str, kind: WatchKind) -> Result<u32>;
+    /// Delete a breakpoint or a watchpoint given its number
+    fn delete_breakpoint(&mut self, num: u32) -> Result<()>;
 
     /// Get current state of program
     fn state(&mut self) -> Result<HashMap<u64, RunState>>;
 
-    /// Check if the given `id` has exited (program terminated)
+    /// Check if the given `id` has exited (program terminated or debugger died)
     fn id_is_exited(&mut self, id: u64) -> Result<bool> {
         let st = self.state()?;
 
         if let Some(st) = st.get(&id) {
-            if let RunState::Stopped(st) = st {
-                if st.exited() {
-                    return Ok(true);
-                }
+            if st.exited() {
+                return Ok(true);
             }
         }
 
@@ -80,15 +114,7 @@ pub trait Debugger: Send + Any {
         Ok(self
             .state()?
             .iter()
-            .map(|(k, v)| {
-                let running = if let RunState::Stopped(st) = v {
-                    st.exited()
-                } else {
-                    false
-                };
-
-                (*k, running)
-            })
+            .map(|(k, v)| (*k, v.exited()))
             .collect())
     }
 
@@ -140,11 +166,23 @@ pub trait Debugger: Send + Any {
     }
 
     /// Snapshot a stopped program
-    fn snapshot(&mut self) -> Result<HashMap<u64, (u64, Vec<BacktraceState>)>>;
+    fn snapshot(
+        &mut self,
+        opts: &SnapshotOptions,
+    ) -> Result<HashMap<u64, (u64, Vec<BacktraceState>)>>;
 
     /// Get Symbol table
     fn symbols(&mut self) -> Result<SymbolTable>;
 
+    /// Functions with a name matching a regex
+    fn find_functions(&mut self, pattern: &str) -> Result<SymbolTable> {
+        let re = Regex::new(pattern)?;
+        Ok(self.symbols()?.filter(&re))
+    }
+
+    /// Last output lines of each process
+    fn output(&mut self) -> Result<HashMap<u64, ProcessOutput>>;
+
     fn as_any_mut(&mut self) -> &mut dyn Any;
 
     // New method to downcast to TreeState
@@ -179,6 +217,25 @@ impl Debugger for DummyDebugger {
     fn cont(&mut self) -> Result<()> {
         Err(anyhow!("Dummy debugger"))
     }
+    /// Detach from the program
+    fn detach(&mut self) -> Result<()> {
+        Err(anyhow!("Dummy debugger"))
+    }
+
+    #[allow(unused)]
+    fn set_breakpoint(&mut self, location: &str) -> Result<u32> {
+        Err(anyhow!("Dummy debugger"))
+    }
+
+    #[allow(unused)]
+    fn set_watchpoint(&mut self, expr: &str, kind: WatchKind) -> Result<u32> {
+        Err(anyhow!("Dummy debugger"))
+    }
+
+    #[allow(unused)]
+    fn delete_breakpoint(&mut self, num: u32) -> Result<()> {
+        Err(anyhow!("Dummy debugger"))
+    }
 
     /// Get current state of program
     fn state(&mut self) -> Result<HashMap<u64, RunState>> {
@@ -186,13 +243,21 @@ impl Debugger for DummyDebugger {
     }
 
     /// Snapshot a stopped program
-    fn snapshot(&mut self) -> Result<HashMap<u64, (u64, Vec<BacktraceState>)>> {
+    #[allow(unused)]
+    fn snapshot(
+        &mut self,
+        opts: &SnapshotOptions,
+    ) -> Result<HashMap<u64, (u64, Vec<BacktraceState>)>> {
         Ok(HashMap::new())
     }
 
     /// Get Symbol table
     fn symbols(&mut self) -> Result<SymbolTable> {
-        Err(anyhow!("Dummy debugger"))
+        Ok(SymbolTable::default())
+    }
+
+    fn output(&mut self) -> Result<HashMap<u64, ProcessOutput>> {
+        Ok(HashMap::new())
     }
 
     fn as_any_mut(&mut self) -> &mut dyn Any {
@@ -206,3 +271,143 @@ impl DummyDebugger {
         dbg
     }
 }
+
+/// A set of local debuggers driven as a single one
+///
+/// This is used to debug several processes from the same snapped instance
+/// (for example when attaching to a list of PIDs) each member is given its
+/// index as debugger ID, spaced to leave room for the member inferiors.
+pub struct DebuggerGroup {
+    id: u64,
+    members: Vec<Box<dyn Debugger>>,
+}
+
+impl DebuggerGroup {
+    pub fn new(mut members: Vec<Box<dyn Debugger>>) -> DebuggerGroup {
+        for (i, m) in members.iter_mut().enumerate() {
+            m.set_id(i as u64 * INFERIOR_ID_SLOTS);
+        }
+
+        DebuggerGroup { id: 0, members }
+    }
+
+    fn on_members(
+        &mut self,
+        f: impl Fn(&mut Box<dyn Debugger>) -> Result<()> + Sync + Send,
+    ) -> Result<()> {
+        let errs: Vec<String> = self
+            .members
+            .par_iter_mut()
+            .map(f)
+            .filter_map(|v| v.err())
+            .map(|e| e.to_string())
+            .collect();
+
+        if !errs.is_empty() {
+            return Err(anyhow!("{}", errs.join(",")));
+        }
+
+        Ok(())
+    }
+}
+
+impl Debugger for DebuggerGroup {
+    fn count(&mut self) -> Result<u64> {
+        Ok(self.members.len() as u64)
+    }
+
+    fn set_id(&mut self, id: u64) {
+        self.id = id;
+    }
+
+    fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    fn start(&mut self) -> Result<()> {
+        self.on_members(|m| m.start())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.on_members(|m| m.stop())
+    }
+
+    fn cont(&mut self) -> Result<()> {
+        self.on_members(|m| m.cont())
+    }
+
+    fn detach(&mut self) -> Result<()> {
+        self.on_members(|m| m.detach())
+    }
+
+    fn set_breakpoint(&mut self, location: &str) -> Result<u32> {
+        let nums = self
+            .members
+            .par_iter_mut()
+            .map(|m| m.set_breakpoint(location))
+            .collect::<Result<Vec<_>>>()?;
+
+        merge_breakpoint_numbers(nums)
+    }
+
+    fn set_watchpoint(&mut self, expr: &str, kind: WatchKind) -> Result<u32> {
+        let nums = self
+            .members
+            .par_iter_mut()
+            .map(|m| m.set_watchpoint(expr, kind))
+            .collect::<Result<Vec<_>>>()?;
+
+        merge_breakpoint_numbers(nums)
+    }
+
+    fn delete_breakpoint(&mut self, num: u32) -> Result<()> {
+        self.on_members(|m| m.delete_breakpoint(num))
+    }
+
+    fn state(&mut self) -> Result<HashMap<u64, RunState>> {
+        let mut ret = HashMap::new();
+
+        for m in self.members.iter_mut() {
+            ret.extend(m.state()?);
+        }
+
+        Ok(ret)
+    }
+
+    fn snapshot(
+        &mut self,
+        opts: &SnapshotOptions,
+    ) -> Result<HashMap<u64, (u64, Vec<BacktraceState>)>> {
+        let components = self
+            .members
+            .par_iter_mut()
+            .map(|m| m.snapshot(opts))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(ProgramSnapshot::components_merge(components))
+    }
+
+    fn symbols(&mut self) -> Result<SymbolTable> {
+        let tables = self
+            .members
+            .par_iter_mut()
+            .map(|m| m.symbols())
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(SymbolTable::merge_all(tables))
+    }
+
+    fn output(&mut self) -> Result<HashMap<u64, ProcessOutput>> {
+        let mut ret = HashMap::new();
+
+        for m in self.members.iter_mut() {
+            ret.extend(m.output()?);
+        }
+
+        Ok(ret)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
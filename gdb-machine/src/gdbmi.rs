@@ -1,24 +1,66 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use regex::Regex;
 use serde::Deserialize;
 use std::any::Any;
 use std::collections::HashMap;
 use std::io::{BufRead, BufReader, Write};
 use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread::JoinHandle;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+use crate::config::DebuggerConfig;
 use crate::debugger::Debugger;
 use crate::metadata::*;
-use crate::tools::*;
+use crate::mi::*;
+use crate::tools::{parse_address, truncate_value};
+use crate::tty::InferiorTty;
+
+/// Default time to wait for GDB to respond to a command
+const GDB_COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+/// Time to wait for commands loading targets or symbols
+const GDB_LOAD_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Registers kept in a fault context, general purpose registers come first
+const MAX_REGISTERS: usize = 34;
+/// Instructions kept before and after the PC in a fault context
+const DISASSEMBLY_WINDOW: usize = 6;
+/// Commands sent to GDB before waiting for their responses, bounded
+/// for GDB not to block on a full output pipe while we write
+const PIPELINE_DEPTH: usize = 64;
+
+/* Set to 1 to wait for each response before sending the next command */
+const ENV_PIPELINE_DEPTH: &str = "GDBW_PIPELINE_DEPTH";
+
+fn pipeline_depth() -> usize {
+    std::env::var(ENV_PIPELINE_DEPTH)
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|d| *d > 0)
+        .unwrap_or(PIPELINE_DEPTH)
+}
+
+/// Inferior number from a GDB thread group id (`i1` is inferior 1)
+fn inferior_num(group: &str) -> u32 {
+    group
+        .strip_prefix('i')
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(1)
+}
 
-enum GdbMiRemote {
+/// Describes what a [GdbMi] instance is debugging
+#[derive(Debug, Clone)]
+pub enum GdbMiRemote {
+    /// Launch the given command under GDB
     Command(Vec<String>),
-    #[allow(unused)]
-    Server(String, u32),
-    #[allow(unused)]
+    /// Connect to a remote gdbserver (host, port, program run
+    /// by a `--multi` gdbserver holding no process)
+    Server(String, u32, Option<Vec<String>>),
+    /// Attach to an already running process
     Attach(u32),
+    /// Post-mortem analysis of a core file (executable, core)
+    Core(String, String),
 }
 
 impl GdbMiRemote {
@@ -32,8 +74,8 @@ impl GdbMiRemote {
                 ret.push("--args".to_string());
                 ret.append(&mut cmd.clone());
             }
-            GdbMiRemote::Server(_, _) => todo!(),
-            GdbMiRemote::Attach(_) => todo!(),
+            /* Remote, attach and cores are loaded with MI commands once configured */
+            GdbMiRemote::Server(..) | GdbMiRemote::Attach(_) | GdbMiRemote::Core(_, _) => {}
         }
 
         ret
@@ -41,41 +83,43 @@ impl GdbMiRemote {
 }
 
 enum GdbMiCommandResponse {
-    Done(String),
+    Done(MiValue),
     Error(String),
     ParseError(String),
 }
 
 impl GdbMiCommandResponse {
-    fn new(line: &str) -> GdbMiCommandResponse {
-        if let Some(error) = line.strip_prefix("error") {
-            return GdbMiCommandResponse::Error(error.to_string());
-        }
-
-        if let Some(resp) = line.strip_prefix("done") {
-            return GdbMiCommandResponse::Done(resp.to_string());
-        }
-
-        if let Some(resp) = line.strip_prefix("running") {
-            return GdbMiCommandResponse::Done(resp.to_string());
+    fn new(record: MiRecord) -> GdbMiCommandResponse {
+        match record.class.as_str() {
+            "done" | "running" | "connected" | "exit" => GdbMiCommandResponse::Done(record.results),
+            "error" => GdbMiCommandResponse::Error(
+                record
+                    .results
+                    .get_str("msg")
+                    .unwrap_or_default()
+                    .to_string(),
+            ),
+            other => GdbMiCommandResponse::ParseError(other.to_string()),
         }
-
-        GdbMiCommandResponse::ParseError(line.to_string())
     }
 }
 
 impl RunState {
-    fn new_from_gdb(resp: &str) -> Result<RunState> {
-        if let Some(runctx) = resp.strip_prefix("running") {
-            return Ok(RunState::Running(runctx.to_string()));
-        }
-
-        if let Some(stopctx) = resp.strip_prefix("stopped") {
-            let stop_reason = StopReason::new(stopctx)?;
-            return Ok(RunState::Stopped(Box::new(stop_reason)));
+    fn new_from_gdb(record: &MiRecord) -> Result<RunState> {
+        match record.class.as_str() {
+            "running" => Ok(RunState::Running(
+                record
+                    .results
+                    .get_str("thread-id")
+                    .unwrap_or_default()
+                    .to_string(),
+            )),
+            "stopped" => {
+                let stop_reason = StopReason::new(&record.results)?;
+                Ok(RunState::Stopped(Box::new(stop_reason)))
+            }
+            other => Err(anyhow!("No such gdb run state: {}", other)),
         }
-
-        Err(anyhow!("No such gdb run state: {}", resp))
     }
 }
 
@@ -119,16 +163,65 @@ pub struct GdbMiState {
     response_id: u64,
     thread_stdout: Option<JoinHandle<Result<()>>>,
     gdb_in: ChildStdin,
-    running: bool,
     gdblog: Vec<String>,
-    resps: HashMap<u64, GdbMiCommandResponse>,
+    /// Commands waiting for a response indexed by token
+    pending: HashMap<u64, Sender<GdbMiCommandResponse>>,
     runstate: Option<RunState>,
+    /// Signaled when `runstate` changes
+    runstate_changed: Arc<Condvar>,
+    /// Time to wait for a response to a command
+    timeout: Duration,
+    /// Live inferiors (thread group id to pid)
+    inferiors: HashMap<String, Option<u32>>,
+    /// Inferiors which exited (thread group id to exit code)
+    exited_inferiors: HashMap<String, Option<i32>>,
+    /// Live threads (thread id to thread group id)
+    threads: HashMap<u32, String>,
+    /// Print what is not MI (inferior output when it has no terminal)
+    passthrough: bool,
 }
 
 impl GdbMiState {
+    /// Are several inferiors debugged (after following forks or execs)
+    fn multi_inferior(&self) -> bool {
+        self.inferiors.len() + self.exited_inferiors.len() > 1
+    }
+
+    /// State of the debugger as a whole, the exit of an
+    /// inferior leaves the others stopped
+    fn global_runstate(&self) -> Option<RunState> {
+        match &self.runstate {
+            Some(RunState::Stopped(st)) if st.exited() && !self.inferiors.is_empty() => {
+                Some(RunState::Stopped(Box::new(StopReason::stopped())))
+            }
+            other => other.clone(),
+        }
+    }
+
+    /// State of each inferior by thread group id
+    fn inferior_runstates(&self) -> HashMap<String, RunState> {
+        let mut ret = HashMap::new();
+
+        if let Some(global) = self.global_runstate() {
+            for group in self.inferiors.keys() {
+                ret.insert(group.clone(), global.clone());
+            }
+
+            for (group, code) in self.exited_inferiors.iter() {
+                let rs = match &global {
+                    RunState::Dead(_) => global.clone(),
+                    _ => RunState::Stopped(Box::new(StopReason::inferior_exited(*code))),
+                };
+                ret.insert(group.clone(), rs);
+            }
+        }
+
+        ret
+    }
+
     fn get_stop_state(st: &Arc<Mutex<GdbMiState>>) -> Option<StopReason> {
         if let Ok(ls) = st.lock().as_mut() {
-            if let Some(RunState::Stopped(st)) = ls.runstate.clone() {
+            if let Some(RunState::Stopped(st)) = ls.global_runstate() {
                 return Some(*st);
             }
         }
@@ -147,7 +240,10 @@ impl GdbMiState {
 
     fn pushresp(state: Arc<Mutex<GdbMiState>>, id: u64, resp: GdbMiCommandResponse) -> Result<()> {
         if let Ok(ls) = state.lock().as_mut() {
-            ls.resps.insert(id, resp);
+            /* The command may have timed out in the meantime */
+            if let Some(tx) = ls.pending.remove(&id) {
+                let _ = tx.send(resp);
+            }
             return Ok(());
         }
 
@@ -164,61 +260,176 @@ impl GdbMiState {
         Err(anyhow!("Failed to lock"))
     }
 
-    fn isrunning(state: Arc<Mutex<GdbMiState>>) -> Result<bool> {
-        if let Ok(ls) = state.lock() {
-            if let Some(RunState::Stopped(stop_state)) = &ls.runstate {
-                /* Program did exit */
-                if stop_state.exited() {
-                    return Ok(false);
-                }
+    fn get_dead_reason(st: &Arc<Mutex<GdbMiState>>) -> Option<String> {
+        if let Ok(ls) = st.lock() {
+            if let Some(RunState::Dead(reason)) = &ls.runstate {
+                return Some(reason.clone());
             }
+        }
 
-            return Ok(ls.running);
+        None
+    }
+
+    /// Mark GDB as dead, commands waiting for a response are failed
+    fn setdead(state: Arc<Mutex<GdbMiState>>, reason: &str) -> Result<()> {
+        if let Ok(ls) = state.lock().as_mut() {
+            log::error!("GDB died: {}", reason);
+            ls.runstate = Some(RunState::Dead(reason.to_string()));
+            ls.runstate_changed.notify_all();
+            /* Dropping senders wakes up the waiters */
+            ls.pending.clear();
+            return Ok(());
         }
 
         Err(anyhow!("Failed to lock"))
     }
 
+    /// Track inferiors and threads from `=` notifications
+    fn notify(state: Arc<Mutex<GdbMiState>>, record: &MiRecord) -> Result<()> {
+        let res = &record.results;
+
+        if let Ok(ls) = state.lock().as_mut() {
+            match record.class.as_str() {
+                "thread-group-started" => {
+                    if let Some(id) = res.get_str("id") {
+                        ls.inferiors
+                            .insert(id.to_string(), res.get_parsed::<u32>("pid"));
+                        ls.exited_inferiors.remove(id);
+                    }
+                }
+                "thread-group-exited" => {
+                    if let Some(id) = res.get_str("id") {
+                        ls.inferiors.remove(id);
+                        ls.threads.retain(|_, group| group != id);
+                        ls.exited_inferiors
+                            .insert(id.to_string(), res.get_parsed::<i32>("exit-code"));
+                    }
+                }
+                "thread-created" => {
+                    if let (Some(id), Some(group)) =
+                        (res.get_parsed::<u32>("id"), res.get_str("group-id"))
+                    {
+                        ls.threads.insert(id, group.to_string());
+                    }
+                }
+                "thread-exited" => {
+                    if let Some(id) = res.get_parsed::<u32>("id") {
+                        ls.threads.remove(&id);
+                    }
+                }
+                "library-loaded" => {
+                    if let Some(name) = res.get_str("target-name").or(res.get_str("id")) {
+                        log::debug!("Library loaded: {}", name);
+                    }
+                }
+                _ => { /* SKIPPED */ }
+            }
+            return Ok(());
+        }
+
+        Err(anyhow!("Failed to lock"))
+    }
+
+    fn passthrough(state: Arc<Mutex<GdbMiState>>) -> bool {
+        match state.lock() {
+            Ok(ls) => ls.passthrough,
+            Err(_) => true,
+        }
+    }
+
     fn setrunstate(state: Arc<Mutex<GdbMiState>>, runstate: RunState) -> Result<()> {
         if let Ok(ls) = state.lock().as_mut() {
             ls.runstate = Some(runstate);
+            ls.runstate_changed.notify_all();
             return Ok(());
         }
 
         Err(anyhow!("Failed to lock"))
     }
 
+    /// Wait up to `timeout` for the target to be stopped (or exited)
+    fn wait_stopped(state: Arc<Mutex<GdbMiState>>, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut ls = state.lock().map_err(|_| anyhow!("Failed to lock"))?;
+        let changed = ls.runstate_changed.clone();
+
+        loop {
+            match ls.global_runstate() {
+                Some(RunState::Running(_)) | None => {}
+                Some(RunState::Dead(reason)) => return Err(anyhow!("GDB died: {}", reason)),
+                Some(RunState::Stopped(_)) => return Ok(()),
+            }
+
+            let left = deadline.saturating_duration_since(Instant::now());
+
+            if left.is_zero() {
+                return Err(anyhow!(
+                    "The program did not stop within {:.1}s",
+                    timeout.as_secs_f64()
+                ));
+            }
+
+            ls = changed
+                .wait_timeout(ls, left)
+                .map_err(|_| anyhow!("Failed to lock"))?
+                .0;
+        }
+    }
+
     fn thread_loop<T: std::io::Read>(state: Arc<Mutex<GdbMiState>>, gdb_out: T) -> Result<()> {
         let mut output = BufReader::new(gdb_out);
-        let mut line = String::new();
+        let mut buf = Vec::new();
+
+        /* GDB still answers once the inferiors exited, only its end stops the loop */
+        loop {
+            match output.read_until(b'\n', &mut buf) {
+                Ok(0) => return GdbMiState::setdead(state, "GDB exited"),
+                Ok(_) => {}
+                Err(e) => {
+                    GdbMiState::setdead(state, &format!("Failed to read GDB output: {}", e))?;
+                    return Err(anyhow!(e));
+                }
+            }
 
-        while GdbMiState::isrunning(state.clone())? {
-            output.read_line(&mut line)?;
+            /* The inferior may write anything through GDB */
+            let line = String::from_utf8_lossy(&buf);
 
             log::trace!("OUTPUT {}", line);
 
-            if let Some(log) = line.strip_prefix("~") {
-                GdbMiState::pushlog(state.clone(), log)?;
-            } else if let Some((id, resp)) = parse_response_with_token("\\^", &line) {
-                GdbMiState::pushresp(state.clone(), id, GdbMiCommandResponse::new(resp.as_str()))?;
-            } else if let Some(srstate) = line.strip_prefix("*") {
-                let rstate = RunState::new_from_gdb(srstate)?;
-                GdbMiState::setrunstate(state.clone(), rstate)?;
-            } else if let Some(_) = line.strip_prefix("~") {
-                /* SKIPPED */
-            } else if let Some(_) = line.strip_prefix("=") {
-                /* SKIPPED */
-            } else if let Some(_) = line.strip_prefix("&") {
-                /* SKIPPED */
-            } else if line.starts_with("(gdb)") {
-                /* SKIPPED */
-            } else if line.starts_with("\u{1b}[H") {
-                /* SKIPPED : not sure what is this one ? */
-            } else {
-                print!("{}", line);
-            }
-
-            line.clear();
+            if let Err(e) = GdbMiState::process_line(state.clone(), &line) {
+                log::warn!("Failed to process GDB output {}: {}", line.trim_end(), e);
+            }
+
+            buf.clear();
+        }
+    }
+
+    fn process_line(state: Arc<Mutex<GdbMiState>>, line: &str) -> Result<()> {
+        match parse_line(line) {
+            MiOutput::Console(log) => {
+                GdbMiState::pushlog(state, &log)?;
+            }
+            MiOutput::Record(record) => match (record.kind, record.token) {
+                (MiRecordKind::Result, Some(id)) => {
+                    GdbMiState::pushresp(state, id, GdbMiCommandResponse::new(record))?;
+                }
+                (MiRecordKind::Exec, _) => {
+                    let rstate = RunState::new_from_gdb(&record)?;
+                    GdbMiState::setrunstate(state, rstate)?;
+                }
+                (MiRecordKind::Notify, _) => {
+                    GdbMiState::notify(state, &record)?;
+                }
+                _ => { /* SKIPPED */ }
+            },
+            MiOutput::Target(_) | MiOutput::Log(_) | MiOutput::Prompt => { /* SKIPPED */ }
+            MiOutput::Other(other) => {
+                if GdbMiState::passthrough(state) && !other.starts_with("\u{1b}[H") {
+                    print!("{}", other);
+                } else {
+                    log::debug!("GDB: {}", other.trim_end());
+                }
+            }
         }
 
         Ok(())
@@ -227,30 +438,47 @@ impl GdbMiState {
     fn await_response(
         state: Arc<Mutex<GdbMiState>>,
         id: u64,
-        timeout_ms: u128,
+        resp: Receiver<GdbMiCommandResponse>,
+        timeout: Duration,
     ) -> Result<GdbMiCommandResponse> {
-        let start_time = Instant::now();
-
-        loop {
-            if let Ok(ls) = state.lock().as_mut() {
-                if let Some(resp) = ls.resps.remove(&id) {
-                    return Ok(resp);
+        match resp.recv_timeout(timeout) {
+            Ok(resp) => Ok(resp),
+            Err(RecvTimeoutError::Timeout) => {
+                /* Late responses are dropped */
+                if let Ok(ls) = state.lock().as_mut() {
+                    ls.pending.remove(&id);
                 }
+                Err(anyhow!(
+                    "Timeout after {} seconds waiting for response",
+                    timeout.as_secs_f64()
+                ))
             }
-
-            if timeout_ms != 0 && start_time.elapsed().as_millis() > timeout_ms {
-                return Err(anyhow!("Timeout waiting for response"));
-            }
+            Err(RecvTimeoutError::Disconnected) => Err(anyhow!("GDB died before responding")),
         }
     }
 
-    fn _send_command(state: Arc<Mutex<GdbMiState>>, command: &str) -> Result<u64> {
+    fn _send_command(
+        state: Arc<Mutex<GdbMiState>>,
+        command: &str,
+    ) -> Result<(u64, Receiver<GdbMiCommandResponse>)> {
         if let Ok(st) = state.lock().as_mut() {
+            if let Some(RunState::Dead(reason)) = &st.runstate {
+                return Err(anyhow!("GDB is dead: {}", reason));
+            }
+
             let id = st.response_id;
             st.response_id += 1;
+
+            let (tx, rx) = channel();
+            st.pending.insert(id, tx);
+
             let cmd = format!("{}{}\n", id, command);
-            st.gdb_in.write_all(cmd.as_bytes())?;
-            return Ok(id);
+            if let Err(e) = st.gdb_in.write_all(cmd.as_bytes()) {
+                st.pending.remove(&id);
+                return Err(anyhow!(e));
+            }
+
+            return Ok((id, rx));
         }
 
         Err(anyhow!("Failed to lock"))
@@ -259,56 +487,159 @@ impl GdbMiState {
     fn _run_command(
         state: Arc<Mutex<GdbMiState>>,
         command: &str,
-        timeout_ms: u128,
+        timeout: Duration,
     ) -> Result<GdbMiCommandResponse> {
-        let id = GdbMiState::_send_command(state.clone(), command)?;
-        let resp = GdbMiState::await_response(state, id, timeout_ms)?;
+        let (id, rx) = GdbMiState::_send_command(state.clone(), command)?;
+        let resp = GdbMiState::await_response(state, id, rx, timeout)?;
         Ok(resp)
     }
 
-    fn command(state: Arc<Mutex<GdbMiState>>, command: &str) -> Result<String> {
-        match GdbMiState::_run_command(state, command, 0)? {
+    fn response_value(resp: GdbMiCommandResponse) -> Result<MiValue> {
+        match resp {
             GdbMiCommandResponse::Done(s) => Ok(s),
             GdbMiCommandResponse::Error(e) => Err(anyhow!("Command returned an error : {}", e)),
             GdbMiCommandResponse::ParseError(e) => Err(anyhow!("Failed to parse response : {}", e)),
         }
     }
 
-    fn list_thread_id(state: Arc<Mutex<GdbMiState>>) -> Result<Vec<u32>> {
-        let resp = GdbMiState::command(state, "-thread-list-ids")?;
+    fn command_timeout(
+        state: Arc<Mutex<GdbMiState>>,
+        command: &str,
+        timeout: Duration,
+    ) -> Result<MiValue> {
+        GdbMiState::response_value(
+            GdbMiState::_run_command(state, command, timeout)
+                .with_context(|| format!("Running {}", command))?,
+        )
+    }
+
+    /// Run independent commands with several of them in flight
+    ///
+    /// Results are given in the order of the commands, a failing
+    /// command does not prevent the others from running
+    fn commands(state: Arc<Mutex<GdbMiState>>, commands: &[String]) -> Vec<Result<MiValue>> {
+        let timeout = match state.lock() {
+            Ok(st) => st.timeout,
+            Err(_) => {
+                return commands
+                    .iter()
+                    .map(|_| Err(anyhow!("Failed to lock")))
+                    .collect()
+            }
+        };
+
+        let mut ret = Vec::with_capacity(commands.len());
 
-        let re = Regex::new("[,\\{]thread-id=\"([0-9]+)\"")?;
-        let cap: Vec<u32> = re
-            .captures_iter(resp.as_str())
-            .flat_map(|v| v.get(1))
-            .flat_map(|v| v.as_str().parse::<u32>())
+        for batch in commands.chunks(pipeline_depth()) {
+            let sent: Vec<Result<(u64, Receiver<GdbMiCommandResponse>)>> = batch
+                .iter()
+                .map(|cmd| GdbMiState::_send_command(state.clone(), cmd))
+                .collect();
+
+            for (cmd, s) in batch.iter().zip(sent) {
+                let resp = s
+                    .and_then(|(id, rx)| GdbMiState::await_response(state.clone(), id, rx, timeout))
+                    .and_then(GdbMiState::response_value)
+                    .with_context(|| format!("Running {}", cmd));
+                ret.push(resp);
+            }
+        }
+
+        ret
+    }
+
+    fn command(state: Arc<Mutex<GdbMiState>>, command: &str) -> Result<MiValue> {
+        let timeout = match state.lock() {
+            Ok(st) => st.timeout,
+            Err(_) => return Err(anyhow!("Failed to lock")),
+        };
+
+        GdbMiState::command_timeout(state, command, timeout)
+    }
+
+    /// Kernel thread id from a target-id ("Thread 0x7f.. (LWP 1234)" or "process 1234")
+    fn parse_lwp(target_id: &str) -> Option<u32> {
+        let start = match (target_id.find("LWP "), target_id.find("process ")) {
+            (Some(pos), _) => pos + "LWP ".len(),
+            (None, Some(pos)) => pos + "process ".len(),
+            _ => return None,
+        };
+
+        let digits: String = target_id[start..]
+            .chars()
+            .take_while(|c| c.is_ascii_digit())
             .collect();
-        Ok(cap)
+
+        digits.parse().ok()
     }
 
-    fn backtrace(state: Arc<Mutex<GdbMiState>>) -> Result<Vec<DebugFrame>> {
-        let resp = GdbMiState::command(state, "-stack-list-frames 0 1000")?;
+    /// List the threads with their metadata (by thread ID)
+    fn thread_info(state: Arc<Mutex<GdbMiState>>) -> Result<Vec<(u32, ThreadInfo)>> {
+        let resp = GdbMiState::command(state, "-thread-info")?;
+
+        let mut threads: Vec<(u32, ThreadInfo)> = resp
+            .get("threads")
+            .map(|v| v.items())
+            .unwrap_or_default()
+            .iter()
+            .flat_map(|t| {
+                let id = t.get_parsed::<u32>("id")?;
+                let target_id = t.get_str("target-id").map(String::from);
+
+                Some((
+                    id,
+                    ThreadInfo {
+                        name: t.get_str("name").map(String::from),
+                        lwp: target_id.as_deref().and_then(GdbMiState::parse_lwp),
+                        target_id,
+                        core: t.get_parsed("core"),
+                        state: t.get_str("state").map(String::from),
+                    },
+                ))
+            })
+            .collect();
+
+        threads.sort_by_key(|(id, _)| *id);
 
-        let re = Regex::new("frame=\\{([^\\}]+)\\}")?;
+        Ok(threads)
+    }
 
-        let cap: Vec<DebugFrame> = re
-            .captures_iter(resp.as_str())
-            .flat_map(|v| v.get(1))
-            .flat_map(|v| DebugFrame::new(v.as_str()))
+    /// Backtraces of the given threads (in the same order)
+    fn backtraces(state: Arc<Mutex<GdbMiState>>, threads: &[u32]) -> Result<Vec<Vec<DebugFrame>>> {
+        let cmds: Vec<String> = threads
+            .iter()
+            .map(|th| format!("-stack-list-frames --thread {} 0 1000", th))
             .collect();
 
-        Ok(cap)
+        GdbMiState::commands(state, &cmds)
+            .into_iter()
+            .map(|resp| {
+                Ok(resp?
+                    .get("stack")
+                    .map(|v| v.items())
+                    .unwrap_or_default()
+                    .into_iter()
+                    .flat_map(DebugFrame::new)
+                    .collect())
+            })
+            .collect()
     }
 
-    fn symbols(state: Arc<Mutex<GdbMiState>>) -> Result<SymbolTable> {
+    /// Functions of the program, only the ones matching `name` if given
+    /// (a regex as read by GDB)
+    fn symbols(state: Arc<Mutex<GdbMiState>>, name: Option<&str>) -> Result<SymbolTable> {
         let mut ret = SymbolTable::default();
 
-        let resp = GdbMiState::command(state, "-symbol-info-functions --include-nondebug")?;
+        let mut cmd = "-symbol-info-functions --include-nondebug".to_string();
 
-        if let Some(strip_start) = resp.strip_prefix(",symbols=") {
-            let data = gdb_output_to_json_repr(strip_start)?;
+        if let Some(name) = name {
+            cmd.push_str(&format!(" --name {}", quote(name)));
+        }
+
+        let resp = GdbMiState::command_timeout(state, &cmd, GDB_LOAD_TIMEOUT)?;
 
-            let symbs: GdbSymbolRecord = serde_json::from_str(&data)?;
+        if let Some(symbols) = resp.get("symbols") {
+            let symbs: GdbSymbolRecord = serde_json::from_value(symbols.to_json())?;
 
             if let Some(per_file) = symbs.debug {
                 for f in per_file {
@@ -330,68 +661,281 @@ impl GdbMiState {
         Ok(ret)
     }
 
-    #[allow(unused)]
-    fn locals(
-        state: Arc<Mutex<GdbMiState>>,
-        threadid: u32,
-        frameid: u32,
-    ) -> Result<Vec<(String, bool, String)>> {
-        let mut ret = Vec::new();
-        let cmd = format!(
+    /// Command listing the variables of a frame
+    fn locals_command(threadid: u32, frameid: u32) -> String {
+        format!(
             "-stack-list-variables --thread {} --frame {} --all-values",
             threadid, frameid
-        );
-        let resp = GdbMiState::command(state, &cmd)?;
+        )
+    }
 
-        let groups = extract_gdb_group(&resp);
+    fn parse_locals(resp: &MiValue) -> Vec<(String, bool, String)> {
+        let mut ret = Vec::new();
 
-        for g in groups {
-            let entries = parse_gdb_equal_list(&g);
+        let vars = resp.get("variables").map(|v| v.items()).unwrap_or_default();
 
-            if let (Some(name), Some(value)) = (entries.get("name"), entries.get("value")) {
-                let is_arg = if let Some(arg) = entries.get("arg") {
-                    arg == "1"
-                } else {
-                    false
-                };
+        for v in vars {
+            if let (Some(name), Some(value)) = (v.get_str("name"), v.get_str("value")) {
+                let is_arg = v.get_str("arg") == Some("1");
 
                 ret.push((name.to_string(), is_arg, value.to_string()));
             }
         }
 
-        Ok(ret)
+        ret
     }
 
-    fn snapshot(state: Arc<Mutex<GdbMiState>>) -> Result<ProgramSnapshot> {
-        let mut ret: HashMap<u32, Vec<DebugFrame>> = HashMap::new();
+    fn set_print_limits(state: Arc<Mutex<GdbMiState>>, opts: &SnapshotOptions) {
+        /* Limits are also enforced when truncating values, older
+        GDBs not supporting these settings are thus not an issue */
+        let _ = GdbMiState::command(
+            state.clone(),
+            format!("-gdb-set print max-depth {}", opts.max_depth).as_str(),
+        );
+        let _ = GdbMiState::command(
+            state,
+            format!("-gdb-set print elements {}", opts.max_string).as_str(),
+        );
+    }
+
+    /// Leading scalar registers of the innermost frame of a thread
+    fn registers(state: Arc<Mutex<GdbMiState>>, thread: u32) -> Result<Vec<(String, String)>> {
+        let names: Vec<String> = GdbMiState::command(state.clone(), "-data-list-register-names")?
+            .get("register-names")
+            .map(|v| v.items())
+            .unwrap_or_default()
+            .iter()
+            .map(|v| v.as_str().unwrap_or_default().to_string())
+            .collect();
+
+        let cmd = format!("-data-list-register-values --thread {} --frame 0 x", thread);
+        let resp = GdbMiState::command(state, &cmd)?;
+
+        /* Vector registers are aggregates and are skipped */
+        let ret = resp
+            .get("register-values")
+            .map(|v| v.items())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|r| {
+                let name = names.get(r.get_parsed::<usize>("number")?)?;
+                let value = r.get_str("value")?;
+
+                match name.is_empty() || value.starts_with('{') {
+                    true => None,
+                    false => Some((name.clone(), value.to_string())),
+                }
+            })
+            .take(MAX_REGISTERS)
+            .collect();
+
+        Ok(ret)
+    }
 
-        let threads = GdbMiState::list_thread_id(state.clone())?;
+    /// Instructions around the PC of the innermost frame of a thread
+    fn disassemble(
+        state: Arc<Mutex<GdbMiState>>,
+        thread: u32,
+        pc: Option<u64>,
+    ) -> Result<Vec<DisassemblyLine>> {
+        /* Disassemble the whole function to get instruction boundaries
+        right, code without symbols only has what follows the PC */
+        let resp = GdbMiState::command(
+            state.clone(),
+            &format!(
+                "-data-disassemble --thread {} --frame 0 -a $pc -- 0",
+                thread
+            ),
+        )
+        .or_else(|_| {
+            GdbMiState::command(
+                state,
+                &format!(
+                    "-data-disassemble --thread {} --frame 0 -s $pc -e \"$pc + 64\" -- 0",
+                    thread
+                ),
+            )
+        })?;
+
+        let insns: Vec<DisassemblyLine> = resp
+            .get("asm_insns")
+            .map(|v| v.items())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|i| {
+                let address = i.get_str("address")?.to_string();
+                let current = pc.is_some() && parse_address(&address) == pc;
+
+                Some(DisassemblyLine {
+                    address,
+                    func: i.get_str("func-name").map(|v| v.to_string()),
+                    offset: i.get_parsed::<u32>("offset"),
+                    inst: i.get_str("inst").unwrap_or_default().to_string(),
+                    current,
+                })
+            })
+            .collect();
 
-        for th in threads {
-            GdbMiState::select_thread(state.clone(), th)?;
-            let bt = GdbMiState::backtrace(state.clone())?;
+        let at = insns.iter().position(|i| i.current).unwrap_or(0);
+        let start = at.saturating_sub(DISASSEMBLY_WINDOW);
+        let end = (at + DISASSEMBLY_WINDOW + 1).min(insns.len());
 
-            //for frame in bt.iter_mut() {
-            //    if let Ok(vars) = GdbMiState::locals(state.clone(), th, frame.level) {
-            //        frame.attach_locals(vars);
-            //    }
-            //}
+        Ok(insns[start..end].to_vec())
+    }
 
-            ret.insert(th, bt);
+    /// Registers and disassembly of the thread which received a signal
+    fn fault_context(state: Arc<Mutex<GdbMiState>>, stop: &StopReason) -> Option<FaultContext> {
+        if stop.reason != "signal-received" {
+            return None;
         }
 
-        let stop_state: Option<StopReason> = GdbMiState::get_stop_state(&state);
+        let thread = stop.thread_id?;
+        let pc = stop.addr.as_deref().and_then(parse_address);
+
+        let registers = GdbMiState::registers(state.clone(), thread).unwrap_or_default();
+        let disassembly = GdbMiState::disassemble(state, thread, pc).unwrap_or_default();
 
-        Ok(ProgramSnapshot {
-            state: ret,
-            stop_state,
+        Some(FaultContext {
+            registers,
+            disassembly,
         })
     }
 
-    fn select_thread(state: Arc<Mutex<GdbMiState>>, id: u32) -> Result<()> {
-        let cmd = format!("-thread-select {}", id);
-        GdbMiState::command(state, cmd.as_str())?;
-        Ok(())
+    /// Thread group ids to the executable they run
+    fn inferior_executables(state: Arc<Mutex<GdbMiState>>) -> Result<HashMap<String, String>> {
+        let resp = GdbMiState::command(state, "-list-thread-groups")?;
+
+        let ret = resp
+            .get("groups")
+            .map(|v| v.items())
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|g| Some((g.get_str("id")?, g.get_str("executable")?)))
+            .map(|(id, exe)| (id.to_string(), exe.to_string()))
+            .collect();
+
+        Ok(ret)
+    }
+
+    /// Snapshot the threads of each inferior (by thread group id)
+    fn snapshot(
+        state: Arc<Mutex<GdbMiState>>,
+        opts: &SnapshotOptions,
+    ) -> Result<HashMap<String, ProgramSnapshot>> {
+        let mut ret: HashMap<String, ProgramSnapshot> = HashMap::new();
+
+        let threads = GdbMiState::thread_info(state.clone())?;
+
+        let (groups, multi, exited) = match state.lock() {
+            Ok(ls) => (
+                ls.threads.clone(),
+                ls.multi_inferior(),
+                ls.exited_inferiors.clone(),
+            ),
+            Err(_) => return Err(anyhow!("Failed to lock")),
+        };
+
+        if opts.locals {
+            GdbMiState::set_print_limits(state.clone(), opts);
+        }
+
+        let ids: Vec<u32> = threads.iter().map(|(th, _)| *th).collect();
+        let mut backtraces = GdbMiState::backtraces(state.clone(), &ids)?;
+
+        if opts.locals {
+            /* (thread index, frame index) of each variable listing */
+            let mut targets = Vec::new();
+            let mut cmds = Vec::new();
+
+            for (i, bt) in backtraces.iter().enumerate() {
+                for (j, frame) in bt.iter().enumerate() {
+                    if frame.level < opts.max_frames {
+                        targets.push((i, j));
+                        cmds.push(GdbMiState::locals_command(ids[i], frame.level));
+                    }
+                }
+            }
+
+            let resps = GdbMiState::commands(state.clone(), &cmds);
+
+            for ((i, j), resp) in targets.into_iter().zip(resps) {
+                if let Ok(resp) = resp {
+                    let vars = GdbMiState::parse_locals(&resp)
+                        .into_iter()
+                        .map(|(name, is_arg, value)| {
+                            let value = truncate_value(&value, opts.max_depth, opts.max_string);
+                            (name, is_arg, value)
+                        })
+                        .collect();
+                    backtraces[i][j].attach_locals(vars);
+                }
+            }
+        }
+
+        for ((th, info), bt) in threads.into_iter().zip(backtraces) {
+            /* Threads of unknown inferiors go to the first one */
+            let group = match (multi, groups.get(&th)) {
+                (true, Some(group)) => group.clone(),
+                _ => "i1".to_string(),
+            };
+
+            let snap = ret.entry(group).or_insert_with(|| ProgramSnapshot {
+                state: HashMap::new(),
+                stop_state: None,
+                inferior: None,
+                fault: None,
+                threads: HashMap::new(),
+            });
+
+            snap.state.insert(th, bt);
+            snap.threads.insert(th, info);
+        }
+
+        let stop_state: Option<StopReason> = GdbMiState::get_stop_state(&state);
+
+        let fault = stop_state
+            .as_ref()
+            .and_then(|s| GdbMiState::fault_context(state.clone(), s));
+
+        if !multi {
+            for snap in ret.values_mut() {
+                snap.stop_state = stop_state.clone();
+                snap.fault = fault.clone();
+            }
+
+            return Ok(ret);
+        }
+
+        /* The stop is only reported for the inferior of the stopping thread */
+        let stop_group = stop_state
+            .as_ref()
+            .and_then(|s| s.thread_id)
+            .and_then(|th| groups.get(&th));
+
+        for (group, snap) in ret.iter_mut() {
+            if stop_group.is_none() || stop_group == Some(group) {
+                snap.stop_state = stop_state.clone();
+                snap.fault = fault.clone();
+            }
+        }
+
+        for (group, code) in exited {
+            ret.insert(
+                group,
+                ProgramSnapshot::exited(Some(StopReason::inferior_exited(code))),
+            );
+        }
+
+        let executables = GdbMiState::inferior_executables(state).unwrap_or_default();
+
+        for (group, snap) in ret.iter_mut() {
+            snap.inferior = Some(DisplayInferior {
+                num: inferior_num(group),
+                executable: executables.get(group).cloned(),
+            });
+        }
+
+        Ok(ret)
     }
 
     fn start(state: Arc<Mutex<GdbMiState>>, gdb_out: ChildStdout) -> Result<()> {
@@ -413,10 +957,15 @@ impl GdbMiState {
                 response_id: 0,
                 thread_stdout: None,
                 gdb_in,
-                running: true,
                 gdblog: Vec::new(),
-                resps: HashMap::new(),
+                pending: HashMap::new(),
                 runstate: None,
+                runstate_changed: Arc::new(Condvar::new()),
+                timeout: GDB_COMMAND_TIMEOUT,
+                inferiors: HashMap::new(),
+                exited_inferiors: HashMap::new(),
+                threads: HashMap::new(),
+                passthrough: true,
             };
 
             let ret = Arc::new(Mutex::new(ret));
@@ -433,6 +982,9 @@ impl GdbMiState {
 pub struct GdbMi {
     id: u64,
     target: GdbMiRemote,
+    config: DebuggerConfig,
+    /// Terminal of the inferior (only for launched commands)
+    tty: Option<InferiorTty>,
     state: Option<Arc<Mutex<GdbMiState>>>,
     child_proc: Option<Child>,
 }
@@ -451,24 +1003,58 @@ impl Debugger for GdbMi {
     }
 
     /// Start the debugged program (program is not started by default)
+    /// attached processes are stopped by the attach and are thus continued
+    /// as are processes already held by a gdbserver, a gdbserver holding
+    /// no process runs the program given with the target (if any)
+    /// core files cannot be run and are left as is
     fn start(&mut self) -> Result<()> {
-        self.cmd("-exec-run")?;
+        let remote_has_process =
+            matches!(self.target, GdbMiRemote::Server(..)) && self.target_stopped();
+
+        match self.target {
+            GdbMiRemote::Attach(_) => self.cmd("-exec-continue")?,
+            GdbMiRemote::Server(..) if remote_has_process => self.cmd("-exec-continue")?,
+            GdbMiRemote::Server(_, _, None) => {
+                return Err(anyhow!(
+                    "The gdbserver holds no process and no program to run was given"
+                ))
+            }
+            GdbMiRemote::Core(_, _) => return Ok(()),
+            _ => self.cmd("-exec-run")?,
+        };
+
+        /* The program holds its terminal now, its end closes the output */
+        if let Some(tty) = self.tty.as_mut() {
+            tty.close_slave();
+        }
+
         Ok(())
     }
 
-    /// If the process is running send a stop signal to interupt it
+    /// If the process is running send a stop signal to interupt it,
+    /// GDB answers at once so the stop itself is waited for
     fn stop(&mut self) -> Result<()> {
-        if self.id_is_stopped(self.id)? {
+        if self.target_stopped() || self.target_exited() {
             /* Already stopped */
             return Ok(());
         }
         self.cmd("-exec-interrupt --all")?;
-        Ok(())
+
+        match &self.state {
+            Some(st) => {
+                let timeout = st.lock().map(|s| s.timeout).unwrap_or(GDB_COMMAND_TIMEOUT);
+                GdbMiState::wait_stopped(st.clone(), timeout)
+            }
+            None => Err(anyhow!("Program is not running")),
+        }
     }
 
     /// Continue an interrupted process
     fn cont(&mut self) -> Result<()> {
-        if self.id_is_running(self.id)? {
+        if let GdbMiRemote::Core(_, _) = self.target {
+            return Err(anyhow!("A core file cannot be continued"));
+        }
+        if self.target_running() {
             /* Already running */
             return Ok(());
         }
@@ -476,14 +1062,64 @@ impl Debugger for GdbMi {
         Ok(())
     }
 
+    /// Detach from the process leaving it running (it is stopped
+    /// first as GDB cannot detach from a running target)
+    fn detach(&mut self) -> Result<()> {
+        if self.target_exited() {
+            /* Nothing to detach from */
+            return Ok(());
+        }
+        if self.target_running() {
+            self.stop()?;
+        }
+        self.cmd("-target-detach")?;
+        Ok(())
+    }
+
+    /// Insert a breakpoint (kept pending if the location is not loaded yet)
+    fn set_breakpoint(&mut self, location: &str) -> Result<u32> {
+        let resp = self.cmd(format!("-break-insert -f {}", location_arg(location)?).as_str())?;
+
+        resp.get("bkpt")
+            .and_then(|b| b.get_parsed::<u32>("number"))
+            .ok_or(anyhow!("No breakpoint number for {}", location))
+    }
+
+    /// Insert a watchpoint
+    fn set_watchpoint(&mut self, expr: &str, kind: WatchKind) -> Result<u32> {
+        let flag = match kind {
+            WatchKind::Write => "",
+            WatchKind::Read => "-r ",
+            WatchKind::Access => "-a ",
+        };
+
+        let resp = self.cmd(format!("-break-watch {}{}", flag, location_arg(expr)?).as_str())?;
+
+        ["wpt", "hw-rwpt", "hw-awpt"]
+            .iter()
+            .find_map(|k| resp.get(k))
+            .and_then(|w| w.get_parsed::<u32>("number"))
+            .ok_or(anyhow!("No watchpoint number for {}", expr))
+    }
+
+    fn delete_breakpoint(&mut self, num: u32) -> Result<()> {
+        self.cmd(format!("-break-delete {}", num).as_str())?;
+        Ok(())
+    }
+
     /// Get current state of the debugged process
+    /// (of each inferior when following forks or execs)
     fn state(&mut self) -> Result<HashMap<u64, RunState>> {
         let mut ret = HashMap::new();
 
         if let Some(st) = &self.state {
             if let Ok(ls) = st.lock() {
-                if let Some(rs) = &ls.runstate {
-                    ret.insert(self.get_id(), rs.clone());
+                if ls.multi_inferior() {
+                    for (group, rs) in ls.inferior_runstates() {
+                        ret.insert(self.inferior_id(&group), rs);
+                    }
+                } else if let Some(rs) = ls.global_runstate() {
+                    ret.insert(self.get_id(), rs);
                 }
             }
         }
@@ -495,64 +1131,153 @@ impl Debugger for GdbMi {
     /// You need to have the program in a stopped state first
     ///     - Calling `stop` to interrupt
     ///     - Checking `is_stopped` to handle breakpoints or crashes
-    fn snapshot(&mut self) -> Result<HashMap<u64, (u64, Vec<BacktraceState>)>> {
-        if self.id_is_running(self.id)? {
+    fn snapshot(
+        &mut self,
+        opts: &SnapshotOptions,
+    ) -> Result<HashMap<u64, (u64, Vec<BacktraceState>)>> {
+        if self.target_running() {
             self.stop()?;
         }
 
-        let exited = self.id_is_exited(self.id)?;
+        let exited = self.target_exited();
 
-        if let Some(st) = &self.state {
-            if exited {
-                let stop_state: Option<StopReason> = GdbMiState::get_stop_state(st);
-                let mut ret = HashMap::new();
+        let Some(st) = &self.state else {
+            return Err(anyhow!("Program is not running"));
+        };
 
-                ret.insert(self.id, ProgramSnapshot::exited(stop_state));
+        let ret = if let Some(reason) = GdbMiState::get_dead_reason(st) {
+            HashMap::from([(self.id, ProgramSnapshot::dead(&reason))])
+        } else if exited {
+            let stop_state: Option<StopReason> = GdbMiState::get_stop_state(st);
+            HashMap::from([(self.id, ProgramSnapshot::exited(stop_state))])
+        } else {
+            GdbMiState::snapshot(st.clone(), opts)?
+                .into_iter()
+                .map(|(group, snap)| (self.inferior_id(&group), snap))
+                .collect()
+        };
 
-                return Ok(ProgramSnapshot::generate_components(ret));
-            }
+        /* Map to snapshot */
+        let ret = ProgramSnapshot::generate_components(ret, opts);
 
-            let snap = GdbMiState::snapshot(st.clone())?;
+        Ok(match &self.tty {
+            Some(tty) => ProgramSnapshot::attach_output(ret, tty.lines()),
+            None => ret,
+        })
+    }
 
-            let mut ret = HashMap::new();
-            ret.insert(self.id, snap);
-            /* Map to snapshot */
-            let ret = ProgramSnapshot::generate_components(ret);
-            return Ok(ret);
+    /// Get the symbol table from the target split it per file
+    fn symbols(&mut self) -> Result<SymbolTable> {
+        self.symbols_named(None)
+    }
+
+    /// GDB only lists the matching functions when it reads the pattern
+    /// the same way, otherwise they are all listed and filtered here
+    fn find_functions(&mut self, pattern: &str) -> Result<SymbolTable> {
+        let re = Regex::new(pattern)?;
+
+        Ok(self.symbols_named(gdb_regex(pattern))?.filter(&re))
+    }
+
+    /// Last output lines of the inferior (if run on its own terminal)
+    fn output(&mut self) -> Result<HashMap<u64, ProcessOutput>> {
+        let mut ret = HashMap::new();
+
+        if let Some(tty) = &self.tty {
+            let tag = ProcessInfo::default()?.tag();
+
+            ret.insert(
+                self.id,
+                ProcessOutput {
+                    tag,
+                    lines: tty.lines(),
+                },
+            );
         }
 
-        Err(anyhow!("Program is not running"))
+        Ok(ret)
     }
 
-    /// Get the symbol table from the target split it per file
-    fn symbols(&mut self) -> Result<SymbolTable> {
-        if self.id_is_running(self.id)? {
+    fn count(&mut self) -> Result<u64> {
+        Ok(1)
+    }
+}
+
+/// A breakpoint location or watched expression as a single MI argument,
+/// newlines would end the command and are refused
+fn location_arg(value: &str) -> Result<String> {
+    if value.contains(['\n', '\r']) {
+        return Err(anyhow!(
+            "Locations and expressions cannot contain newlines: {:?}",
+            value
+        ));
+    }
+
+    Ok(quote(value))
+}
+
+/// The pattern if GDB reads it as [Regex] does, GDB using basic POSIX
+/// regexes where these characters are literals or differ in meaning
+fn gdb_regex(pattern: &str) -> Option<&str> {
+    (!pattern.contains(['|', '+', '?', '(', ')', '{', '}', '\\'])).then_some(pattern)
+}
+
+impl GdbMi {
+    fn symbols_named(&mut self, name: Option<&str>) -> Result<SymbolTable> {
+        if self.target_running() {
             return Err(anyhow!("Symbols can only be retrieved on a stopped target"));
         }
 
         if let Some(st) = &self.state {
-            return GdbMiState::symbols(st.clone());
+            return GdbMiState::symbols(st.clone(), name);
         }
 
         Err(anyhow!("No GDB state was available to retrieve symbols"))
     }
 
-    fn count(&mut self) -> Result<u64> {
-        Ok(1)
+    /// ID of an inferior given its thread group id
+    fn inferior_id(&self, group: &str) -> u64 {
+        let num = (inferior_num(group) as u64).clamp(1, INFERIOR_ID_SLOTS);
+        self.id + num - 1
+    }
+
+    fn runstate(&self) -> Option<RunState> {
+        if let Some(st) = &self.state {
+            if let Ok(ls) = st.lock() {
+                return ls.global_runstate();
+            }
+        }
+
+        None
+    }
+
+    fn target_running(&self) -> bool {
+        matches!(self.runstate(), Some(RunState::Running(_)))
+    }
+
+    fn target_stopped(&self) -> bool {
+        matches!(self.runstate(), Some(RunState::Stopped(_)))
+    }
+
+    fn target_exited(&self) -> bool {
+        self.runstate().is_some_and(|r| r.exited())
     }
-}
 
-impl GdbMi {
     fn _start_gdb(&mut self) -> Result<()> {
-        let gdbargs = self.target.gdbargs();
+        /* The target comes last as it ends with the program arguments */
+        let mut gdbargs = self.config.gdb_args()?;
+        gdbargs.append(&mut self.target.gdbargs());
 
         log::debug!("{:?}", gdbargs);
 
-        let mut command = Command::new("gdb")
+        let gdb = self.config.gdb_path();
+
+        let mut command = Command::new(&gdb)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .args(&gdbargs)
-            .spawn()?;
+            .spawn()
+            .with_context(|| format!("Failed to launch {}", gdb))?;
 
         let state = GdbMiState::new(command.stdin.take(), command.stdout.take())?;
 
@@ -567,7 +1292,10 @@ impl GdbMi {
     }
 
     /// Run an arbitraty GDB-MI command on the target
-    pub fn cmd(&mut self, command: &str) -> Result<String> {
+    ///
+    /// Fails if GDB does not respond within the command timeout
+    /// (see [GdbMi::set_timeout])
+    pub fn cmd(&mut self, command: &str) -> Result<MiValue> {
         if let Some(st) = &self.state {
             let ret = GdbMiState::command(st.clone(), command)?;
             return Ok(ret);
@@ -576,6 +1304,25 @@ impl GdbMi {
         Err(anyhow!("Program is not running"))
     }
 
+    /// Run an arbitraty GDB-MI command on the target with a specific timeout
+    pub fn cmd_timeout(&mut self, command: &str, timeout: Duration) -> Result<MiValue> {
+        if let Some(st) = &self.state {
+            let ret = GdbMiState::command_timeout(st.clone(), command, timeout)?;
+            return Ok(ret);
+        }
+
+        Err(anyhow!("Program is not running"))
+    }
+
+    /// Set the time to wait for GDB to respond to commands
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        if let Some(st) = &self.state {
+            if let Ok(st) = st.lock().as_mut() {
+                st.timeout = timeout;
+            }
+        }
+    }
+
     /// Gets the log output from GDB (can be safely ignored)
     /// The log is drained each time this is called.
     pub fn log(&self) -> Option<Vec<String>> {
@@ -592,13 +1339,11 @@ impl GdbMi {
         None
     }
 
-    /// Launch a command wrapped in GDB
-    ///
-    /// Note this does not start the underlying program you need to call `start` to do so
-    pub fn run(cmd: &[&str]) -> Result<GdbMi> {
-        let cmd: Vec<String> = cmd.iter().map(|v| v.to_string()).collect();
+    fn _init(target: GdbMiRemote, config: &DebuggerConfig) -> Result<GdbMi> {
         let mut ret = GdbMi {
-            target: GdbMiRemote::Command(cmd),
+            target,
+            config: config.clone(),
+            tty: None,
             state: None,
             id: 0,
             child_proc: None,
@@ -606,42 +1351,260 @@ impl GdbMi {
 
         ret._start_gdb()?;
 
-        ret.cmd("-gdb-set mi-async on")?;
+        /* GDB only answers once the executable symbols are loaded */
+        ret.cmd_timeout("-gdb-set mi-async on", GDB_LOAD_TIMEOUT)?;
         ret.cmd("-enable-pretty-printing")?;
 
+        for setting in ret.config.gdb_settings() {
+            ret.cmd(format!("-gdb-set {}", setting).as_str())?;
+        }
+
+        if let GdbMiRemote::Command(_) = ret.target {
+            ret._setup_tty();
+        }
+
+        ret._load_target()?;
+
         Ok(ret)
     }
 
-    /**
-       pub fn server(host: String, port: u32) -> Result<GdbMi> {
-           unimplemented!("No server support yet");
+    /// Run the inferior on its own terminal to separate its output
+    /// from GDB, if this fails the output goes through GDB as before
+    fn _setup_tty(&mut self) {
+        let tag = ProcessInfo::default().map(|p| p.tag()).unwrap_or_default();
+
+        let tty = match InferiorTty::new(tag) {
+            Ok(tty) => tty,
+            Err(e) => {
+                log::warn!("Inferior output will not be captured: {}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = self.cmd(format!("-inferior-tty-set {}", tty.path()).as_str()) {
+            log::warn!("Inferior output will not be captured: {}", e);
+            return;
+        }
+
+        if let Some(st) = &self.state {
+            if let Ok(ls) = st.lock().as_mut() {
+                ls.passthrough = false;
+            }
+        }
+
+        self.tty = Some(tty);
+    }
+
+    /// Load targets which are not given on the GDB command line
+    fn _load_target(&mut self) -> Result<()> {
+        match self.target.clone() {
+            GdbMiRemote::Command(_) => {}
+            GdbMiRemote::Attach(pid) => {
+                self.cmd_timeout(format!("-target-attach {}", pid).as_str(), GDB_LOAD_TIMEOUT)?;
+            }
+            GdbMiRemote::Core(exe, core) => {
+                self.cmd_timeout(
+                    format!("-file-exec-and-symbols {}", quote(&exe)).as_str(),
+                    GDB_LOAD_TIMEOUT,
+                )?;
+                /* Drop the log from the executable load */
+                self.log();
+                self.cmd_timeout(
+                    format!("-target-select core {}", quote(&core)).as_str(),
+                    GDB_LOAD_TIMEOUT,
+                )?;
+
+                let re = Regex::new("Program terminated with signal (SIG[A-Z0-9]+)")?;
+
+                let signal_name = self.log().and_then(|log| {
+                    log.iter()
+                        .find_map(|l| re.captures(l).and_then(|c| c.get(1)))
+                        .map(|v| v.as_str().to_string())
+                });
+
+                if let Some(st) = &self.state {
+                    let stop = StopReason::core_dumped(signal_name);
+                    GdbMiState::setrunstate(st.clone(), RunState::Stopped(Box::new(stop)))?;
+                }
+            }
+            GdbMiRemote::Server(host, port, program) => {
+                self.cmd_timeout(
+                    format!("-target-select extended-remote {}:{}", host, port).as_str(),
+                    GDB_LOAD_TIMEOUT,
+                )?;
+
+                /* Program run by the gdbserver if it holds no process */
+                if let Some((exe, args)) = program.as_deref().and_then(|p| p.split_first()) {
+                    self.cmd(format!("-gdb-set remote exec-file {}", quote(exe)).as_str())?;
+
+                    if !args.is_empty() {
+                        let args: Vec<String> = args.iter().map(|a| quote(a)).collect();
+                        self.cmd(format!("-exec-arguments {}", args.join(" ")).as_str())?;
+                    }
+                }
+            }
+        }
 
-           let ret = GdbMi {
-               target: GdbMiRemote::Server(host, port),
-               state: None,
-               id: 0,
-           };
+        Ok(())
+    }
 
-           Ok(ret)
-       }
+    /// Create a debugger for the given target
+    pub fn new(target: GdbMiRemote) -> Result<GdbMi> {
+        GdbMi::with_config(target, &DebuggerConfig::default())
+    }
 
-       pub fn attach(pid: u32) -> Result<GdbMi> {
-           unimplemented!("No attach support yet");
+    /// Create a debugger for the given target with the given configuration
+    pub fn with_config(target: GdbMiRemote, config: &DebuggerConfig) -> Result<GdbMi> {
+        GdbMi::_init(target, config)
+    }
+
+    /// Launch a command wrapped in GDB
+    ///
+    /// Note this does not start the underlying program you need to call `start` to do so
+    pub fn run(cmd: &[&str]) -> Result<GdbMi> {
+        let cmd: Vec<String> = cmd.iter().map(|v| v.to_string()).collect();
+        GdbMi::new(GdbMiRemote::Command(cmd))
+    }
 
-           let ret = GdbMi {
-               target: GdbMiRemote::Attach(pid),
-               state: None,
-               id: 0,
-           };
+    /// Attach to a running process
+    ///
+    /// The process is stopped once attached, call `start` to resume it
+    /// or `detach` to release it
+    pub fn attach(pid: u32) -> Result<GdbMi> {
+        GdbMi::new(GdbMiRemote::Attach(pid))
+    }
 
-           todo!("Not done yet");
+    /// Load a core file for post-mortem analysis
+    ///
+    /// The process is considered stopped by the signal which
+    /// terminated it, as reported by GDB when loading the core
+    pub fn core(exe: &str, core: &str) -> Result<GdbMi> {
+        GdbMi::new(GdbMiRemote::Core(exe.to_string(), core.to_string()))
+    }
 
-           Ok(ret)
-       }
-    */
+    /// Drive a gdbserver through `target extended-remote`
+    ///
+    /// If the gdbserver already holds a process (launched or attached) it
+    /// is stopped and `start` continues it, otherwise `start` runs `program`
+    /// on the gdbserver (which must have been started with `--multi`)
+    pub fn server(host: String, port: u32, program: Option<Vec<String>>) -> Result<GdbMi> {
+        GdbMi::new(GdbMiRemote::Server(host, port, program))
+    }
 
     pub fn instance(self) -> Arc<Mutex<Box<dyn Debugger>>> {
         let dbg: Arc<Mutex<Box<dyn Debugger>>> = Arc::new(Mutex::new(Box::new(self)));
         dbg
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// State fed with what is written to GDB, to play its records
+    fn echo_gdb() -> (Child, Arc<Mutex<GdbMiState>>) {
+        let mut cat = Command::new("cat")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let state = GdbMiState::new(cat.stdin.take(), cat.stdout.take()).unwrap();
+
+        (cat, state)
+    }
+
+    fn play(state: &Arc<Mutex<GdbMiState>>, record: &str) {
+        let mut ls = state.lock().unwrap();
+        writeln!(ls.gdb_in, "{}", record).unwrap();
+    }
+
+    #[test]
+    fn stops_are_waited_for() {
+        let (mut cat, state) = echo_gdb();
+
+        play(&state, "*running,thread-id=\"all\"");
+
+        let st = state.clone();
+        let stopping = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            play(
+                &st,
+                "*stopped,reason=\"signal-received\",signal-name=\"SIGINT\"",
+            );
+        });
+
+        GdbMiState::wait_stopped(state.clone(), Duration::from_secs(10)).unwrap();
+        stopping.join().unwrap();
+
+        let _ = cat.kill();
+    }
+
+    #[test]
+    fn stop_wait_times_out() {
+        let (mut cat, state) = echo_gdb();
+
+        play(&state, "*running,thread-id=\"all\"");
+
+        /* Wait for the record to be read */
+        while !matches!(state.lock().unwrap().runstate, Some(RunState::Running(_))) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(GdbMiState::wait_stopped(state.clone(), Duration::from_millis(100)).is_err());
+
+        /* The death of GDB ends the wait */
+        let _ = cat.kill();
+        let err = GdbMiState::wait_stopped(state, Duration::from_secs(10)).unwrap_err();
+        assert!(err.to_string().contains("GDB died"));
+    }
+
+    #[test]
+    fn locations_are_single_arguments() {
+        assert_eq!(location_arg("main.c:12").unwrap(), "\"main.c:12\"");
+        assert_eq!(
+            location_arg("-source a.c -line 3").unwrap(),
+            "\"-source a.c -line 3\""
+        );
+        assert_eq!(
+            location_arg("buf[\"key\"]").unwrap(),
+            "\"buf[\\\"key\\\"]\""
+        );
+
+        /* A newline would start another command */
+        assert!(location_arg("main\n-exec-continue").is_err());
+        assert!(location_arg("x\r-target-detach").is_err());
+    }
+
+    #[test]
+    fn breakpoints_refuse_injected_commands() {
+        let (mut cat, state) = echo_gdb();
+        let mut gdb = GdbMi {
+            id: 0,
+            target: GdbMiRemote::Attach(cat.id()),
+            config: DebuggerConfig::default(),
+            tty: None,
+            state: Some(state),
+            child_proc: None,
+        };
+
+        assert!(gdb.set_breakpoint("main\n-gdb-exit").is_err());
+        assert!(gdb
+            .set_watchpoint("x\nshell rm -rf /", WatchKind::Write)
+            .is_err());
+
+        cat.kill().unwrap();
+        cat.wait().unwrap();
+    }
+
+    #[test]
+    fn patterns_given_to_gdb() {
+        assert_eq!(gdb_regex("^MPI_"), Some("^MPI_"));
+        assert_eq!(gdb_regex("solve.*[0-9]$"), Some("solve.*[0-9]$"));
+
+        /* Alternations and groups are literals for GDB */
+        assert_eq!(gdb_regex("MPI_(Send|Recv)"), None);
+        assert_eq!(gdb_regex("a+"), None);
+        assert_eq!(gdb_regex(r"\bmain"), None);
+    }
+}
@@ -19,7 +19,7 @@
 //!
 //! ```
 //! // Start a local debugger instance
-//! let mut dbg = GdbMachine::local(cmd)?;
+//! let mut dbg = GdbMachine::local(vec![GdbMiRemote::Command(cmd)], &DebuggerConfig::default())?;
 //! // Run the debuggee
 //! dbg.start()?
 //!
@@ -33,37 +33,52 @@
 //!    }
 //!  }
 //!  /* Get processes's state */
-//!  let snap = dbg.snapshot()?;
+//!  let snap = dbg.snapshot(&SnapshotOptions::default())?;
 //! ```
 //!
 
+pub mod auth;
+pub mod config;
 pub mod debugger;
 pub mod gdbmi;
+mod link;
+pub mod lldb;
 pub mod metadata;
+pub mod mi;
 mod protocol;
+mod reactor;
+pub mod tls;
 mod tools;
+pub mod topology;
+mod transport;
+mod tty;
 
 use anyhow::anyhow;
 use anyhow::Context;
 use anyhow::Result;
+use config::DebuggerConfig;
+use debugger::merge_breakpoint_numbers;
 use debugger::Debugger;
+use debugger::DebuggerGroup;
 use debugger::DummyDebugger;
 use gdbmi::GdbMi;
+use gdbmi::GdbMiRemote;
+use link::Link;
+use lldb::Lldb;
 use metadata::BacktraceState;
 use metadata::ProcessInfo;
+use metadata::ProcessOutput;
 use metadata::ProgramSnapshot;
 use metadata::RunState;
+use metadata::SnapshotOptions;
 use metadata::SymbolTable;
-use metadata::TreeIdFactory;
+use metadata::WatchKind;
 use protocol::GdbMachineResponse;
 use rayon::prelude::*;
-use rayon::scope;
 use std::any::Any;
 use std::collections::HashMap;
-use std::io::Write;
+use std::io::ErrorKind;
 use std::net::SocketAddr;
-use std::net::TcpListener;
-use std::net::TcpStream;
 use std::process::Child;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -71,42 +86,174 @@ use std::sync::Mutex;
 use std::thread;
 use std::thread::sleep;
 use std::time::Duration;
+use std::time::Instant;
 use std::u64;
-use tools::read_until_null;
-use tools::strdistance;
+use tls::TlsSession;
+use topology::{Topology, TopologyNode, TopologyOptions};
+use transport::{Listener, Stream};
 
 use crate::protocol::GdbMachineCommand;
+use crate::protocol::GdbMachineRequest;
+
+/// Time given by default to the tree to answer a command
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Part of its time a node keeps to answer once its children answered
+const TIMEOUT_MARGIN_RATIO: u32 = 10;
+const MIN_TIMEOUT_MARGIN: Duration = Duration::from_secs(1);
 
 pub struct GdbClient {
-    client_sock: TcpStream,
+    link: Link<Stream>,
+    /// Tree ID of the peer (0 if not a child)
+    id: u64,
+    /// Name of the peer (see [ProcessInfo::tag])
+    tag: String,
+    /// Why the link is unusable
+    lost: Option<String>,
+    /// Processes of the subtree of the peer when it last counted them
+    size: u64,
 }
 
 impl GdbClient {
     pub fn new(addr: &str) -> Result<GdbClient> {
-        let client_sock = TcpStream::connect(addr)?;
-
-        Ok(GdbClient { client_sock })
+        let client_sock = Stream::connect(addr)?;
+
+        Ok(GdbClient {
+            link: Link::connect(client_sock)?,
+            id: 0,
+            tag: addr.to_string(),
+            lost: None,
+            size: 1,
+        })
     }
 
     fn do_command(&mut self, cmd: &GdbMachineCommand) -> Result<GdbMachineResponse> {
-        let cmd_in_json = serde_json::to_string(&cmd)?;
+        self.request(cmd, None)
+    }
+
+    /// Run a command giving `budget` to the peer to answer it
+    ///
+    /// On timeout the late response is skipped, on link
+    /// failure the link is not used anymore
+    fn request(
+        &mut self,
+        cmd: &GdbMachineCommand,
+        budget: Option<Duration>,
+    ) -> Result<GdbMachineResponse> {
+        if let Some(e) = &self.lost {
+            return Err(anyhow!("{}", e));
+        }
 
-        /* Write JSON */
-        self.client_sock.write_all(cmd_in_json.as_bytes())?;
-        /* Write Separator */
-        self.client_sock.write_all("\0".as_bytes())?;
-        self.client_sock.flush()?;
+        let ret = self._request(cmd, budget);
 
-        /* Get Response */
-        let resp = read_until_null(&mut self.client_sock)?;
-        /* Parse Response */
-        let ret: GdbMachineResponse = serde_json::from_str(&resp)?;
+        if let Err(e) = &ret {
+            let timed_out = e
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|e| matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut));
 
-        Ok(ret)
+            if let (true, Some(b)) = (timed_out, budget) {
+                self.link.skip_response();
+                return Err(anyhow!("No answer within {:.1}s", b.as_secs_f64()));
+            }
+
+            self.lost = Some(e.to_string());
+            return Err(anyhow!("{}", e));
+        }
+
+        ret
     }
 
-    pub fn join(&mut self, targ: String) -> Result<()> {
-        self.do_command(&GdbMachineCommand::Join(targ))?.ok()
+    /// Send `cmd` to all `clients` at once giving them `budget` to answer it
+    ///
+    /// The late responses of clients which do not answer in time are
+    /// skipped, clients whose link failed are not used anymore
+    fn exchange(
+        clients: &mut [GdbClient],
+        cmd: &GdbMachineCommand,
+        budget: Option<Duration>,
+    ) -> Vec<Result<GdbMachineResponse>> {
+        let req = GdbMachineRequest {
+            cmd: cmd.clone(),
+            budget: budget.map(|b| b.as_millis() as u64),
+        };
+
+        let mut links: Vec<&mut Link<Stream>> = clients
+            .iter_mut()
+            .filter(|c| c.lost.is_none())
+            .map(|c| &mut c.link)
+            .collect();
+
+        let answers = reactor::exchange(&mut links, &req, budget.map(|b| Instant::now() + b))
+            .unwrap_or_else(|e| {
+                let reason = e.to_string();
+                links
+                    .iter()
+                    .map(|_| Some(Err(anyhow!("{}", reason))))
+                    .collect()
+            });
+
+        let mut answers = answers.into_iter();
+
+        clients
+            .iter_mut()
+            .map(|c| {
+                if let Some(e) = &c.lost {
+                    return Err(anyhow!("{}", e));
+                }
+
+                match answers.next().flatten() {
+                    Some(Ok(resp)) => Ok(resp),
+                    Some(Err(e)) => {
+                        c.lost = Some(e.to_string());
+                        Err(e)
+                    }
+                    None => {
+                        c.link.skip_response();
+                        Err(anyhow!(
+                            "No answer within {:.1}s",
+                            budget.unwrap_or_default().as_secs_f64()
+                        ))
+                    }
+                }
+            })
+            .collect()
+    }
+
+    fn _request(
+        &mut self,
+        cmd: &GdbMachineCommand,
+        budget: Option<Duration>,
+    ) -> Result<GdbMachineResponse> {
+        self.link.get_mut().set_read_timeout(budget)?;
+
+        self.link.send(&GdbMachineRequest {
+            cmd: cmd.clone(),
+            budget: budget.map(|b| b.as_millis() as u64),
+        })?;
+
+        self.link
+            .recv()?
+            .ok_or(anyhow!("Connection closed before a response"))
+    }
+
+    pub fn join(&mut self, id: u64, targ: String) -> Result<()> {
+        let process_info = ProcessInfo::default()?;
+
+        self.do_command(&GdbMachineCommand::Join(id, process_info, targ))?
+            .ok()
+    }
+
+    /// Have the root sign the certificate of this node if links are encrypted
+    pub fn certify(&mut self) -> Result<()> {
+        let Some(tls) = TlsSession::get()?.filter(|t| !t.certified()) else {
+            return Ok(());
+        };
+
+        match self.do_command(&GdbMachineCommand::Certify(tls.public_key()))? {
+            GdbMachineResponse::Certificate(cert) => tls.set_certificate(&cert),
+            GdbMachineResponse::Error(e) => Err(anyhow!("Failed to get a certificate: {}", e)),
+            _ => Err(anyhow!("Bad response for certify")),
+        }
     }
 
     pub fn pivot(&mut self, local_url: String) -> Result<(u64, String)> {
@@ -114,11 +261,11 @@ impl GdbClient {
 
         let ret = self.do_command(&GdbMachineCommand::Pivot(process_info, local_url))?;
 
-        if let GdbMachineResponse::Pivot(id, targ) = ret {
-            return Ok((id, targ));
+        match ret {
+            GdbMachineResponse::Pivot(id, targ) => Ok((id, targ)),
+            GdbMachineResponse::Error(e) => Err(anyhow!("Failed to join the tree: {}", e)),
+            _ => Err(anyhow!("Bad response for pivot")),
         }
-
-        Err(anyhow!("Bad response for pivot"))
     }
 }
 
@@ -146,6 +293,25 @@ impl Debugger for GdbClient {
     fn cont(&mut self) -> Result<()> {
         self.do_command(&GdbMachineCommand::Continue)?.ok()
     }
+    /// Detach from the program
+    fn detach(&mut self) -> Result<()> {
+        self.do_command(&GdbMachineCommand::Detach)?.ok()
+    }
+
+    fn set_breakpoint(&mut self, location: &str) -> Result<u32> {
+        self.do_command(&GdbMachineCommand::SetBreakpoint(location.to_string()))?
+            .breakpoint()
+    }
+
+    fn set_watchpoint(&mut self, expr: &str, kind: WatchKind) -> Result<u32> {
+        self.do_command(&GdbMachineCommand::SetWatchpoint(expr.to_string(), kind))?
+            .breakpoint()
+    }
+
+    fn delete_breakpoint(&mut self, num: u32) -> Result<()> {
+        self.do_command(&GdbMachineCommand::DeleteBreakpoint(num))?
+            .ok()
+    }
 
     /// Get current state of program
     fn state(&mut self) -> Result<HashMap<u64, RunState>> {
@@ -155,13 +321,29 @@ impl Debugger for GdbClient {
     }
 
     /// Snapshot a stopped program
-    fn snapshot(&mut self) -> Result<HashMap<u64, (u64, Vec<BacktraceState>)>> {
-        self.do_command(&GdbMachineCommand::GetSnapshot)?.snapshot()
+    fn snapshot(
+        &mut self,
+        opts: &SnapshotOptions,
+    ) -> Result<HashMap<u64, (u64, Vec<BacktraceState>)>> {
+        self.do_command(&GdbMachineCommand::GetSnapshot(opts.clone()))?
+            .snapshot()
     }
 
     /// Get Symbol table
     fn symbols(&mut self) -> Result<SymbolTable> {
-        self.do_command(&GdbMachineCommand::GetSymbols)?.symbols()
+        self.do_command(&GdbMachineCommand::GetSymbols(None))?
+            .symbols()
+    }
+
+    /// Filtered by the processes to only send back matches
+    fn find_functions(&mut self, pattern: &str) -> Result<SymbolTable> {
+        self.do_command(&GdbMachineCommand::GetSymbols(Some(pattern.to_string())))?
+            .symbols()
+    }
+
+    /// Get last output lines
+    fn output(&mut self) -> Result<HashMap<u64, ProcessOutput>> {
+        self.do_command(&GdbMachineCommand::GetOutput)?.output()
     }
 
     fn count(&mut self) -> Result<u64> {
@@ -169,107 +351,160 @@ impl Debugger for GdbClient {
     }
 }
 
+/// Answer of a child, `resp` is an error if the child did not answer
+struct ChildReply {
+    id: u64,
+    tag: String,
+    /// Processes of the subtree of the child (see [GdbClient::size])
+    size: u64,
+    resp: Result<GdbMachineResponse>,
+}
+
+impl ChildReply {
+    /// Responses of the children which answered, the others being logged
+    fn answered(replies: Vec<ChildReply>) -> Vec<GdbMachineResponse> {
+        replies
+            .into_iter()
+            .filter_map(|r| match r.resp {
+                Ok(resp) => Some(resp),
+                Err(e) => {
+                    log::warn!("{} did not answer: {}", r.tag, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Responses of all the children, failing with the
+    /// ones which did not answer if there are some
+    fn all_answered(replies: Vec<ChildReply>) -> Result<Vec<GdbMachineResponse>> {
+        let mut ret = Vec::new();
+        let mut missing = Vec::new();
+
+        for r in replies {
+            match r.resp {
+                Ok(resp) => ret.push(resp),
+                Err(e) => missing.push(format!("{} (id {}): {}", r.tag, r.id, e)),
+            }
+        }
+
+        if !missing.is_empty() {
+            return Err(anyhow!("No answer from {}", missing.join(", ")));
+        }
+
+        Ok(ret)
+    }
+}
+
 pub struct TreeState {
     id: Option<u64>,
-    seen_children: HashMap<String, (String, TreeIdFactory)>,
+    /// Processes of the tree (on the root)
+    topology: Option<Topology>,
     children: Vec<GdbClient>,
+    /// Time given to the tree for regular commands (on the root)
+    timeout: Duration,
+    /// Time left to answer the current command (given by the parent)
+    budget: Option<Duration>,
 }
 
 impl TreeState {
     fn default() -> TreeState {
         TreeState {
-            seen_children: HashMap::new(),
+            topology: None,
             children: Vec::new(),
             id: None,
+            timeout: DEFAULT_TIMEOUT,
+            budget: None,
         }
     }
 
-    fn set_root(&mut self, root_url: String) {
-        self.seen_children
-            .insert("ROOT".to_string(), (root_url, TreeIdFactory::default()));
+    fn set_root(&mut self, root_url: String, opts: &TopologyOptions) {
+        self.topology = Some(Topology::new(root_url, opts.clone()));
     }
 
-    fn _pivot_get_closest_id_match(&mut self, locator: &String) -> Result<(String, TreeIdFactory)> {
-        /* Exact match */
-        if let Some(_) = self.seen_children.get(locator) {
-            return Err(anyhow!("Process {:?} is already registered", locator));
-        }
+    fn pivot(&mut self, process_info: &ProcessInfo, from: String) -> Result<(u64, String)> {
+        self.topology
+            .as_mut()
+            .ok_or(anyhow!("It is only possible to pivot on root process"))?
+            .place(process_info, from)
+    }
 
-        /* If root is not full use it */
-        let (url, root) = self
-            .seen_children
-            .get_mut("ROOT")
-            .expect("It is only possible to pivot on root process");
+    fn join(&mut self, id: u64, process_info: &ProcessInfo, targ: String) -> Result<()> {
+        let mut client = GdbClient::new(targ.as_str())?;
+        client.id = id;
+        client.tag = process_info.tag();
+        self.children.push(client);
+        Ok(())
+    }
 
-        if !root.full() {
-            log::info!("PIVOT {} is Joining ROOT", locator);
-            return Ok((url.clone(), root.inherit()?));
-        }
+    /// Time given to the children to answer `cmd`, a part of the
+    /// time of this node is kept for it to merge and send the results
+    fn children_budget(&self, cmd: &GdbMachineCommand) -> Duration {
+        let budget = self.budget.unwrap_or(cmd.deadline(self.timeout));
+        let margin = (budget / TIMEOUT_MARGIN_RATIO).max(MIN_TIMEOUT_MARGIN);
 
-        /* Now attempt closest match */
-        let mut mtch = None;
-        let mut distance: u64 = u64::MAX;
+        budget.saturating_sub(margin).max(MIN_TIMEOUT_MARGIN)
+    }
 
-        self.seen_children
-            .iter()
-            .filter(|(_, v)| !v.1.full())
-            .for_each(|(k, _)| {
-                let dist = strdistance(k, &locator);
+    fn run_on_children(&mut self, cmd: GdbMachineCommand) -> Vec<ChildReply> {
+        let budget = self.children_budget(&cmd);
 
-                if dist < distance {
-                    distance = dist;
-                    mtch = Some(k.clone());
-                }
-            });
+        GdbClient::exchange(&mut self.children, &cmd, Some(budget))
+            .into_iter()
+            .zip(self.children.iter())
+            .map(|(resp, c)| ChildReply {
+                id: c.id,
+                tag: c.tag.clone(),
+                size: c.size,
+                resp,
+            })
+            .collect()
+    }
 
-        match mtch {
-            Some(m) => {
-                if m == "ROOT" {
-                    unimplemented!("Distance match should not capture root");
-                }
-                /* We are sure the key exists as k is from keys */
-                if let Some((url, val)) = self.seen_children.get_mut(&m) {
-                    log::info!("PIVOT {} is Joining {}", locator, m);
-                    return Ok((url.clone(), val.inherit()?));
-                } else {
-                    unreachable!("Key must exist");
-                }
-            }
-            None => unreachable!("Root should have had at least another child"),
+    fn breakpoint_on_children(&mut self, cmd: GdbMachineCommand) -> Result<u32> {
+        if self.children.is_empty() {
+            return Ok(0);
         }
-    }
 
-    fn pivot(&mut self, process_info: &ProcessInfo, from: String) -> Result<(u64, String)> {
-        /* Generate range for new entry */
-        let (url, new_range) =
-            self._pivot_get_closest_id_match(&process_info.locality_descriptor)?;
+        let resps = ChildReply::answered(self.run_on_children(cmd));
 
-        /* Let new id */
-        let id = new_range.id();
+        TreeState::all_resp_ok(&resps)?;
 
-        /* Insert range to locator */
-        self.seen_children
-            .insert(process_info.locality_descriptor.clone(), (from, new_range));
+        let nums = resps
+            .into_iter()
+            .map(|v| v.breakpoint())
+            .collect::<Result<Vec<_>>>()?;
 
-        Ok((id, url))
+        merge_breakpoint_numbers(nums)
     }
 
-    fn join(&mut self, targ: String) -> Result<()> {
-        let client = GdbClient::new(targ.as_str())?;
-        self.children.push(client);
-        Ok(())
-    }
+    /// Merge the symbols of the children which could list them,
+    /// failing only if none could
+    fn symbols_on_children(&mut self, pattern: Option<String>) -> Result<SymbolTable> {
+        if self.children.is_empty() {
+            return Ok(SymbolTable::default());
+        }
 
-    fn run_on_children(&mut self, cmd: GdbMachineCommand) -> Result<Vec<GdbMachineResponse>> {
-        let ret = self
-            .children
-            .par_iter_mut()
-            .map(|c| c.do_command(&cmd))
-            .collect::<Vec<_>>();
+        let mut tables = Vec::new();
+        let mut errs = Vec::new();
 
-        let ret: Result<Vec<GdbMachineResponse>> = ret.into_iter().collect();
+        for resp in
+            ChildReply::answered(self.run_on_children(GdbMachineCommand::GetSymbols(pattern)))
+        {
+            match resp.symbols() {
+                Ok(st) => tables.push(st),
+                Err(e) => {
+                    log::warn!("Could not retrieve symbols: {}", e);
+                    errs.push(e.to_string());
+                }
+            }
+        }
 
-        ret
+        if tables.is_empty() && !errs.is_empty() {
+            return Err(anyhow!("{}", errs.join(",")));
+        }
+
+        Ok(SymbolTable::merge_all(tables))
     }
 
     fn all_resp_ok(resps: &Vec<GdbMachineResponse>) -> Result<()> {
@@ -349,8 +584,38 @@ impl TreeState {
                         "Incompatible type to be merged Count".to_string(),
                     )),
                 },
-                GdbMachineResponse::Symbols(_) => todo!(),
-                GdbMachineResponse::Pivot(_, _) => {
+                GdbMachineResponse::Breakpoint(n1) => match r2 {
+                    GdbMachineResponse::Breakpoint(n2) => {
+                        Some(GdbMachineResponse::breakpoint_from_result(
+                            merge_breakpoint_numbers([n1, n2]),
+                        ))
+                    }
+                    GdbMachineResponse::Error(e) => Some(GdbMachineResponse::Error(e.to_string())),
+                    _ => Some(GdbMachineResponse::Error(
+                        "Incompatible type to be merged Breakpoint".to_string(),
+                    )),
+                },
+                GdbMachineResponse::Output(mut o1) => match r2 {
+                    GdbMachineResponse::Output(o2) => {
+                        o1.extend(o2);
+                        Some(GdbMachineResponse::Output(o1))
+                    }
+                    GdbMachineResponse::Error(e) => Some(GdbMachineResponse::Error(e.to_string())),
+                    _ => Some(GdbMachineResponse::Error(
+                        "Incompatible type to be merged Output".to_string(),
+                    )),
+                },
+                GdbMachineResponse::Symbols(mut s1) => match r2 {
+                    GdbMachineResponse::Symbols(s2) => {
+                        s1.merge(s2);
+                        Some(GdbMachineResponse::Symbols(s1))
+                    }
+                    GdbMachineResponse::Error(e) => Some(GdbMachineResponse::Error(e.to_string())),
+                    _ => Some(GdbMachineResponse::Error(
+                        "Incompatible type to be merged Symbols".to_string(),
+                    )),
+                },
+                GdbMachineResponse::Pivot(_, _) | GdbMachineResponse::Certificate(_) => {
                     todo!()
                 }
             }
@@ -386,7 +651,9 @@ impl Debugger for TreeState {
             return Ok(());
         }
 
-        TreeState::all_resp_ok(&self.run_on_children(GdbMachineCommand::Start)?)
+        TreeState::all_resp_ok(&ChildReply::all_answered(
+            self.run_on_children(GdbMachineCommand::Start),
+        )?)
     }
 
     fn count(&mut self) -> Result<u64> {
@@ -394,18 +661,19 @@ impl Debugger for TreeState {
             return Ok(0);
         }
 
-        let ret = &self.run_on_children(GdbMachineCommand::Count)?;
+        let resps = ChildReply::all_answered(self.run_on_children(GdbMachineCommand::Count))?;
 
-        let ret = ret
-            .iter()
-            .filter_map(|v| {
-                if let GdbMachineResponse::Count(c) = v {
-                    Some(c)
-                } else {
-                    None
-                }
-            })
-            .sum::<u64>();
+        TreeState::all_resp_ok(&resps)?;
+
+        let mut ret = 0;
+
+        /* Kept to weight the subtrees which do not answer snapshots */
+        for (resp, child) in resps.iter().zip(self.children.iter_mut()) {
+            if let GdbMachineResponse::Count(c) = resp {
+                child.size = *c;
+                ret += c;
+            }
+        }
 
         Ok(ret)
     }
@@ -415,7 +683,9 @@ impl Debugger for TreeState {
             return Ok(());
         }
 
-        TreeState::all_resp_ok(&self.run_on_children(GdbMachineCommand::Stop)?)
+        TreeState::all_resp_ok(&ChildReply::all_answered(
+            self.run_on_children(GdbMachineCommand::Stop),
+        )?)
     }
 
     fn cont(&mut self) -> Result<()> {
@@ -423,7 +693,37 @@ impl Debugger for TreeState {
             return Ok(());
         }
 
-        TreeState::all_resp_ok(&self.run_on_children(GdbMachineCommand::Continue)?)
+        TreeState::all_resp_ok(&ChildReply::all_answered(
+            self.run_on_children(GdbMachineCommand::Continue),
+        )?)
+    }
+
+    fn detach(&mut self) -> Result<()> {
+        if self.children.is_empty() {
+            return Ok(());
+        }
+
+        TreeState::all_resp_ok(&ChildReply::all_answered(
+            self.run_on_children(GdbMachineCommand::Detach),
+        )?)
+    }
+
+    fn set_breakpoint(&mut self, location: &str) -> Result<u32> {
+        self.breakpoint_on_children(GdbMachineCommand::SetBreakpoint(location.to_string()))
+    }
+
+    fn set_watchpoint(&mut self, expr: &str, kind: WatchKind) -> Result<u32> {
+        self.breakpoint_on_children(GdbMachineCommand::SetWatchpoint(expr.to_string(), kind))
+    }
+
+    fn delete_breakpoint(&mut self, num: u32) -> Result<()> {
+        if self.children.is_empty() {
+            return Ok(());
+        }
+
+        TreeState::all_resp_ok(&ChildReply::all_answered(
+            self.run_on_children(GdbMachineCommand::DeleteBreakpoint(num)),
+        )?)
     }
 
     fn state(&mut self) -> Result<HashMap<u64, RunState>> {
@@ -433,35 +733,47 @@ impl Debugger for TreeState {
             return Ok(ret);
         }
 
-        let resps = self.run_on_children(GdbMachineCommand::GetState)?;
-
-        TreeState::all_resp_ok(&resps)?;
-
-        for resp in resps {
-            if let GdbMachineResponse::State(st) = resp {
-                ret.extend(st.into_iter());
-            }
+        /* Children which do not answer are reported as dead */
+        for reply in self.run_on_children(GdbMachineCommand::GetState) {
+            let reason = match reply.resp {
+                Ok(GdbMachineResponse::State(st)) => {
+                    ret.extend(st);
+                    continue;
+                }
+                Ok(GdbMachineResponse::Error(e)) => e,
+                Ok(_) => "Bad response for state".to_string(),
+                Err(e) => e.to_string(),
+            };
+
+            ret.insert(
+                reply.id,
+                RunState::Dead(format!("{}: {}", reply.tag, reason)),
+            );
         }
 
         Ok(ret)
     }
 
-    fn snapshot(&mut self) -> Result<HashMap<u64, (u64, Vec<BacktraceState>)>> {
+    fn snapshot(
+        &mut self,
+        opts: &SnapshotOptions,
+    ) -> Result<HashMap<u64, (u64, Vec<BacktraceState>)>> {
         if self.children.is_empty() {
             return Ok(HashMap::new());
         }
 
-        let resps = self.run_on_children(GdbMachineCommand::GetSnapshot)?;
-
-        TreeState::all_resp_ok(&resps)?;
-
-        let components: Vec<HashMap<u64, (u64, Vec<BacktraceState>)>> = resps
+        /* Keep what arrived, the others are listed as missing */
+        let components: Vec<HashMap<u64, (u64, Vec<BacktraceState>)>> = self
+            .run_on_children(GdbMachineCommand::GetSnapshot(opts.clone()))
             .into_iter()
-            .filter_map(|v| {
-                if let GdbMachineResponse::Snapshot(st) = v {
-                    return Some(st);
-                }
-                None
+            .map(|reply| {
+                let error = match reply.resp {
+                    Ok(GdbMachineResponse::Snapshot(st)) => return st,
+                    Ok(GdbMachineResponse::Error(e)) => e,
+                    Ok(_) => "Bad response".to_string(),
+                    Err(e) => e.to_string(),
+                };
+                ProgramSnapshot::no_response(reply.tag, error, reply.size)
             })
             .collect();
 
@@ -469,12 +781,36 @@ impl Debugger for TreeState {
     }
 
     fn symbols(&mut self) -> Result<SymbolTable> {
-        todo!()
+        self.symbols_on_children(None)
+    }
+
+    fn find_functions(&mut self, pattern: &str) -> Result<SymbolTable> {
+        self.symbols_on_children(Some(pattern.to_string()))
+    }
+
+    fn output(&mut self) -> Result<HashMap<u64, ProcessOutput>> {
+        let mut ret = HashMap::new();
+
+        if self.children.is_empty() {
+            return Ok(ret);
+        }
+
+        let resps = ChildReply::all_answered(self.run_on_children(GdbMachineCommand::GetOutput))?;
+
+        TreeState::all_resp_ok(&resps)?;
+
+        for resp in resps {
+            if let GdbMachineResponse::Output(out) = resp {
+                ret.extend(out);
+            }
+        }
+
+        Ok(ret)
     }
 }
 
 pub struct GdbMachine {
-    listening_sock: TcpListener,
+    listening_sock: Listener,
     host: String,
     dbg: Arc<Mutex<Box<dyn Debugger>>>,
     state: Arc<Mutex<Box<dyn Debugger>>>,
@@ -484,7 +820,7 @@ impl GdbMachine {
     pub fn new(bindaddr: &str, dbg: Arc<Mutex<Box<dyn Debugger>>>) -> Result<GdbMachine> {
         let address = SocketAddr::from_str(bindaddr)?;
 
-        let listening_sock = TcpListener::bind(address)?;
+        let listening_sock = Listener::bind(address)?;
 
         let host = gethostname::gethostname()
             .to_str()
@@ -501,26 +837,68 @@ impl GdbMachine {
         Ok(ret)
     }
 
-    pub fn local(command: &[String]) -> Result<RootDebugger> {
-        let v: Vec<&str> = command.iter().map(|x| &**x).collect();
-        let mut gdb = GdbMi::run(v.as_slice())?;
+    /// Start the debugger selected in `config` on `target`
+    ///
+    /// The debugger process is returned for the caller to kill it on exit
+    fn launch_debugger(
+        target: GdbMiRemote,
+        config: &DebuggerConfig,
+    ) -> Result<(Box<dyn Debugger>, Option<Child>)> {
+        if config.use_lldb() {
+            let mut lldb = Lldb::with_config(target, config)?;
+            let child_proc = lldb.take_child();
+            return Ok((Box::new(lldb), child_proc));
+        }
 
+        let mut gdb = GdbMi::with_config(target, config)?;
         let child_proc = gdb.take_child();
+        Ok((Box::new(gdb), child_proc))
+    }
 
-        return Ok(RootDebugger {
-            state: Arc::new(Mutex::new(Box::new(gdb))),
-            child_proc,
-        });
+    /// Debug the given targets locally
+    ///
+    /// A single target is driven directly while several targets
+    /// are aggregated in a [DebuggerGroup]
+    pub fn local(mut targets: Vec<GdbMiRemote>, config: &DebuggerConfig) -> Result<RootDebugger> {
+        if targets.len() == 1 {
+            let (dbg, child_proc) = GdbMachine::launch_debugger(targets.remove(0), config)?;
+
+            return Ok(RootDebugger {
+                state: Arc::new(Mutex::new(dbg)),
+                child_proc,
+            });
+        }
+
+        let members = targets
+            .into_par_iter()
+            .map(|t| GdbMachine::launch_debugger(t, config).map(|(dbg, _)| dbg))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(RootDebugger {
+            state: Arc::new(Mutex::new(Box::new(DebuggerGroup::new(members)))),
+            child_proc: None,
+        })
     }
 
-    pub fn run_as_leaf(root: String, command: &[String]) -> Result<()> {
-        let v: Vec<&str> = command.iter().map(|x| &**x).collect();
-        let gdb = GdbMi::run(v.as_slice())?;
+    pub fn run_as_leaf(root: String, target: GdbMiRemote, config: &DebuggerConfig) -> Result<()> {
+        let (dbg, _) = GdbMachine::launch_debugger(target, config)?;
 
-        let server = GdbMachine::new("0.0.0.0:0", gdb.instance())?;
+        GdbMachine::run_as_leaf_with_debugger(root, dbg)
+    }
+
+    /// Join the tree of `root` serving the commands with `dbg`
+    pub fn run_as_leaf_with_debugger(root: String, dbg: Box<dyn Debugger>) -> Result<()> {
+        let server = Arc::new(GdbMachine::new("0.0.0.0:0", Arc::new(Mutex::new(dbg)))?);
+
+        /* Our parent connects back to us before answering the join */
+        let srv = server.clone();
+        let serving = thread::spawn(move || srv.run());
 
         let mut client = GdbClient::new(&root)?;
 
+        /* Peers connecting back to us check our certificate */
+        client.certify()?;
+
         let (id, targ) = client.pivot(server.url()?)?;
 
         server.set_id(id);
@@ -531,13 +909,13 @@ impl GdbMachine {
 
         // Now we notify the new client we want him to join us
         let mut client = GdbClient::new(&targ)?;
-        client.join(server.url()?)?;
+        client.join(id, server.url()?)?;
         //We are done the targ is conncted to our local server
         drop(client);
 
-        server.run()?;
-
-        Ok(())
+        serving
+            .join()
+            .map_err(|_| anyhow!("The server of the leaf panicked"))?
     }
 
     pub fn wait_for_child(&self, child_count: usize) -> Result<()> {
@@ -558,9 +936,9 @@ impl GdbMachine {
         Ok(())
     }
 
-    pub fn run_as_root() -> Result<(Arc<GdbMachine>, RootDebugger)> {
+    pub fn run_as_root(opts: &TopologyOptions) -> Result<(Arc<GdbMachine>, RootDebugger)> {
         let srv = GdbMachine::new("0.0.0.0:0", DummyDebugger::instance())?;
-        srv.set_master();
+        srv.set_master(opts);
 
         let srv = Arc::new(srv);
 
@@ -581,21 +959,46 @@ impl GdbMachine {
         dbg: Arc<Mutex<Box<dyn Debugger>>>,
         state: Option<Arc<Mutex<Box<dyn Debugger>>>>,
         cmd: &GdbMachineCommand,
+        budget: Option<Duration>,
     ) -> Option<GdbMachineResponse> {
         let mut dbg = dbg.lock().unwrap();
 
+        if let Some(tree_state) = dbg.as_treestate() {
+            tree_state.budget = budget;
+        }
+
         match cmd {
             GdbMachineCommand::Start => Some(GdbMachineResponse::from_result(dbg.start())),
             GdbMachineCommand::Stop => Some(GdbMachineResponse::from_result(dbg.stop())),
             GdbMachineCommand::Continue => Some(GdbMachineResponse::from_result(dbg.cont())),
+            GdbMachineCommand::Detach => Some(GdbMachineResponse::from_result(dbg.detach())),
+            GdbMachineCommand::SetBreakpoint(location) => Some(
+                GdbMachineResponse::breakpoint_from_result(dbg.set_breakpoint(location)),
+            ),
+            GdbMachineCommand::SetWatchpoint(expr, kind) => Some(
+                GdbMachineResponse::breakpoint_from_result(dbg.set_watchpoint(expr, *kind)),
+            ),
+            GdbMachineCommand::DeleteBreakpoint(num) => {
+                Some(GdbMachineResponse::from_result(dbg.delete_breakpoint(*num)))
+            }
             GdbMachineCommand::GetState => Some(GdbMachineResponse::from_state(dbg.state())),
-            GdbMachineCommand::GetSnapshot => {
-                Some(GdbMachineResponse::snapshot_from_result(dbg.snapshot()))
+            GdbMachineCommand::GetSnapshot(opts) => {
+                Some(GdbMachineResponse::snapshot_from_result(dbg.snapshot(opts)))
             }
-            GdbMachineCommand::GetSymbols => {
-                Some(GdbMachineResponse::symbols_from_result(dbg.symbols()))
+            GdbMachineCommand::GetSymbols(pattern) => {
+                let ret = match pattern {
+                    Some(p) => dbg.find_functions(p),
+                    None => dbg.symbols(),
+                };
+                Some(GdbMachineResponse::symbols_from_result(ret))
+            }
+            GdbMachineCommand::GetOutput => {
+                Some(GdbMachineResponse::output_from_result(dbg.output()))
             }
-            GdbMachineCommand::Count => Some(GdbMachineResponse::Count(dbg.count().unwrap_or(0))),
+            GdbMachineCommand::Count => Some(match dbg.count() {
+                Ok(c) => GdbMachineResponse::Count(c),
+                Err(e) => GdbMachineResponse::Error(e.to_string()),
+            }),
             GdbMachineCommand::Pivot(process_info, from) => {
                 let ret = if let Some(state) = state {
                     let mut state = state.lock().unwrap();
@@ -611,12 +1014,17 @@ impl GdbMachine {
                 };
                 ret
             }
-            GdbMachineCommand::Join(target) => {
+            /* Signed once by the root, not by its subtree */
+            GdbMachineCommand::Certify(key) => state.map(|_| match TlsSession::sign(key) {
+                Ok(cert) => GdbMachineResponse::Certificate(cert),
+                Err(e) => GdbMachineResponse::Error(e.to_string()),
+            }),
+            GdbMachineCommand::Join(id, process_info, target) => {
                 let ret = if let Some(state) = state {
                     let mut state = state.lock().unwrap();
                     let tree_state = state.as_mut().as_treestate().unwrap();
 
-                    match tree_state.join(target.clone()) {
+                    match tree_state.join(*id, process_info, target.clone()) {
                         Ok(()) => Some(GdbMachineResponse::Ok),
                         Err(e) => Some(GdbMachineResponse::Error(e.to_string())),
                     }
@@ -632,91 +1040,62 @@ impl GdbMachine {
         dbg: Arc<Mutex<Box<dyn Debugger>>>,
         state: Arc<Mutex<Box<dyn Debugger>>>,
         cmd: GdbMachineCommand,
+        budget: Option<Duration>,
     ) -> GdbMachineResponse {
         let mut remote_result = None;
         let mut local_result = None;
 
-        let st1 = state.clone();
-        let st2 = state.clone();
-
-        // Use rayon's scope to execute the tasks in parallel
-        scope(|s| {
-            s.spawn(|_| {
-                // Execute the first command in a separate thread
-                remote_result = GdbMachine::do_cmd(st1, None, &cmd);
+        /* The subtree only waits for the answers of the children, it is not
+        run on the rayon pool which may be busy with the local debuggers */
+        thread::scope(|s| {
+            s.spawn(|| {
+                remote_result = GdbMachine::do_cmd(state.clone(), None, &cmd, budget);
             });
 
-            s.spawn(|_| {
-                // Execute the second command in a separate thread
-                local_result = GdbMachine::do_cmd(dbg, Some(st2), &cmd);
-            });
+            local_result = GdbMachine::do_cmd(dbg, Some(state.clone()), &cmd, budget);
         });
 
-        if let Some(local) = local_result {
-            return TreeState::merge_results(local, remote_result);
-        }
-
-        GdbMachineResponse::Error("Local command did not return a response".to_string())
-    }
-
-    fn _client_loop(
-        mut sock: TcpStream,
-        dbg: Arc<Mutex<Box<dyn Debugger>>>,
-        state: Arc<Mutex<Box<dyn Debugger>>>,
-    ) -> Result<()> {
-        loop {
-            let resp = read_until_null(&mut sock)?;
-
-            if resp.is_empty() {
-                break;
+        if let Some(mut local) = local_result {
+            /* The snapshots of the subtree are kept if the local one failed */
+            if let (GdbMachineCommand::GetSnapshot(_), GdbMachineResponse::Error(e)) =
+                (&cmd, &local)
+            {
+                let tag = ProcessInfo::default()
+                    .map(|p| p.tag())
+                    .unwrap_or("?".to_string());
+                local =
+                    GdbMachineResponse::Snapshot(ProgramSnapshot::no_response(tag, e.clone(), 1));
             }
 
-            log::debug!("INBOUND: {:?}", resp);
-
-            let cmd: GdbMachineCommand = serde_json::from_str(&resp)?;
-
-            let resp = GdbMachine::_run_command(dbg.clone(), state.clone(), cmd);
-
-            log::debug!("OUTBOUND: {:?}", resp);
-
-            let resp_json = serde_json::to_string(&resp)?;
-
-            /* Write JSON */
-            sock.write_all(resp_json.as_bytes())?;
-            /* Write Separator */
-            sock.write_all("\0".as_bytes())?;
-            sock.flush()?;
+            return TreeState::merge_results(local, remote_result);
         }
 
-        Ok(())
+        GdbMachineResponse::Error("Local command did not return a response".to_string())
     }
 
     pub fn run(&self) -> Result<()> {
-        loop {
-            let (stream, _) = self.listening_sock.accept()?;
-
-            let dbg = self.dbg.clone();
-            let state = self.state.clone();
-            thread::spawn(move || match GdbMachine::_client_loop(stream, dbg, state) {
-                Ok(_) => {}
-                Err(e) => {
-                    println!("Error processing client request : {}", e);
-                }
-            });
-        }
+        let dbg = self.dbg.clone();
+        let state = self.state.clone();
+
+        reactor::serve(
+            &self.listening_sock,
+            Arc::new(move |req: GdbMachineRequest| {
+                let budget = req.budget.map(Duration::from_millis);
+                GdbMachine::_run_command(dbg.clone(), state.clone(), req.cmd, budget)
+            }),
+        )
     }
 
     pub fn url(&self) -> Result<String> {
-        Ok(format!(
-            "{}:{}",
-            self.host,
-            self.listening_sock.local_addr()?.port()
-        ))
+        self.listening_sock.url(&self.host)
     }
 
-    pub fn set_master(&self) {
+    pub fn set_master(&self, opts: &TopologyOptions) {
         if let Ok(state) = self.state.lock().as_mut() {
-            state.as_treestate().unwrap().set_root(self.url().unwrap());
+            state
+                .as_treestate()
+                .unwrap()
+                .set_root(self.url().unwrap(), opts);
         }
     }
 
@@ -743,8 +1122,13 @@ impl GdbMachine {
             return None;
         }
 
-        /* We remove 1 as the root is self-pushed in this vec */
-        Some(state.as_treestate().unwrap().seen_children.len() - 1)
+        /* We remove 1 as the root is part of the topology */
+        state
+            .as_treestate()
+            .unwrap()
+            .topology
+            .as_ref()
+            .map(|t| t.len() - 1)
     }
 }
 
@@ -754,6 +1138,21 @@ pub struct RootDebugger {
 }
 
 impl RootDebugger {
+    /// Time given to the tree to answer commands
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        if let Some(st) = self.state.lock().unwrap().as_treestate() {
+            st.timeout = timeout;
+        }
+    }
+
+    /// Processes of the tree with their parent (None if not a tree)
+    pub fn topology(&mut self) -> Option<(Vec<TopologyNode>, (usize, u64))> {
+        let mut state = self.state.lock().unwrap();
+        let topology = state.as_treestate()?.topology.as_ref()?;
+
+        Some((topology.nodes(), topology.shape()))
+    }
+
     pub fn set_child(&mut self, child: Child) {
         self.child_proc = Some(child);
     }
@@ -811,6 +1210,34 @@ impl Debugger for RootDebugger {
         }
     }
 
+    fn detach(&mut self) -> Result<()> {
+        match self.state.lock().as_mut() {
+            Ok(st) => st.detach(),
+            Err(e) => Err(anyhow!(e.to_string())),
+        }
+    }
+
+    fn set_breakpoint(&mut self, location: &str) -> Result<u32> {
+        match self.state.lock().as_mut() {
+            Ok(st) => st.set_breakpoint(location),
+            Err(e) => Err(anyhow!(e.to_string())),
+        }
+    }
+
+    fn set_watchpoint(&mut self, expr: &str, kind: WatchKind) -> Result<u32> {
+        match self.state.lock().as_mut() {
+            Ok(st) => st.set_watchpoint(expr, kind),
+            Err(e) => Err(anyhow!(e.to_string())),
+        }
+    }
+
+    fn delete_breakpoint(&mut self, num: u32) -> Result<()> {
+        match self.state.lock().as_mut() {
+            Ok(st) => st.delete_breakpoint(num),
+            Err(e) => Err(anyhow!(e.to_string())),
+        }
+    }
+
     fn state(&mut self) -> Result<HashMap<u64, RunState>> {
         match self.state.lock().as_mut() {
             Ok(st) => st.state(),
@@ -818,9 +1245,12 @@ impl Debugger for RootDebugger {
         }
     }
 
-    fn snapshot(&mut self) -> Result<HashMap<u64, (u64, Vec<BacktraceState>)>> {
+    fn snapshot(
+        &mut self,
+        opts: &SnapshotOptions,
+    ) -> Result<HashMap<u64, (u64, Vec<BacktraceState>)>> {
         match self.state.lock().as_mut() {
-            Ok(st) => st.snapshot(),
+            Ok(st) => st.snapshot(opts),
             Err(e) => Err(anyhow!(e.to_string())),
         }
     }
@@ -831,4 +1261,128 @@ impl Debugger for RootDebugger {
             Err(e) => Err(anyhow!(e.to_string())),
         }
     }
+
+    fn find_functions(&mut self, pattern: &str) -> Result<SymbolTable> {
+        match self.state.lock().as_mut() {
+            Ok(st) => st.find_functions(pattern),
+            Err(e) => Err(anyhow!(e.to_string())),
+        }
+    }
+
+    fn output(&mut self) -> Result<HashMap<u64, ProcessOutput>> {
+        match self.state.lock().as_mut() {
+            Ok(st) => st.output(),
+            Err(e) => Err(anyhow!(e.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Wait up to a few seconds for `cond` to hold
+    fn eventually(mut cond: impl FnMut() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(20);
+
+        while Instant::now() < deadline {
+            if cond() {
+                return true;
+            }
+            sleep(Duration::from_millis(50));
+        }
+
+        false
+    }
+
+    fn reply(id: u64, resp: Result<GdbMachineResponse>) -> ChildReply {
+        ChildReply {
+            id,
+            tag: format!("rank {}", id),
+            size: 4,
+            resp,
+        }
+    }
+
+    #[test]
+    fn missing_children_are_reported() {
+        let replies = vec![
+            reply(2, Ok(GdbMachineResponse::Ok)),
+            reply(3, Err(anyhow!("No answer within 1.0s"))),
+        ];
+
+        let err = ChildReply::all_answered(replies).unwrap_err().to_string();
+        assert_eq!(err, "No answer from rank 3 (id 3): No answer within 1.0s");
+
+        let replies = vec![reply(2, Ok(GdbMachineResponse::Ok))];
+        assert_eq!(ChildReply::all_answered(replies).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn lost_subtrees_keep_their_size() {
+        let snap = ProgramSnapshot::no_response("rank 3".to_string(), "Lost".to_string(), 4);
+
+        assert_eq!(snap.values().map(|(cnt, _)| cnt).sum::<u64>(), 4);
+    }
+
+    #[test]
+    fn late_children_are_kept() {
+        let listener = Listener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let url = listener.url("127.0.0.1").unwrap();
+
+        /* A child answering its first and third commands late */
+        let calls = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let handler: Arc<reactor::Handler> = Arc::new(move |_| {
+            let n = calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if n.is_multiple_of(2) {
+                sleep(Duration::from_millis(500));
+            }
+            GdbMachineResponse::Count(n)
+        });
+        thread::spawn(move || reactor::serve(&listener, handler));
+
+        let mut clients = vec![GdbClient::new(&url).unwrap()];
+        let short = Some(Duration::from_millis(100));
+        let long = Some(Duration::from_secs(10));
+
+        let resp = GdbClient::exchange(&mut clients, &GdbMachineCommand::Count, short);
+        assert!(resp[0].is_err());
+        assert!(clients[0].lost.is_none());
+
+        /* The late answer to the first command is skipped */
+        let mut resp = GdbClient::exchange(&mut clients, &GdbMachineCommand::Count, long);
+        assert!(matches!(resp.remove(0), Ok(GdbMachineResponse::Count(1))));
+
+        let client = &mut clients[0];
+        assert!(client.request(&GdbMachineCommand::Count, short).is_err());
+        assert!(client.lost.is_none());
+        assert!(matches!(
+            client.request(&GdbMachineCommand::Count, long),
+            Ok(GdbMachineResponse::Count(3))
+        ));
+    }
+
+    #[test]
+    fn leaves_join_the_root() {
+        let (srv, mut root) = GdbMachine::run_as_root(&TopologyOptions::default()).unwrap();
+        let url = srv.url().unwrap();
+
+        /* Processes are told apart by their pid, a single leaf fits in a test */
+        thread::spawn(move || GdbMachine::run_as_leaf_with_debugger(url, Box::new(DummyDebugger)));
+
+        assert!(eventually(|| srv.tree_count() == Some(1)));
+
+        /* The root connected back to the leaf */
+        assert!(eventually(|| {
+            let mut state = srv.state.lock().unwrap();
+            state.as_treestate().unwrap().children.len() == 1
+        }));
+
+        root.set_timeout(Duration::from_secs(5));
+
+        /* Commands reach the debuggers of the leaves */
+        let err = root.set_breakpoint("main").unwrap_err();
+        assert!(err.to_string().contains("Dummy debugger"));
+        assert!(root.state().unwrap().is_empty());
+    }
 }
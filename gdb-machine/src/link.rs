@@ -0,0 +1,775 @@
+//! Framing of the messages exchanged between tree nodes
+//!
+//! Each message is sent as a frame made of a header, the payload length
+//! (u32 little endian) and its [WireFormat] (one byte, with [COMPRESSED_FLAG]
+//! set for compressed payloads), followed by the payload. The format is given
+//! per frame so that a node speaking JSON for debugging can talk to nodes
+//! speaking binary.
+//!
+//! When a link is established both sides exchange a [Hello] holding their
+//! protocol version and capabilities, incompatible peers are rejected and the
+//! [LinkOptions] are the ones supported by both sides. The hellos also carry
+//! the challenges used to authenticate both sides (see [crate::auth]), no
+//! message is read from a peer which did not prove its knowledge of the
+//! session token and frames are kept small until it did.
+//!
+//! Links work on blocking streams as well as on non-blocking ones driven by
+//! an event loop (see [crate::reactor]): messages are queued with
+//! [Link::queue] and written as the stream accepts them with [Link::flush],
+//! [Link::try_recv] decodes the frames received so far without waiting.
+
+use std::env;
+use std::io::{ErrorKind, Read, Write};
+
+use anyhow::{anyhow, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::auth;
+
+/* Set to json to send human readable messages */
+const ENV_WIRE_FORMAT: &str = "GDBW_WIRE_FORMAT";
+/* Set to none to disable compression */
+const ENV_COMPRESSION: &str = "GDBW_COMPRESSION";
+
+/// Set in the format byte when the payload is compressed
+const COMPRESSED_FLAG: u8 = 0x80;
+
+/// Payloads smaller than this are not worth compressing
+const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Largest payload accepted from a peer
+const MAX_FRAME_SIZE: usize = 1 << 30;
+
+/// Largest payload accepted before the peer is authenticated
+const MAX_SETUP_FRAME_SIZE: usize = 16 * 1024;
+
+/// Size of the frame header (length and format)
+const HEADER_SIZE: usize = 5;
+
+/// Bytes read from the stream at once
+const READ_CHUNK: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WireFormat {
+    /// Compact binary encoding (bincode)
+    Binary,
+    /// JSON (for debugging)
+    Json,
+}
+
+impl WireFormat {
+    /// Format selected in the environment (binary by default)
+    pub fn from_env() -> WireFormat {
+        match env::var(ENV_WIRE_FORMAT) {
+            Ok(v) if v == "json" => WireFormat::Json,
+            _ => WireFormat::Binary,
+        }
+    }
+
+    fn tag(&self) -> u8 {
+        match self {
+            WireFormat::Binary => 0,
+            WireFormat::Json => 1,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<WireFormat> {
+        match tag {
+            0 => Ok(WireFormat::Binary),
+            1 => Ok(WireFormat::Json),
+            t => Err(anyhow!("Unknown wire format {}", t)),
+        }
+    }
+
+    fn encode<T: Serialize>(&self, msg: &T) -> Result<Vec<u8>> {
+        Ok(match self {
+            WireFormat::Binary => bincode::serialize(msg)?,
+            WireFormat::Json => serde_json::to_vec(msg)?,
+        })
+    }
+
+    fn decode<T: DeserializeOwned>(&self, payload: &[u8]) -> Result<T> {
+        Ok(match self {
+            WireFormat::Binary => bincode::deserialize(payload)?,
+            WireFormat::Json => serde_json::from_slice(payload)?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Lz4,
+}
+
+impl Compression {
+    fn compress(&self, payload: Vec<u8>) -> (Vec<u8>, bool) {
+        match self {
+            Compression::Lz4 if payload.len() >= COMPRESSION_THRESHOLD => {
+                (lz4_flex::compress_prepend_size(&payload), true)
+            }
+            _ => (payload, false),
+        }
+    }
+
+    fn decompress(payload: &[u8], max_size: usize) -> Result<Vec<u8>> {
+        /* Check the announced size before allocating it */
+        let size = payload
+            .get(0..4)
+            .map(|s| u32::from_le_bytes([s[0], s[1], s[2], s[3]]) as usize)
+            .ok_or(anyhow!("Truncated compressed frame"))?;
+
+        if size > max_size {
+            return Err(anyhow!("Compressed frame of {} bytes is too large", size));
+        }
+
+        lz4_flex::decompress_size_prepended(payload).map_err(|e| anyhow!(e))
+    }
+}
+
+/// Version of the messages exchanged between nodes, bump it
+/// on any incompatible change of [crate::protocol] or of the framing
+pub const PROTOCOL_VERSION: u32 = 7;
+
+/* Peers encrypting their links (or not) cannot read our hello */
+const HELLO_FAILURE: &str =
+    "Failed to read the hello of the peer (is it a snapped instance started with the same --tls setting?)";
+
+/// Capability of nodes able to decompress LZ4 frames
+const CAPABILITY_LZ4: &str = "lz4";
+
+/// First message sent by both sides of a link
+///
+/// It is always sent as JSON for nodes of any version to read it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Hello {
+    pub protocol: u32,
+    /// Version of the crate (informative)
+    pub version: String,
+    pub capabilities: Vec<String>,
+    /// Why the link is refused (in the reply of the accepting side)
+    pub error: Option<String>,
+    /// Random challenge to be answered by the peer
+    pub challenge: Option<String>,
+    /// Answer to the challenge of the connecting side
+    pub proof: Option<Vec<u8>>,
+}
+
+/// Answer of the connecting side to the challenge of the accepting side
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Auth {
+    proof: Vec<u8>,
+}
+
+/// Outcome of the authentication sent by the accepting side
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct AuthResult {
+    error: Option<String>,
+}
+
+impl Hello {
+    /// Hello of this node
+    fn local() -> Result<Hello> {
+        let mut capabilities = Vec::new();
+
+        if !matches!(env::var(ENV_COMPRESSION), Ok(v) if v == "none") {
+            capabilities.push(CAPABILITY_LZ4.to_string());
+        }
+
+        Ok(Hello {
+            protocol: PROTOCOL_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities,
+            error: None,
+            challenge: Some(auth::challenge()?),
+            proof: None,
+        })
+    }
+
+    fn has(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "snapped {} (protocol version {})",
+            self.version, self.protocol
+        )
+    }
+
+    /// Check that a peer can be talked to
+    fn check(&self, peer: &Hello) -> Result<()> {
+        if self.protocol != peer.protocol {
+            return Err(anyhow!(
+                "Incompatible peer: it runs {} while this is {}, use the same snapped installation on all nodes",
+                peer.describe(),
+                self.describe()
+            ));
+        }
+
+        if self.version != peer.version {
+            log::warn!(
+                "Peer runs {} while this is {}",
+                peer.describe(),
+                self.describe()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Settings of a link agreed on by both sides
+#[derive(Clone, Debug)]
+pub struct LinkOptions {
+    pub compression: Compression,
+}
+
+impl LinkOptions {
+    /// Options supported by both sides
+    fn agree(local: &Hello, peer: &Hello) -> LinkOptions {
+        let compression = if local.has(CAPABILITY_LZ4) && peer.has(CAPABILITY_LZ4) {
+            Compression::Lz4
+        } else {
+            Compression::None
+        };
+
+        LinkOptions { compression }
+    }
+}
+
+/// Outcome of a non-blocking receive
+pub(crate) enum Received<T> {
+    Message(T),
+    /// The frame is not complete yet
+    Pending,
+    /// The peer closed the connection
+    Closed,
+}
+
+/// Progress of the setup of a link accepted by a node
+pub(crate) enum Accepting {
+    /// Waiting for the hello of the peer
+    Hello,
+    /// Waiting for the answer of the peer to our challenge
+    Auth {
+        local: Box<Hello>,
+        peer: Box<Hello>,
+    },
+    Ready,
+}
+
+/// A connection to another node exchanging framed messages
+///
+/// Received bytes are buffered until a frame is complete and queued
+/// messages are kept until the stream accepted them
+pub struct Link<S: Read + Write> {
+    stream: S,
+    format: WireFormat,
+    options: LinkOptions,
+    rbuf: Vec<u8>,
+    wbuf: Vec<u8>,
+    /// Bytes of `wbuf` already written
+    wpos: usize,
+    /// Largest payload accepted, small until the peer is authenticated
+    max_frame: usize,
+    /// Late responses to skip, their requests having been given up on
+    stale: usize,
+}
+
+impl<S: Read + Write> Link<S> {
+    fn new(stream: S) -> Link<S> {
+        Link {
+            stream,
+            format: WireFormat::from_env(),
+            options: LinkOptions {
+                compression: Compression::None,
+            },
+            rbuf: Vec::new(),
+            wbuf: Vec::new(),
+            wpos: 0,
+            max_frame: MAX_SETUP_FRAME_SIZE,
+            stale: 0,
+        }
+    }
+
+    fn recv_hello(&mut self) -> Result<Hello> {
+        self.recv::<Hello>()
+            .and_then(|h| h.ok_or(anyhow!("Connection closed while setting up the link")))
+            .context(HELLO_FAILURE)
+    }
+
+    fn recv_setup<T: DeserializeOwned>(&mut self) -> Result<T> {
+        self.recv::<T>()?
+            .ok_or(anyhow!("Connection closed while setting up the link"))
+    }
+
+    /// Establish a link to a node listening on `stream`
+    pub fn connect(stream: S) -> Result<Link<S>> {
+        let mut ret = Link::new(stream);
+        let local = Hello::local()?;
+
+        ret.send_as(WireFormat::Json, &local)?;
+
+        let peer = ret.recv_hello()?;
+
+        if let Some(e) = &peer.error {
+            return Err(anyhow!("Link refused by {}: {}", peer.describe(), e));
+        }
+
+        local.check(&peer)?;
+
+        /* The peer proves itself first, then we answer its challenge */
+        let (Some(ours), Some(proof), Some(theirs)) =
+            (&local.challenge, &peer.proof, &peer.challenge)
+        else {
+            return Err(anyhow!("Peer {} did not authenticate", peer.describe()));
+        };
+
+        auth::verify(auth::ROLE_SERVER, ours, proof)?;
+
+        let answer = Auth {
+            proof: auth::proof(auth::ROLE_CLIENT, theirs)?,
+        };
+        ret.send_as(WireFormat::Json, &answer)?;
+
+        if let Some(e) = ret.recv_setup::<AuthResult>()?.error {
+            return Err(anyhow!("Link refused by {}: {}", peer.describe(), e));
+        }
+
+        ret.options = LinkOptions::agree(&local, &peer);
+        ret.max_frame = MAX_FRAME_SIZE;
+
+        Ok(ret)
+    }
+
+    /// Start the setup of a link with a node which connected to us,
+    /// it is driven by [Link::accept_step] as the peer sends its messages
+    pub(crate) fn accept(stream: S) -> (Link<S>, Accepting) {
+        (Link::new(stream), Accepting::Hello)
+    }
+
+    /// Handle the setup messages received so far, replies are queued
+    pub(crate) fn accept_step(&mut self, step: Accepting) -> Result<Accepting> {
+        match step {
+            Accepting::Hello => match self.try_recv::<Hello>().context(HELLO_FAILURE)? {
+                Received::Message(peer) => {
+                    let local = self.answer_hello(&peer)?;
+                    self.accept_step(Accepting::Auth {
+                        local: Box::new(local),
+                        peer: Box::new(peer),
+                    })
+                }
+                Received::Pending => Ok(Accepting::Hello),
+                Received::Closed => Err(anyhow!("Connection closed while setting up the link")),
+            },
+            Accepting::Auth { local, peer } => match self.try_recv::<Auth>()? {
+                Received::Message(answer) => {
+                    self.check_answer(&local, &answer)?;
+                    self.options = LinkOptions::agree(&local, &peer);
+                    self.max_frame = MAX_FRAME_SIZE;
+                    Ok(Accepting::Ready)
+                }
+                Received::Pending => Ok(Accepting::Auth { local, peer }),
+                Received::Closed => Err(anyhow!("Connection closed while setting up the link")),
+            },
+            Accepting::Ready => Ok(Accepting::Ready),
+        }
+    }
+
+    /// Queue our hello, refusing the peer if it cannot be talked to
+    fn answer_hello(&mut self, peer: &Hello) -> Result<Hello> {
+        let mut local = Hello::local()?;
+
+        let checked = local.check(peer).and_then(|_| {
+            let theirs = peer
+                .challenge
+                .as_ref()
+                .ok_or(anyhow!("Peer did not send an authentication challenge"))?;
+            local.proof = Some(auth::proof(auth::ROLE_SERVER, theirs)?);
+            Ok(())
+        });
+
+        if let Err(e) = checked {
+            local.error = Some(e.to_string());
+            self.queue_as(WireFormat::Json, &local)?;
+            return Err(e);
+        }
+
+        self.queue_as(WireFormat::Json, &local)?;
+
+        Ok(local)
+    }
+
+    /// Check the answer of the peer to our challenge, nothing
+    /// else is read before it succeeded
+    fn check_answer(&mut self, local: &Hello, answer: &Auth) -> Result<()> {
+        let ours = local.challenge.as_deref().unwrap_or_default();
+
+        if let Err(e) = auth::verify(auth::ROLE_CLIENT, ours, &answer.proof) {
+            self.queue_as(
+                WireFormat::Json,
+                &AuthResult {
+                    error: Some(e.to_string()),
+                },
+            )?;
+            return Err(e);
+        }
+
+        self.queue_as(WireFormat::Json, &AuthResult { error: None })
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn send<T: Serialize>(&mut self, msg: &T) -> Result<()> {
+        self.send_as(self.format, msg)
+    }
+
+    fn send_as<T: Serialize>(&mut self, format: WireFormat, msg: &T) -> Result<()> {
+        self.queue_as(format, msg)?;
+
+        /* Blocking streams take all the queued bytes */
+        self.flush()?;
+
+        Ok(())
+    }
+
+    /// Queue a message to be written by [Link::flush]
+    pub(crate) fn queue<T: Serialize>(&mut self, msg: &T) -> Result<()> {
+        self.queue_as(self.format, msg)
+    }
+
+    fn queue_as<T: Serialize>(&mut self, format: WireFormat, msg: &T) -> Result<()> {
+        let payload = format.encode(msg)?;
+        let (payload, compressed) = self.options.compression.compress(payload);
+
+        if payload.len() > MAX_FRAME_SIZE {
+            return Err(anyhow!("Message of {} bytes is too large", payload.len()));
+        }
+
+        let mut tag = format.tag();
+
+        if compressed {
+            tag |= COMPRESSED_FLAG;
+        }
+
+        if self.wpos == self.wbuf.len() {
+            self.wbuf.clear();
+            self.wpos = 0;
+        }
+
+        self.wbuf
+            .extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        self.wbuf.push(tag);
+        self.wbuf.extend_from_slice(&payload);
+
+        Ok(())
+    }
+
+    /// Write the queued messages, returns false if the stream
+    /// would block before all of them were written
+    pub(crate) fn flush(&mut self) -> Result<bool> {
+        while self.wpos < self.wbuf.len() {
+            match self.stream.write(&self.wbuf[self.wpos..]) {
+                Ok(0) => return Err(anyhow!("Connection closed while sending")),
+                Ok(n) => self.wpos += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        /* TLS streams may still hold encrypted records */
+        match self.stream.flush() {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+
+        self.wbuf.clear();
+        self.wpos = 0;
+
+        Ok(true)
+    }
+
+    /// Give up on the response to the last request, it is
+    /// skipped when it arrives so that the next one is read
+    pub(crate) fn skip_response(&mut self) {
+        self.stale += 1;
+    }
+
+    /// Decode the first frame of the received bytes if it is complete
+    fn decode_frame<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        let len = loop {
+            if self.rbuf.len() < HEADER_SIZE {
+                return Ok(None);
+            }
+
+            let header = &self.rbuf[..HEADER_SIZE];
+            let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+
+            if len > self.max_frame {
+                return Err(anyhow!("Frame of {} bytes is too large", len));
+            }
+
+            if self.rbuf.len() < HEADER_SIZE + len {
+                self.rbuf.reserve(HEADER_SIZE + len - self.rbuf.len());
+                return Ok(None);
+            }
+
+            if self.stale == 0 {
+                break len;
+            }
+
+            /* Late response to a request given up on */
+            self.rbuf.drain(..HEADER_SIZE + len);
+            self.stale -= 1;
+        };
+
+        let tag = self.rbuf[4];
+        let format = WireFormat::from_tag(tag & !COMPRESSED_FLAG)?;
+
+        let payload = &self.rbuf[HEADER_SIZE..HEADER_SIZE + len];
+
+        let msg = if tag & COMPRESSED_FLAG != 0 {
+            format.decode(&Compression::decompress(payload, self.max_frame)?)?
+        } else {
+            format.decode(payload)?
+        };
+
+        self.rbuf.drain(..HEADER_SIZE + len);
+
+        Ok(Some(msg))
+    }
+
+    /// Read what the stream holds, returns 0 on end of stream
+    fn fill(&mut self) -> std::io::Result<usize> {
+        let start = self.rbuf.len();
+        self.rbuf.resize(start + READ_CHUNK, 0);
+
+        let ret = self.stream.read(&mut self.rbuf[start..]);
+
+        self.rbuf.truncate(start + *ret.as_ref().unwrap_or(&0));
+
+        ret
+    }
+
+    fn closed<T>(&self) -> Result<Received<T>> {
+        match self.rbuf.is_empty() {
+            true => Ok(Received::Closed),
+            false => Err(anyhow!("Connection closed in the middle of a message")),
+        }
+    }
+
+    /// Receive a message without waiting for the stream (non-blocking)
+    pub(crate) fn try_recv<T: DeserializeOwned>(&mut self) -> Result<Received<T>> {
+        loop {
+            if let Some(msg) = self.decode_frame()? {
+                return Ok(Received::Message(msg));
+            }
+
+            match self.fill() {
+                Ok(0) => return self.closed(),
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(Received::Pending),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Receive a message, returns None if the peer closed the connection
+    pub fn recv<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        loop {
+            if let Some(msg) = self.decode_frame()? {
+                return Ok(Some(msg));
+            }
+
+            match self.fill() {
+                Ok(0) => {
+                    self.closed::<T>()?;
+                    return Ok(None);
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                /* Including timeouts for callers to tell them apart */
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    use crate::protocol::{GdbMachineCommand, GdbMachineRequest, GdbMachineResponse};
+
+    fn request(location: &str) -> GdbMachineRequest {
+        GdbMachineRequest {
+            cmd: GdbMachineCommand::SetBreakpoint(location.to_string()),
+            budget: Some(1500),
+        }
+    }
+
+    fn location(req: GdbMachineRequest) -> String {
+        match req.cmd {
+            GdbMachineCommand::SetBreakpoint(l) => l,
+            other => panic!("Unexpected command {:?}", other),
+        }
+    }
+
+    /// Both ends of a socket pair, linked through the whole setup
+    fn linked_pair() -> (Link<UnixStream>, Link<UnixStream>) {
+        let (a, b) = UnixStream::pair().unwrap();
+
+        let connecting = thread::spawn(move || Link::connect(a).unwrap());
+
+        /* Driven like the reactor does, replies are flushed between steps */
+        b.set_nonblocking(true).unwrap();
+        let (mut accepted, mut step) = Link::accept(b);
+
+        loop {
+            step = accepted.accept_step(step).unwrap();
+            accepted.flush().unwrap();
+
+            if matches!(step, Accepting::Ready) {
+                break;
+            }
+
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        accepted.get_mut().set_nonblocking(false).unwrap();
+
+        (connecting.join().unwrap(), accepted)
+    }
+
+    /// Links set up without the handshake
+    fn plain_pair() -> (Link<UnixStream>, Link<UnixStream>) {
+        let (a, b) = UnixStream::pair().unwrap();
+        let (mut a, mut b) = (Link::new(a), Link::new(b));
+        a.max_frame = MAX_FRAME_SIZE;
+        b.max_frame = MAX_FRAME_SIZE;
+        (a, b)
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let (mut a, mut b) = plain_pair();
+
+        for format in [WireFormat::Binary, WireFormat::Json] {
+            a.send_as(format, &request("main.c:42")).unwrap();
+            assert_eq!(location(b.recv().unwrap().unwrap()), "main.c:42");
+        }
+
+        /* Large payloads are compressed once agreed on */
+        let large = "f".repeat(10 * COMPRESSION_THRESHOLD);
+        a.options.compression = Compression::Lz4;
+
+        for format in [WireFormat::Binary, WireFormat::Json] {
+            a.send_as(format, &request(&large)).unwrap();
+            assert_eq!(location(b.recv().unwrap().unwrap()), large);
+        }
+
+        b.send(&GdbMachineResponse::Breakpoint(3)).unwrap();
+        assert!(matches!(
+            a.recv().unwrap(),
+            Some(GdbMachineResponse::Breakpoint(3))
+        ));
+
+        drop(a);
+        assert!(b.recv::<GdbMachineRequest>().unwrap().is_none());
+    }
+
+    #[test]
+    fn partial_frames_are_pending() {
+        let (mut raw, b) = UnixStream::pair().unwrap();
+        let mut b = Link::new(b);
+        b.get_mut().set_nonblocking(true).unwrap();
+
+        let frame = {
+            let (a, _) = UnixStream::pair().unwrap();
+            let mut a = Link::new(a);
+            a.queue_as(WireFormat::Binary, &request("foo")).unwrap();
+            a.wbuf.clone()
+        };
+
+        for byte in &frame[..frame.len() - 1] {
+            raw.write_all(&[*byte]).unwrap();
+            assert!(matches!(
+                b.try_recv::<GdbMachineRequest>().unwrap(),
+                Received::Pending
+            ));
+        }
+
+        raw.write_all(&frame[frame.len() - 1..]).unwrap();
+
+        match b.try_recv::<GdbMachineRequest>().unwrap() {
+            Received::Message(req) => assert_eq!(location(req), "foo"),
+            _ => panic!("The frame was not decoded"),
+        }
+
+        /* A peer leaving in the middle of a frame is an error */
+        raw.write_all(&frame[..HEADER_SIZE]).unwrap();
+        drop(raw);
+        assert!(b.try_recv::<GdbMachineRequest>().is_err());
+    }
+
+    #[test]
+    fn oversized_frames_are_refused() {
+        let (mut raw, b) = UnixStream::pair().unwrap();
+        let mut b = Link::new(b);
+
+        raw.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        raw.write_all(&[WireFormat::Binary.tag()]).unwrap();
+
+        assert!(b.recv::<GdbMachineRequest>().is_err());
+    }
+
+    #[test]
+    fn setup_frames_are_small() {
+        let (mut raw, b) = UnixStream::pair().unwrap();
+        let mut b = Link::new(b);
+
+        /* Refused from the header, before reading or allocating the payload */
+        raw.write_all(&((MAX_SETUP_FRAME_SIZE + 1) as u32).to_le_bytes())
+            .unwrap();
+        raw.write_all(&[WireFormat::Json.tag()]).unwrap();
+
+        assert!(b.recv::<Hello>().is_err());
+        assert!(b.rbuf.capacity() < MAX_SETUP_FRAME_SIZE + READ_CHUNK);
+    }
+
+    #[test]
+    fn linked_pair_exchanges_messages() {
+        let (mut client, mut server) = linked_pair();
+
+        assert_eq!(client.options.compression, server.options.compression);
+        assert_eq!(client.max_frame, MAX_FRAME_SIZE);
+        assert_eq!(server.max_frame, MAX_FRAME_SIZE);
+
+        /* Larger than setup frames once authenticated */
+        let large = "b".repeat(4 * MAX_SETUP_FRAME_SIZE);
+        client.send(&request(&large)).unwrap();
+        assert_eq!(location(server.recv().unwrap().unwrap()), large);
+
+        client.send(&request("bar")).unwrap();
+        assert_eq!(location(server.recv().unwrap().unwrap()), "bar");
+
+        server.send(&GdbMachineResponse::Count(12)).unwrap();
+        assert!(matches!(
+            client.recv().unwrap(),
+            Some(GdbMachineResponse::Count(12))
+        ));
+    }
+}
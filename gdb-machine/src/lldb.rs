@@ -0,0 +1,658 @@
+//! LLDB backend
+//!
+//! LLDB is driven through its command interpreter on a pipe and processes
+//! are inspected with the Python helpers of `lldb_helpers.py`. Each helper
+//! replies with a single line holding [REPLY_PREFIX], the token of the
+//! request and a JSON document which maps to the data produced with GDB.
+
+use anyhow::{anyhow, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::DebuggerConfig;
+use crate::debugger::Debugger;
+use crate::gdbmi::GdbMiRemote;
+use crate::metadata::*;
+use crate::tools::truncate_value;
+use crate::tty::InferiorTty;
+
+/// Start of the lines holding helper replies
+const REPLY_PREFIX: &str = "SNAPPED";
+/// Python module imported in LLDB
+const HELPERS: &str = include_str!("lldb_helpers.py");
+/// Default time to wait for LLDB to respond to a request
+const LLDB_COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+/// Time to wait for requests walking all the symbols
+const LLDB_LOAD_TIMEOUT: Duration = Duration::from_secs(600);
+/// Time given to LLDB to reply once the helper gave up waiting for a stop
+const STOP_REPLY_MARGIN: Duration = Duration::from_secs(5);
+
+/* Helper files are written per instance as several may start at once */
+static HELPER_INSTANCES: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Deserialize)]
+struct LldbState {
+    #[serde(default)]
+    running: bool,
+    stop: Option<StopReason>,
+}
+
+#[derive(Deserialize)]
+struct LldbThread {
+    id: u32,
+    info: ThreadInfo,
+    frames: Vec<DebugFrame>,
+    /// (frame level, name, is argument, value)
+    vars: Vec<(u32, String, bool, String)>,
+}
+
+#[derive(Deserialize)]
+struct LldbSnapshot {
+    threads: Vec<LldbThread>,
+    stop: Option<StopReason>,
+    fault: Option<FaultContext>,
+}
+
+#[derive(Deserialize)]
+struct LldbNumber {
+    number: u32,
+}
+
+/// Quote a string for a Python call (JSON strings are valid Python)
+fn py_str(s: &str) -> String {
+    serde_json::to_string(s).unwrap_or_default()
+}
+
+pub struct Lldb {
+    id: u64,
+    target: GdbMiRemote,
+    config: DebuggerConfig,
+    /// Terminal of the inferior (only for launched commands)
+    tty: Option<InferiorTty>,
+    /// Last output lines of a launched inferior which has no terminal
+    lines: Arc<Mutex<VecDeque<String>>>,
+    lldb_in: Option<ChildStdin>,
+    /// Helper replies (token, JSON)
+    replies: Option<Receiver<(u64, String)>>,
+    token: u64,
+    timeout: Duration,
+    /// Why LLDB is gone (if it is)
+    dead: Option<String>,
+    child_proc: Option<Child>,
+}
+
+impl Debugger for Lldb {
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn set_id(&mut self, id: u64) {
+        self.id = id;
+    }
+
+    fn get_id(&self) -> u64 {
+        self.id
+    }
+
+    /// Start the debugged program, see [crate::gdbmi::GdbMi] for
+    /// how each kind of target is handled, LLDB only continues
+    /// processes already held by a gdbserver
+    fn start(&mut self) -> Result<()> {
+        let remote_has_process =
+            matches!(self.target, GdbMiRemote::Server(..)) && self.target_stopped()?;
+
+        match self.target {
+            GdbMiRemote::Attach(_) => self.run("cont", &[])?,
+            GdbMiRemote::Server(..) if remote_has_process => self.run("cont", &[])?,
+            GdbMiRemote::Server(..) => {
+                return Err(anyhow!(
+                    "The gdbserver holds no process, LLDB cannot run a program on it"
+                ))
+            }
+            GdbMiRemote::Core(_, _) => return Ok(()),
+            _ => {
+                let tty = self.tty.as_ref().map(|t| t.path()).unwrap_or_default();
+                let tty = py_str(tty);
+                self.run("launch", &[tty])?
+            }
+        };
+
+        /* The program holds its terminal now, its end closes the output */
+        if let Some(tty) = self.tty.as_mut() {
+            tty.close_slave();
+        }
+
+        Ok(())
+    }
+
+    /// Interrupt the process, the helper replies once it is stopped
+    fn stop(&mut self) -> Result<()> {
+        if !self.target_running()? {
+            return Ok(());
+        }
+
+        let timeout = self.timeout;
+
+        self.call_timeout::<serde_json::Value>(
+            "stop",
+            &[timeout.as_secs().max(1).to_string()],
+            timeout + STOP_REPLY_MARGIN,
+        )?;
+
+        Ok(())
+    }
+
+    fn cont(&mut self) -> Result<()> {
+        if let GdbMiRemote::Core(_, _) = self.target {
+            return Err(anyhow!("A core file cannot be continued"));
+        }
+        if self.target_running()? {
+            return Ok(());
+        }
+        self.run("cont", &[])
+    }
+
+    fn detach(&mut self) -> Result<()> {
+        if self.target_exited()? {
+            return Ok(());
+        }
+        self.stop()?;
+        self.run("detach", &[])
+    }
+
+    fn set_breakpoint(&mut self, location: &str) -> Result<u32> {
+        let ret: LldbNumber = self.call("set_breakpoint", &[py_str(location)])?;
+        Ok(ret.number)
+    }
+
+    /// Watch an expression (LLDB needs a process to evaluate it)
+    fn set_watchpoint(&mut self, expr: &str, kind: WatchKind) -> Result<u32> {
+        let kind = match kind {
+            WatchKind::Write => "write",
+            WatchKind::Read => "read",
+            WatchKind::Access => "access",
+        };
+
+        let ret: LldbNumber = self.call("set_watchpoint", &[py_str(expr), py_str(kind)])?;
+        Ok(ret.number)
+    }
+
+    fn delete_breakpoint(&mut self, num: u32) -> Result<()> {
+        self.run("delete_breakpoint", &[num.to_string()])
+    }
+
+    fn state(&mut self) -> Result<HashMap<u64, RunState>> {
+        let mut ret = HashMap::new();
+
+        if let Some(rs) = self.runstate()? {
+            ret.insert(self.id, rs);
+        }
+
+        Ok(ret)
+    }
+
+    fn snapshot(
+        &mut self,
+        opts: &SnapshotOptions,
+    ) -> Result<HashMap<u64, (u64, Vec<BacktraceState>)>> {
+        self.stop()?;
+
+        let mut ret = HashMap::new();
+
+        match self.runstate()? {
+            Some(RunState::Dead(reason)) => {
+                ret.insert(self.id, ProgramSnapshot::dead(&reason));
+            }
+            Some(RunState::Stopped(stop)) if stop.exited() => {
+                ret.insert(self.id, ProgramSnapshot::exited(Some(*stop)));
+            }
+            Some(RunState::Stopped(_)) => {
+                let snap: LldbSnapshot = self.call(
+                    "snapshot",
+                    &[
+                        (opts.locals as u32).to_string(),
+                        opts.max_frames.to_string(),
+                        opts.max_depth.to_string(),
+                    ],
+                )?;
+                ret.insert(self.id, Lldb::program_snapshot(snap, opts));
+            }
+            _ => return Err(anyhow!("Program is not running")),
+        }
+
+        let ret = ProgramSnapshot::generate_components(ret, opts);
+
+        Ok(ProgramSnapshot::attach_output(ret, self.output_lines()))
+    }
+
+    fn symbols(&mut self) -> Result<SymbolTable> {
+        if self.target_running()? {
+            return Err(anyhow!("Symbols can only be retrieved on a stopped target"));
+        }
+
+        self.call_timeout("symbols", &[], LLDB_LOAD_TIMEOUT)
+    }
+
+    /// Last output lines of the inferior (if run on its own terminal)
+    fn output(&mut self) -> Result<HashMap<u64, ProcessOutput>> {
+        let mut ret = HashMap::new();
+
+        if let GdbMiRemote::Command(_) = self.target {
+            let tag = ProcessInfo::default()?.tag();
+
+            ret.insert(
+                self.id,
+                ProcessOutput {
+                    tag,
+                    lines: self.output_lines(),
+                },
+            );
+        }
+
+        Ok(ret)
+    }
+
+    fn count(&mut self) -> Result<u64> {
+        Ok(1)
+    }
+}
+
+impl Lldb {
+    /// Read LLDB replies, other lines are kept in `output` if given
+    fn read_loop(
+        out: ChildStdout,
+        replies: Sender<(u64, String)>,
+        output: Option<Arc<Mutex<VecDeque<String>>>>,
+    ) {
+        let reader = BufReader::new(out);
+
+        for line in reader.lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(_) => break,
+            };
+
+            let reply = line
+                .strip_prefix(REPLY_PREFIX)
+                .and_then(|r| r.trim_start().split_once(' '))
+                .and_then(|(token, json)| Some((token.parse::<u64>().ok()?, json.to_string())));
+
+            match (reply, &output) {
+                (Some(r), _) => {
+                    if replies.send(r).is_err() {
+                        break;
+                    }
+                }
+                /* Without a terminal the inferior writes through LLDB */
+                (None, Some(output)) => {
+                    log::info!("{}", line);
+                    if let Ok(l) = output.lock().as_mut() {
+                        InferiorTty::push_line(l, line);
+                    }
+                }
+                (None, None) => log::debug!("LLDB: {}", line),
+            }
+        }
+    }
+
+    /// Last output lines of the inferior (from its terminal if it has one)
+    fn output_lines(&self) -> Vec<String> {
+        if let Some(tty) = &self.tty {
+            return tty.lines();
+        }
+
+        match self.lines.lock() {
+            Ok(l) => l.iter().cloned().collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Call a helper which only replies on success
+    fn run(&mut self, func: &str, args: &[String]) -> Result<()> {
+        self.call::<serde_json::Value>(func, args)?;
+        Ok(())
+    }
+
+    /// Call a helper and parse its reply
+    fn call<T: DeserializeOwned>(&mut self, func: &str, args: &[String]) -> Result<T> {
+        self.call_timeout(func, args, self.timeout)
+    }
+
+    fn call_timeout<T: DeserializeOwned>(
+        &mut self,
+        func: &str,
+        args: &[String],
+        timeout: Duration,
+    ) -> Result<T> {
+        if let Some(reason) = &self.dead {
+            return Err(anyhow!("LLDB is dead: {}", reason));
+        }
+
+        let token = self.token;
+        self.token += 1;
+
+        let mut params = vec![token.to_string()];
+        params.extend_from_slice(args);
+
+        let cmd = format!("script snapped_lldb.{}({})\n", func, params.join(", "));
+
+        self.send(&cmd)?;
+
+        let reply = self.await_reply(token, timeout);
+
+        if let Err(RecvTimeoutError::Disconnected) = reply {
+            self.dead = Some("LLDB exited".to_string());
+        }
+
+        let reply = match reply {
+            Ok(r) => r,
+            Err(RecvTimeoutError::Timeout) => {
+                return Err(anyhow!(
+                    "Timeout after {} seconds waiting for LLDB",
+                    timeout.as_secs_f64()
+                ))
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(anyhow!("LLDB died before responding"))
+            }
+        };
+
+        let value: serde_json::Value = serde_json::from_str(&reply)
+            .with_context(|| format!("Failed to parse LLDB reply to {}", func))?;
+
+        if let Some(e) = value.get("error") {
+            return Err(anyhow!("{} returned an error : {}", func, e));
+        }
+
+        Ok(serde_json::from_value(value)?)
+    }
+
+    fn send(&mut self, cmd: &str) -> Result<()> {
+        match self.lldb_in.as_mut() {
+            Some(lldb_in) => Ok(lldb_in.write_all(cmd.as_bytes())?),
+            None => Err(anyhow!("LLDB is not running")),
+        }
+    }
+
+    /// Wait for the reply to `token`, late replies to requests
+    /// which timed out are dropped
+    fn await_reply(&self, token: u64, timeout: Duration) -> Result<String, RecvTimeoutError> {
+        let replies = self
+            .replies
+            .as_ref()
+            .ok_or(RecvTimeoutError::Disconnected)?;
+
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+
+            match replies.recv_timeout(left)? {
+                (t, reply) if t == token => return Ok(reply),
+                _ => continue,
+            }
+        }
+    }
+
+    /// State of the process (None if LLDB holds no process)
+    fn runstate(&mut self) -> Result<Option<RunState>> {
+        let st: Result<LldbState> = self.call("state", &[]);
+
+        if let Some(reason) = &self.dead {
+            return Ok(Some(RunState::Dead(reason.clone())));
+        }
+
+        Ok(match st.context("Failed to get LLDB state")? {
+            LldbState { running: true, .. } => Some(RunState::Running("running".to_string())),
+            LldbState {
+                stop: Some(stop), ..
+            } => Some(RunState::Stopped(Box::new(stop))),
+            _ => None,
+        })
+    }
+
+    fn target_running(&mut self) -> Result<bool> {
+        Ok(matches!(self.runstate()?, Some(RunState::Running(_))))
+    }
+
+    fn target_stopped(&mut self) -> Result<bool> {
+        Ok(matches!(self.runstate()?, Some(RunState::Stopped(_))))
+    }
+
+    fn target_exited(&mut self) -> Result<bool> {
+        Ok(self.runstate()?.is_some_and(|r| r.exited()))
+    }
+
+    fn program_snapshot(snap: LldbSnapshot, opts: &SnapshotOptions) -> ProgramSnapshot {
+        let mut ret = ProgramSnapshot {
+            state: HashMap::new(),
+            stop_state: snap.stop,
+            inferior: None,
+            fault: snap.fault,
+            threads: HashMap::new(),
+        };
+
+        for th in snap.threads {
+            let mut frames = th.frames;
+
+            for frame in frames.iter_mut() {
+                let vars = th
+                    .vars
+                    .iter()
+                    .filter(|(level, _, _, _)| *level == frame.level)
+                    .map(|(_, name, is_arg, value)| {
+                        let value = truncate_value(value, opts.max_depth, opts.max_string);
+                        (name.clone(), *is_arg, value)
+                    })
+                    .collect::<Vec<_>>();
+
+                if !vars.is_empty() {
+                    frame.attach_locals(vars);
+                }
+            }
+
+            ret.state.insert(th.id, frames);
+            ret.threads.insert(th.id, th.info);
+        }
+
+        ret
+    }
+
+    /// LLDB arguments loading the target
+    fn target_args(&self) -> Vec<String> {
+        match &self.target {
+            GdbMiRemote::Command(cmd) => {
+                let mut ret = vec!["--".to_string()];
+                ret.extend(cmd.iter().cloned());
+                ret
+            }
+            GdbMiRemote::Attach(pid) => vec!["-p".to_string(), pid.to_string()],
+            GdbMiRemote::Core(exe, core) => vec![exe.clone(), "-c".to_string(), core.clone()],
+            /* Connected once the helpers are loaded */
+            GdbMiRemote::Server(..) => Vec::new(),
+        }
+    }
+
+    fn _start_lldb(&mut self) -> Result<()> {
+        /* The target comes last as it ends with the program arguments */
+        let mut args = self.config.lldb_args()?;
+        args.append(&mut self.target_args());
+
+        log::debug!("{:?}", args);
+
+        let lldb = self.config.lldb_path();
+
+        let mut command = Command::new(&lldb)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .args(&args)
+            .spawn()
+            .with_context(|| format!("Failed to launch {}", lldb))?;
+
+        let (lldb_in, lldb_out) = match (command.stdin.take(), command.stdout.take()) {
+            (Some(i), Some(o)) => (i, o),
+            _ => return Err(anyhow!("Failed to capture LLDB child process stdin/stdout")),
+        };
+
+        let (tx, rx) = channel();
+        let output = match (&self.target, &self.tty) {
+            (GdbMiRemote::Command(_), None) => Some(self.lines.clone()),
+            _ => None,
+        };
+        thread::spawn(move || Lldb::read_loop(lldb_out, tx, output));
+
+        self.lldb_in = Some(lldb_in);
+        self.replies = Some(rx);
+        self.child_proc = Some(command);
+
+        Ok(())
+    }
+
+    /// Import the helpers, the file can go once LLDB has loaded it
+    fn _load_helpers(&mut self) -> Result<()> {
+        let dir = std::env::temp_dir().join(format!(
+            "snapped-lldb-{}-{}",
+            std::process::id(),
+            HELPER_INSTANCES.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&dir)?;
+
+        let path = dir.join("snapped_lldb.py");
+        std::fs::write(&path, HELPERS)?;
+
+        self.send(&format!("command script import {}\n", path.display()))?;
+        let ret = self.call_timeout::<serde_json::Value>("init", &[], LLDB_LOAD_TIMEOUT);
+
+        let _ = std::fs::remove_dir_all(&dir);
+
+        ret?;
+        Ok(())
+    }
+
+    fn _init(target: GdbMiRemote, config: &DebuggerConfig) -> Result<Lldb> {
+        let mut ret = Lldb {
+            id: 0,
+            target,
+            config: config.clone(),
+            tty: None,
+            lines: Arc::new(Mutex::new(VecDeque::new())),
+            lldb_in: None,
+            replies: None,
+            token: 0,
+            timeout: LLDB_COMMAND_TIMEOUT,
+            dead: None,
+            child_proc: None,
+        };
+
+        if config.keep_forks || config.follow_exec.is_some() {
+            log::warn!("LLDB does not keep forks nor follow execs, these settings are ignored");
+        }
+
+        if let GdbMiRemote::Command(_) = ret.target {
+            let tag = ProcessInfo::default().map(|p| p.tag()).unwrap_or_default();
+
+            match InferiorTty::new(tag) {
+                Ok(tty) => ret.tty = Some(tty),
+                Err(e) => log::warn!("Inferior output will not be captured: {}", e),
+            }
+        }
+
+        ret._start_lldb()?;
+        ret._load_helpers()?;
+
+        if let GdbMiRemote::Server(host, port, _) = &ret.target {
+            let cmd = py_str(&format!("gdb-remote {}:{}", host, port));
+            ret.call_timeout::<serde_json::Value>("command", &[cmd], LLDB_LOAD_TIMEOUT)?;
+        }
+
+        Ok(ret)
+    }
+
+    pub fn with_config(target: GdbMiRemote, config: &DebuggerConfig) -> Result<Lldb> {
+        Lldb::_init(target, config)
+    }
+
+    pub fn take_child(&mut self) -> Option<Child> {
+        self.child_proc.take()
+    }
+
+    /// Set the time to wait for LLDB to respond to requests
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// LLDB standing for a launched program without a terminal, whose
+    /// commands are echoed and never answered
+    fn echo_lldb(target: GdbMiRemote) -> (Child, Lldb) {
+        let mut cat = Command::new("cat")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let mut lldb = Lldb {
+            id: 0,
+            target,
+            config: DebuggerConfig::default(),
+            tty: None,
+            lines: Arc::new(Mutex::new(VecDeque::new())),
+            lldb_in: cat.stdin.take(),
+            replies: None,
+            token: 0,
+            timeout: Duration::from_millis(100),
+            dead: None,
+            child_proc: None,
+        };
+
+        let (tx, rx) = channel();
+        let (out, lines) = (cat.stdout.take().unwrap(), lldb.lines.clone());
+        thread::spawn(move || Lldb::read_loop(out, tx, Some(lines)));
+        lldb.replies = Some(rx);
+
+        (cat, lldb)
+    }
+
+    #[test]
+    fn state_timeouts_are_reported() {
+        let (mut cat, mut lldb) = echo_lldb(GdbMiRemote::Server("localhost".into(), 1, None));
+
+        let err = lldb.start().unwrap_err();
+        assert!(format!("{:#}", err).contains("Timeout"), "{:#}", err);
+
+        cat.kill().unwrap();
+        cat.wait().unwrap();
+    }
+
+    #[test]
+    fn output_without_terminal_is_kept() {
+        let (mut cat, mut lldb) = echo_lldb(GdbMiRemote::Command(vec!["prog".into()]));
+
+        lldb.send("hello from the program\n").unwrap();
+        lldb.send(&format!("{} 7 {{}}\n", REPLY_PREFIX)).unwrap();
+
+        /* Replies are not part of the output */
+        assert!(lldb.await_reply(7, Duration::from_secs(10)).is_ok());
+        assert_eq!(lldb.output_lines(), vec!["hello from the program"]);
+        assert_eq!(
+            lldb.output().unwrap()[&0].lines,
+            vec!["hello from the program"]
+        );
+
+        cat.kill().unwrap();
+        cat.wait().unwrap();
+    }
+}
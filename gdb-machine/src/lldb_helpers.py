@@ -0,0 +1,461 @@
+"""Helpers used by snapped to drive processes with LLDB
+
+Each entry point takes the token of the request and prints a single reply
+line made of the reply prefix, the token and a JSON document. Failures are
+replied as {"error": "..."}.
+"""
+
+import functools
+import json
+import time
+
+import lldb
+
+PREFIX = "SNAPPED"
+
+# Children printed per aggregate value
+MAX_CHILDREN = 16
+# Registers kept in a fault context, general purpose registers come first
+MAX_REGISTERS = 34
+# Instructions kept before and after the PC in a fault context
+DISASSEMBLY_WINDOW = 6
+
+# States in which a process no longer runs
+STOPPED_STATES = (
+    lldb.eStateStopped,
+    lldb.eStateSuspended,
+    lldb.eStateCrashed,
+    lldb.eStateExited,
+    lldb.eStateDetached,
+)
+
+# Watchpoint kind to (read, write)
+WATCH_KINDS = {"write": (False, True), "read": (True, False), "access": (True, True)}
+
+# Breakpoints and watchpoints share a single numbering as in GDB
+# (kind, lldb id) to number
+numbers = {}
+next_number = 1
+# The last stop was requested (its SIGSTOP is not a stop reason),
+# processes we attach to are stopped by us
+interrupted = True
+
+
+def reply(token, obj):
+    print("%s %d %s" % (PREFIX, token, json.dumps(obj)), flush=True)
+
+
+def fail(token, msg):
+    reply(token, {"error": str(msg)})
+
+
+def entry(func):
+    """Reply the exceptions of an entry point as failures, without a
+    reply the caller would wait for its timeout"""
+
+    @functools.wraps(func)
+    def wrapper(token, *args):
+        try:
+            func(token, *args)
+        except Exception as e:
+            fail(token, "%s: %s" % (type(e).__name__, e))
+
+    return wrapper
+
+
+def target():
+    return lldb.debugger.GetSelectedTarget()
+
+
+def process():
+    return target().GetProcess()
+
+
+@entry
+def init(token):
+    lldb.debugger.SetAsync(True)
+    reply(token, {})
+
+
+@entry
+def command(token, cmd):
+    res = lldb.SBCommandReturnObject()
+    lldb.debugger.GetCommandInterpreter().HandleCommand(cmd, res)
+
+    if res.Succeeded():
+        reply(token, {"output": res.GetOutput()})
+    else:
+        fail(token, res.GetError())
+
+
+@entry
+def launch(token, tty):
+    global interrupted
+    interrupted = False
+
+    info = target().GetLaunchInfo()
+
+    if tty:
+        info.AddOpenFileAction(0, tty, True, False)
+        info.AddOpenFileAction(1, tty, False, True)
+        info.AddOpenFileAction(2, tty, False, True)
+
+    error = lldb.SBError()
+    target().Launch(info, error)
+
+    if error.Success():
+        reply(token, {})
+    else:
+        fail(token, error)
+
+
+@entry
+def cont(token):
+    global interrupted
+    interrupted = False
+
+    error = process().Continue()
+
+    if error.Success():
+        reply(token, {})
+    else:
+        fail(token, error)
+
+
+@entry
+def stop(token, timeout):
+    """Interrupt the process and reply once it is stopped"""
+    global interrupted
+    interrupted = True
+
+    proc = process()
+    listener = lldb.SBListener("snapped-stop")
+    # Listen before stopping not to miss the state change
+    proc.GetBroadcaster().AddListener(listener, lldb.SBProcess.eBroadcastBitStateChanged)
+
+    try:
+        error = proc.Stop()
+
+        if not error.Success():
+            fail(token, error)
+            return
+
+        deadline = time.monotonic() + timeout
+        event = lldb.SBEvent()
+
+        while proc.IsValid() and proc.GetState() not in STOPPED_STATES:
+            left = deadline - time.monotonic()
+
+            if left <= 0:
+                fail(token, "Process did not stop after an interrupt")
+                return
+
+            listener.WaitForEvent(max(int(left), 1), event)
+
+        reply(token, {})
+    finally:
+        proc.GetBroadcaster().RemoveListener(listener)
+
+
+@entry
+def detach(token):
+    error = process().Detach()
+
+    if error.Success():
+        reply(token, {})
+    else:
+        fail(token, error)
+
+
+def new_number(kind, ident):
+    global next_number
+
+    num = next_number
+    next_number += 1
+    numbers[(kind, ident)] = num
+
+    return num
+
+
+@entry
+def set_breakpoint(token, location):
+    path, _, line = location.rpartition(":")
+
+    if path and line.isdigit():
+        bp = target().BreakpointCreateByLocation(path, int(line))
+    else:
+        bp = target().BreakpointCreateByName(location)
+
+    if not bp.IsValid():
+        fail(token, "Cannot set a breakpoint at %s" % location)
+        return
+
+    reply(token, {"number": new_number("b", bp.GetID())})
+
+
+@entry
+def set_watchpoint(token, expr, kind):
+    read, write = WATCH_KINDS[kind]
+
+    value = target().EvaluateExpression(expr)
+    addr = value.GetLoadAddress()
+
+    if not value.IsValid() or addr == lldb.LLDB_INVALID_ADDRESS:
+        fail(token, "Cannot watch %s (no address)" % expr)
+        return
+
+    error = lldb.SBError()
+    wp = target().WatchAddress(addr, value.GetByteSize(), read, write, error)
+
+    if not error.Success():
+        fail(token, error)
+        return
+
+    reply(token, {"number": new_number("w", wp.GetID())})
+
+
+@entry
+def delete_breakpoint(token, num):
+    for (kind, ident), n in list(numbers.items()):
+        if n != num:
+            continue
+
+        del numbers[(kind, ident)]
+
+        if kind == "b":
+            target().BreakpointDelete(ident)
+        else:
+            target().DeleteWatchpoint(ident)
+
+        reply(token, {})
+        return
+
+    fail(token, "No breakpoint number %d" % num)
+
+
+def stop_reason(proc):
+    """Stop reason as given by GDB for the first thread with one"""
+    if proc.GetState() == lldb.eStateExited:
+        code = proc.GetExitStatus()
+        reason = "exited-normally" if code == 0 else "exited"
+        return {"reason": reason, "exit_code": code}
+
+    for th in proc:
+        kind = th.GetStopReason()
+        desc = th.GetStopDescription(256)
+        ret = {"thread_id": th.GetIndexID()}
+
+        if kind == lldb.eStopReasonSignal:
+            signo = th.GetStopReasonDataAtIndex(0)
+            name = proc.GetUnixSignals().GetSignalAsCString(signo)
+
+            if interrupted and name in ("SIGSTOP", "SIGINT"):
+                continue
+
+            ret.update(reason="signal-received", signal_name=name, meaning=desc)
+        elif kind == lldb.eStopReasonException:
+            name = desc.split(" ")[0] if desc else "EXCEPTION"
+            ret.update(reason="signal-received", signal_name=name, meaning=desc)
+        elif kind == lldb.eStopReasonBreakpoint:
+            num = numbers.get(("b", th.GetStopReasonDataAtIndex(0)))
+            ret.update(reason="breakpoint-hit", breakpoint_num=num)
+        elif kind == lldb.eStopReasonWatchpoint:
+            num = numbers.get(("w", th.GetStopReasonDataAtIndex(0)))
+            ret.update(reason="watchpoint-trigger", breakpoint_num=num)
+        else:
+            continue
+
+        frame = th.GetFrameAtIndex(0)
+        ret.update(addr="0x%x" % frame.GetPC(), function=frame.GetFunctionName())
+
+        return ret
+
+    return {"reason": ""}
+
+
+@entry
+def state(token):
+    proc = process()
+
+    if not proc.IsValid():
+        reply(token, {})
+        return
+
+    st = proc.GetState()
+
+    if st in (lldb.eStateRunning, lldb.eStateStepping, lldb.eStateLaunching, lldb.eStateAttaching):
+        reply(token, {"running": True})
+    elif st in (lldb.eStateStopped, lldb.eStateSuspended, lldb.eStateCrashed, lldb.eStateExited):
+        reply(token, {"stop": stop_reason(proc)})
+    else:
+        reply(token, {})
+
+
+def value_str(v, depth):
+    """Value printed as GDB does for aggregates"""
+    summary = v.GetSummary()
+
+    if summary:
+        return summary
+
+    count = v.GetNumChildren()
+
+    if count == 0 or v.TypeIsPointerType():
+        return v.GetValue() or ""
+
+    if depth == 0:
+        return "{...}"
+
+    children = []
+
+    for i in range(min(count, MAX_CHILDREN)):
+        c = v.GetChildAtIndex(i)
+        children.append("%s = %s" % (c.GetName(), value_str(c, depth - 1)))
+
+    if count > MAX_CHILDREN:
+        children.append("...")
+
+    return "{" + ", ".join(children) + "}"
+
+
+def frame_info(frame):
+    le = frame.GetLineEntry()
+    spec = le.GetFileSpec()
+    has_line = le.IsValid() and spec.IsValid()
+
+    return {
+        "level": frame.GetFrameID(),
+        "addr": "0x%x" % frame.GetPC(),
+        "func": frame.GetFunctionName() or "??",
+        "file": spec.GetFilename() if has_line else None,
+        "fullname": str(spec) if has_line else None,
+        "line": le.GetLine() if has_line else None,
+        "from": frame.GetModule().GetFileSpec().GetFilename(),
+        "arch": target().GetTriple(),
+        "args": None,
+        "locals": None,
+    }
+
+
+def frame_vars(frame, depth):
+    ret = []
+
+    for v in frame.GetVariables(True, False, False, True):
+        ret.append([frame.GetFrameID(), v.GetName(), True, value_str(v, depth)])
+
+    for v in frame.GetVariables(False, True, False, True):
+        ret.append([frame.GetFrameID(), v.GetName(), False, value_str(v, depth)])
+
+    return ret
+
+
+def disassembly(frame):
+    tgt = target()
+    pc = frame.GetPC()
+    symbol = frame.GetSymbol()
+
+    if symbol.IsValid():
+        start = symbol.GetStartAddress().GetLoadAddress(tgt)
+        insts = list(symbol.GetInstructions(tgt))
+    else:
+        start = pc
+        insts = list(tgt.ReadInstructions(frame.GetPCAddress(), DISASSEMBLY_WINDOW + 1))
+
+    addrs = [i.GetAddress().GetLoadAddress(tgt) for i in insts]
+    current = addrs.index(pc) if pc in addrs else 0
+    first = max(current - DISASSEMBLY_WINDOW, 0)
+
+    ret = []
+
+    for inst, addr in list(zip(insts, addrs))[first:current + DISASSEMBLY_WINDOW + 1]:
+        ret.append(
+            {
+                "address": "0x%x" % addr,
+                "func": symbol.GetName() if symbol.IsValid() else None,
+                "offset": addr - start if addr >= start else None,
+                "inst": "%s %s" % (inst.GetMnemonic(tgt), inst.GetOperands(tgt)),
+                "current": addr == pc,
+            }
+        )
+
+    return ret
+
+
+def fault_context(thread):
+    frame = thread.GetFrameAtIndex(0)
+    registers = []
+    sets = frame.GetRegisters()
+
+    if sets.GetSize():
+        for reg in sets.GetValueAtIndex(0):
+            if len(registers) == MAX_REGISTERS:
+                break
+            if reg.GetValue() is not None:
+                registers.append([reg.GetName(), reg.GetValue()])
+
+    return {"registers": registers, "disassembly": disassembly(frame)}
+
+
+@entry
+def snapshot(token, with_vars, max_frames, max_depth):
+    proc = process()
+    threads = []
+
+    for th in proc:
+        variables = []
+
+        if with_vars:
+            for frame in th:
+                if frame.GetFrameID() >= max_frames:
+                    break
+                variables += frame_vars(frame, max_depth)
+
+        threads.append(
+            {
+                "id": th.GetIndexID(),
+                "info": {
+                    "name": th.GetName(),
+                    "target_id": "LWP %d" % th.GetThreadID(),
+                    "lwp": th.GetThreadID(),
+                    "core": None,
+                    "state": "stopped",
+                },
+                "frames": [frame_info(f) for f in th],
+                "vars": variables,
+            }
+        )
+
+    stop = stop_reason(proc)
+    fault = None
+
+    if stop.get("reason") == "signal-received":
+        fault = fault_context(proc.GetThreadByIndexID(stop["thread_id"]))
+
+    reply(token, {"threads": threads, "stop": stop, "fault": fault})
+
+
+@entry
+def symbols(token):
+    per_file = {}
+
+    for module in target().modules:
+        for sym in module:
+            if sym.GetType() != lldb.eSymbolTypeCode:
+                continue
+
+            addr = sym.GetStartAddress()
+            le = addr.GetLineEntry()
+            has_line = le.IsValid() and le.GetFileSpec().IsValid()
+            path = str(le.GetFileSpec()) if has_line else "Unknown"
+
+            per_file.setdefault(path, []).append(
+                {
+                    "name": sym.GetName(),
+                    "address": "0x%x" % addr.GetFileAddress(),
+                    "line": le.GetLine() if has_line else None,
+                    "type": None,
+                    "description": None,
+                }
+            )
+
+    reply(token, {"symbols_per_file": per_file})
@@ -1,13 +1,14 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use gethostname::gethostname;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     hash::{DefaultHasher, Hash, Hasher},
 };
 
-use crate::tools::{dominating_numa_id, parse_gdb_equal_list};
+use crate::mi::MiValue;
+use crate::tools::dominating_numa_id;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct TreeIdFactory {
@@ -15,33 +16,58 @@ pub struct TreeIdFactory {
     dynamic: u64,
     stride: u64,
     offset: u64,
+    arity: u64,
 }
 
-const TREE_ARITY: u64 = 24;
+/// Default number of children of a tree node
+pub const TREE_ARITY: u64 = 24;
+
+/// Number of IDs reserved for the inferiors of each debugger
+/// (the first one being the ID of the debugger itself)
+pub const INFERIOR_ID_SLOTS: u64 = 64;
 
 impl TreeIdFactory {
     pub fn default() -> TreeIdFactory {
+        TreeIdFactory::new(TREE_ARITY)
+    }
+
+    /// IDs of a root with up to `arity` children
+    pub fn new(arity: u64) -> TreeIdFactory {
+        let arity = arity.max(1);
+
         TreeIdFactory {
             root_id: 0,
             dynamic: std::u64::MAX,
-            stride: (std::u64::MAX - 1) / TREE_ARITY,
+            stride: (std::u64::MAX - INFERIOR_ID_SLOTS) / arity,
             offset: 0,
+            arity,
         }
     }
 
-    pub fn inherit(&mut self) -> Result<TreeIdFactory> {
-        let root_id = self.root_id + 1 + self.stride * self.offset;
+    /// IDs of a new child with up to `arity` children
+    pub fn inherit(&mut self, arity: u64) -> Result<TreeIdFactory> {
+        if self.full() {
+            return Err(anyhow!("No ID left for a new child"));
+        }
+
+        let arity = arity.max(1);
+        let root_id = self.root_id + INFERIOR_ID_SLOTS + self.stride * self.offset;
         self.offset += 1;
 
-        let dynamic = (self.dynamic - 1) / TREE_ARITY;
+        let dynamic = self.stride;
 
-        let stride = dynamic / TREE_ARITY;
+        if dynamic < INFERIOR_ID_SLOTS * (arity + 1) {
+            return Err(anyhow!("The tree is too deep to give IDs to new processes"));
+        }
+
+        let stride = (dynamic - INFERIOR_ID_SLOTS) / arity;
 
         Ok(TreeIdFactory {
             root_id,
             dynamic,
             stride,
             offset: 0,
+            arity,
         })
     }
 
@@ -50,10 +76,23 @@ impl TreeIdFactory {
     }
 
     pub fn full(&self) -> bool {
-        self.offset == TREE_ARITY
+        self.offset == self.arity
+    }
+
+    /// Number of children given an ID
+    pub fn children(&self) -> u64 {
+        self.offset
     }
 }
 
+/// Variables holding the rank of a process as set by MPI launchers
+const RANK_VARIABLES: [&str; 4] = [
+    "PMI_RANK",
+    "PMIX_RANK",
+    "OMPI_COMM_WORLD_RANK",
+    "SLURM_PROCID",
+];
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ProcessInfo {
     pub mpirank: Option<u32>,
@@ -75,8 +114,9 @@ impl ProcessInfo {
         let locality_descriptor = ProcessInfo::locality_descriptor()?;
         let hostname = String::from(gethostname().as_os_str().to_str().unwrap());
         let pid = std::process::id() as u64;
-        let mpirank = std::env::var("PMI_RANK")
-            .ok()
+        let mpirank = RANK_VARIABLES
+            .iter()
+            .find_map(|v| std::env::var(v).ok())
             .and_then(|v| v.parse::<u32>().ok());
 
         Ok(ProcessInfo {
@@ -86,34 +126,242 @@ impl ProcessInfo {
             locality_descriptor,
         })
     }
+
+    /// Short name of the process for users (its rank if known)
+    pub fn tag(&self) -> String {
+        match self.mpirank {
+            Some(rank) => format!("rank {}", rank),
+            None => format!("{}:{}", self.hostname, self.pid),
+        }
+    }
 }
 
-#[derive(Hash, Serialize, Deserialize, Debug, Clone)]
+/// Last output lines of a debugged process
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProcessOutput {
+    /// Name of the process (see [ProcessInfo::tag])
+    pub tag: String,
+    pub lines: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DisplayState {
     pub reason: String,
     pub signal_name: Option<String>,
     pub exit_code: Option<i32>,
+    /// Breakpoint or watchpoint which caused the stop
+    #[serde(default)]
+    pub breakpoint_num: Option<u32>,
+    /// Machine state of the thread which received a signal
+    #[serde(default)]
+    pub fault: Option<FaultContext>,
+    /// Why the debugger died (for "debugger-died" states)
+    #[serde(default)]
+    pub detail: Option<String>,
+}
+
+/* The fault context is not part of the identity of a state, processes
+crashing at the same place but with different registers are merged */
+impl Hash for DisplayState {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.reason.hash(state);
+        self.signal_name.hash(state);
+        self.exit_code.hash(state);
+        self.breakpoint_num.hash(state);
+        self.detail.hash(state);
+    }
 }
 
+/// A disassembled instruction
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DisassemblyLine {
+    pub address: String,
+    pub func: Option<String>,
+    pub offset: Option<u32>,
+    pub inst: String,
+    /// Is this the instruction at the stop PC
+    pub current: bool,
+}
+
+/// Registers and instructions of a thread which received a signal
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FaultContext {
+    /// General purpose registers (name, value)
+    pub registers: Vec<(String, String)>,
+    /// Instructions around the PC
+    pub disassembly: Vec<DisassemblyLine>,
+}
+
+/// An inferior of a debugger following forks or execs
+#[derive(Hash, Serialize, Deserialize, Debug, Clone)]
+pub struct DisplayInferior {
+    /// Inferior number in the debugger
+    pub num: u32,
+    /// Program run by the inferior
+    pub executable: Option<String>,
+}
+
+/// Threads grouped by name (see [SnapshotOptions::group_by_thread_name])
 #[derive(Hash, Serialize, Deserialize, Debug, Clone)]
+pub struct DisplayThread {
+    pub name: Option<String>,
+}
+
+/// Processes which did not answer a command (see [ProgramSnapshot::no_response])
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DisplayMissing {
+    /// Why they did not answer (timeout, closed link or error)
+    pub error: String,
+    /// Processes (see [ProcessInfo::tag]) with the ones they relay
+    pub processes: Vec<String>,
+}
+
+/* Processes are not part of the identity so that all the
+processes failing for the same reason are listed together */
+impl Hash for DisplayMissing {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.error.hash(state);
+    }
+}
+
+/// Maximum number of distinct outputs kept under a stack group
+const MAX_GROUP_OUTPUTS: usize = 4;
+
+/// Last output lines of the processes ending in a stack group
+/// (see [ProgramSnapshot::attach_output])
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DisplayOutput {
+    /// Distinct outputs with the number of stacks ending on them
+    pub outputs: Vec<(Vec<String>, u64)>,
+}
+
+/* Outputs are not part of the identity so that a stack
+group is not split by the outputs of its processes */
+impl Hash for DisplayOutput {
+    fn hash<H: Hasher>(&self, _state: &mut H) {}
+}
+
+impl DisplayOutput {
+    fn merge(&mut self, other: &DisplayOutput) {
+        for (lines, cnt) in other.outputs.iter() {
+            match self.outputs.iter_mut().find(|(l, _)| l == lines) {
+                Some((_, c)) => *c += cnt,
+                None => self.outputs.push((lines.clone(), *cnt)),
+            }
+        }
+
+        if self.outputs.len() > MAX_GROUP_OUTPUTS {
+            /* Fold the least common outputs */
+            self.outputs.sort_by_key(|o| std::cmp::Reverse(o.1));
+            let others: u64 = self.outputs[MAX_GROUP_OUTPUTS - 1..]
+                .iter()
+                .map(|(_, c)| c)
+                .sum();
+            self.outputs.truncate(MAX_GROUP_OUTPUTS - 1);
+            self.outputs.push((vec!["...".to_string()], others));
+        }
+    }
+}
+
+/// Maximum number of distinct values kept for a variable across processes
+const MAX_VARIABLE_VALUES: usize = 8;
+
+/// A variable captured in a frame with its values across processes
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DisplayVariable {
+    pub name: String,
+    /// Is this a function argument (local variable otherwise)
+    pub arg: bool,
+    /// Distinct values with the number of processes sharing them
+    pub values: Vec<(String, u64)>,
+}
+
+impl DisplayVariable {
+    fn merge(&mut self, other: &DisplayVariable) {
+        for (value, cnt) in other.values.iter() {
+            match self.values.iter_mut().find(|(v, _)| v == value) {
+                Some((_, c)) => *c += cnt,
+                None => self.values.push((value.clone(), *cnt)),
+            }
+        }
+
+        if self.values.len() > MAX_VARIABLE_VALUES {
+            /* Fold the least common values */
+            self.values.sort_by_key(|v| std::cmp::Reverse(v.1));
+            let others: u64 = self.values[MAX_VARIABLE_VALUES - 1..]
+                .iter()
+                .map(|(_, c)| c)
+                .sum();
+            self.values.truncate(MAX_VARIABLE_VALUES - 1);
+            self.values.push(("...".to_string(), others));
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DisplayFrame {
     pub func: String,
     pub file: Option<String>,
     pub line: Option<u32>,
+    /// Captured arguments and locals (see [SnapshotOptions])
+    #[serde(default)]
+    pub vars: Vec<DisplayVariable>,
+}
+
+/* Variables are not part of the identity of a frame so that stacks
+only differing by their values are merged */
+impl Hash for DisplayFrame {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.func.hash(state);
+        self.file.hash(state);
+        self.line.hash(state);
+    }
+}
+
+impl DisplayFrame {
+    pub fn merge_vars(&mut self, other: &DisplayFrame) {
+        for var in other.vars.iter() {
+            match self
+                .vars
+                .iter_mut()
+                .find(|v| v.name == var.name && v.arg == var.arg)
+            {
+                Some(v) => v.merge(var),
+                None => self.vars.push(var.clone()),
+            }
+        }
+    }
 }
 
 #[derive(Hash, Serialize, Deserialize, Debug, Clone)]
 pub enum BacktraceState {
     Frame(DisplayFrame),
     State(DisplayState),
+    Inferior(DisplayInferior),
+    Thread(DisplayThread),
+    Missing(DisplayMissing),
+    Output(DisplayOutput),
 }
 
 impl From<&DebugFrame> for BacktraceState {
     fn from(value: &DebugFrame) -> Self {
+        let args = value.args.iter().flatten().map(|v| (v, true));
+        let locals = value.locals.iter().flatten().map(|v| (v, false));
+
+        let vars = args
+            .chain(locals)
+            .map(|((name, val), arg)| DisplayVariable {
+                name: name.clone(),
+                arg,
+                values: vec![(val.clone(), 1)],
+            })
+            .collect();
+
         BacktraceState::Frame(DisplayFrame {
             func: value.func.clone(),
             file: value.fullname.clone(),
             line: value.line.clone(),
+            vars,
         })
     }
 }
@@ -124,6 +372,12 @@ impl From<&StopReason> for BacktraceState {
             reason: value.reason.clone(),
             signal_name: value.signal_name.clone(),
             exit_code: value.exit_code.clone(),
+            breakpoint_num: value.breakpoint_num,
+            fault: None,
+            detail: match value.reason.as_str() {
+                "debugger-died" => value.meaning.clone(),
+                _ => None,
+            },
         })
     }
 }
@@ -134,9 +388,26 @@ impl BacktraceState {
             func: ".".to_string(),
             file: None,
             line: None,
+            vars: Vec::new(),
         })
     }
 
+    /// Merge the values captured in `other` (which should have the same hash)
+    pub fn merge(&mut self, other: &BacktraceState) {
+        match (self, other) {
+            (BacktraceState::Frame(a), BacktraceState::Frame(b)) => a.merge_vars(b),
+            /* Keep the fault context of a single process */
+            (BacktraceState::State(a), BacktraceState::State(b)) if a.fault.is_none() => {
+                a.fault = b.fault.clone();
+            }
+            (BacktraceState::Missing(a), BacktraceState::Missing(b)) => {
+                a.processes.extend(b.processes.iter().cloned())
+            }
+            (BacktraceState::Output(a), BacktraceState::Output(b)) => a.merge(b),
+            _ => {}
+        }
+    }
+
     fn print(&self) -> String {
         match &self {
             BacktraceState::Frame(b) => format!(
@@ -152,6 +423,18 @@ impl BacktraceState {
                     s.signal_name.clone().unwrap_or("".to_string()),
                 )
             }
+            BacktraceState::Inferior(i) => format!(
+                "inferior {} {}",
+                i.num,
+                i.executable.clone().unwrap_or("".to_string()),
+            ),
+            BacktraceState::Thread(t) => {
+                format!("thread {}", t.name.clone().unwrap_or("".to_string()))
+            }
+            BacktraceState::Missing(m) => {
+                format!("missing {} ({})", m.processes.join(", "), m.error)
+            }
+            BacktraceState::Output(o) => format!("output ({} distinct)", o.outputs.len()),
         }
     }
 
@@ -216,60 +499,35 @@ impl DebugFrame {
         }
     }
 
-    /// Creates a new `DebugFrame` from a GDB-MI backtrace state.
-    ///
-    /// This function parses the given string, which represents a frame in a GDB-MI backtrace
-    /// state, and returns a `Result` containing the parsed `DebugFrame`. If parsing fails,
-    /// the returned `Result` will be an error.
-    pub fn new(desc: &str) -> Result<DebugFrame> {
-        let entries = parse_gdb_equal_list(desc);
-
-        let mut ret = DebugFrame {
-            level: 0,
-            addr: "".to_string(),
-            func: "".to_string(),
-            file: None,
-            fullname: None,
-            line: None,
-            from: None,
-            arch: None,
-            args: None,
-            locals: None,
-        };
-
-        if let Some(level) = entries.get("level") {
-            ret.level = level.parse::<u32>().unwrap_or(0);
-        }
-
-        if let Some(addr) = entries.get("addr") {
-            ret.addr = addr.clone();
-        }
-
-        if let Some(func) = entries.get("func") {
-            ret.func = func.clone();
-        }
-
-        if let Some(file) = entries.get("file") {
-            ret.file = Some(file.clone());
-        }
-
-        if let Some(fullname) = entries.get("fullname") {
-            ret.fullname = Some(fullname.clone());
-        }
-
-        if let Some(line) = entries.get("line") {
-            ret.line = line.parse::<u32>().ok();
-        }
-
-        if let Some(from) = entries.get("from") {
-            ret.from = Some(from.clone());
+    pub fn dead() -> DebugFrame {
+        DebugFrame {
+            func: "Debugger has died (no stack)".to_string(),
+            ..DebugFrame::exited()
         }
+    }
 
-        if let Some(arch) = entries.get("arch") {
-            ret.arch = Some(arch.clone());
+    /// Creates a new `DebugFrame` from a GDB-MI frame tuple.
+    ///
+    /// This function reads the given frame, as found in a GDB-MI backtrace
+    /// or stop record, and returns a `Result` containing the parsed `DebugFrame`.
+    /// If the value is not a frame, the returned `Result` will be an error.
+    pub fn new(desc: &MiValue) -> Result<DebugFrame> {
+        if !matches!(desc, MiValue::Tuple(_)) {
+            return Err(anyhow!("A frame should be a tuple"));
         }
 
-        Ok(ret)
+        Ok(DebugFrame {
+            level: desc.get_parsed::<u32>("level").unwrap_or(0),
+            addr: desc.get_str("addr").unwrap_or_default().to_string(),
+            func: desc.get_str("func").unwrap_or_default().to_string(),
+            file: desc.get_str("file").map(|v| v.to_string()),
+            fullname: desc.get_str("fullname").map(|v| v.to_string()),
+            line: desc.get_parsed::<u32>("line"),
+            from: desc.get_str("from").map(|v| v.to_string()),
+            arch: desc.get_str("arch").map(|v| v.to_string()),
+            args: None,
+            locals: None,
+        })
     }
 
     /// Attaches additional arguments and local variables from a variable list.
@@ -332,12 +590,71 @@ impl DebugFrame {
     }
 }
 
+/// Accesses triggering a watchpoint
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+/// Options controlling what is captured in a snapshot
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotOptions {
+    /// Capture arguments and local variables
+    pub locals: bool,
+    /// Number of innermost frames for which variables are captured
+    pub max_frames: u32,
+    /// Nesting depth of aggregates in captured values
+    pub max_depth: u32,
+    /// Maximum length of a captured value
+    pub max_string: usize,
+    /// Split stacks by thread name
+    pub group_by_thread_name: bool,
+}
+
+impl Default for SnapshotOptions {
+    fn default() -> SnapshotOptions {
+        SnapshotOptions {
+            locals: false,
+            max_frames: 1,
+            max_depth: 2,
+            max_string: 64,
+            group_by_thread_name: false,
+        }
+    }
+}
+
+/// Thread metadata as given by `-thread-info`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThreadInfo {
+    /// Name of the thread (e.g. set with `pthread_setname_np`)
+    pub name: Option<String>,
+    /// System description of the thread (e.g. "Thread 0x7ffff7d8a740 (LWP 1234)")
+    pub target_id: Option<String>,
+    /// Kernel thread id
+    pub lwp: Option<u32>,
+    /// Core on which the thread last ran
+    pub core: Option<u32>,
+    /// Either "stopped" or "running"
+    pub state: Option<String>,
+}
+
 /// Represents the full state of a program, including the list of stack frames for each individual thread.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ProgramSnapshot {
     /// A map where the keys are thread IDs and the values are lists of `DebugFrame`s representing that thread's call stack.
     pub state: HashMap<u32, Vec<DebugFrame>>,
     pub stop_state: Option<StopReason>,
+    /// Inferior the threads belong to (when debugging several)
+    #[serde(default)]
+    pub inferior: Option<DisplayInferior>,
+    /// Machine state of the thread which received a signal
+    #[serde(default)]
+    pub fault: Option<FaultContext>,
+    /// Metadata of the threads (by thread ID)
+    #[serde(default)]
+    pub threads: HashMap<u32, ThreadInfo>,
 }
 
 impl ProgramSnapshot {
@@ -345,7 +662,77 @@ impl ProgramSnapshot {
         let mut state = HashMap::new();
         state.insert(0, vec![DebugFrame::exited()]);
 
-        ProgramSnapshot { state, stop_state }
+        ProgramSnapshot {
+            state,
+            stop_state,
+            inferior: None,
+            fault: None,
+            threads: HashMap::new(),
+        }
+    }
+
+    /// Snapshot of a process whose debugger died (`reason` tells why)
+    pub fn dead(reason: &str) -> ProgramSnapshot {
+        let mut state = HashMap::new();
+        state.insert(0, vec![DebugFrame::dead()]);
+
+        ProgramSnapshot {
+            state,
+            stop_state: Some(StopReason::debugger_died(reason)),
+            inferior: None,
+            fault: None,
+            threads: HashMap::new(),
+        }
+    }
+
+    /// Components standing for a `process` which did not answer a snapshot
+    /// with the `size` processes it relays, shown under a "no response" state
+    pub fn no_response(
+        process: String,
+        error: String,
+        size: u64,
+    ) -> HashMap<u64, (u64, Vec<BacktraceState>)> {
+        let comp = vec![
+            BacktraceState::Missing(DisplayMissing {
+                error,
+                processes: vec![process],
+            }),
+            BacktraceState::State(DisplayState {
+                reason: "no-response".to_string(),
+                signal_name: None,
+                exit_code: None,
+                breakpoint_num: None,
+                fault: None,
+                detail: None,
+            }),
+        ];
+
+        let mut ret = HashMap::new();
+        ret.insert(DebugFrame::hash_component(&comp), (size, comp));
+        ret
+    }
+
+    /// Put the last output `lines` of a process under each of its
+    /// stacks so that they are shown with the stack groups
+    pub fn attach_output(
+        components: HashMap<u64, (u64, Vec<BacktraceState>)>,
+        lines: Vec<String>,
+    ) -> HashMap<u64, (u64, Vec<BacktraceState>)> {
+        if lines.is_empty() {
+            return components;
+        }
+
+        components
+            .into_values()
+            .map(|(cnt, mut comp)| {
+                let output = DisplayOutput {
+                    outputs: vec![(lines.clone(), cnt)],
+                };
+                /* Stacks are listed from the innermost frame */
+                comp.insert(0, BacktraceState::Output(output));
+                (DebugFrame::hash_component(&comp), (cnt, comp))
+            })
+            .collect()
     }
 
     pub fn json(&self) -> Result<String> {
@@ -355,19 +742,32 @@ impl ProgramSnapshot {
 
     pub fn generate_components(
         dist_state: HashMap<u64, ProgramSnapshot>,
+        opts: &SnapshotOptions,
     ) -> HashMap<u64, (u64, Vec<BacktraceState>)> {
         let mut components: HashMap<u64, (u64, Vec<BacktraceState>)> = HashMap::new();
 
         for snap in dist_state.values() {
-            for thsnap in snap.state.values() {
+            for (th, thsnap) in snap.state.iter() {
                 let mut comp = if let Some(stop_reason) = &snap.stop_state {
                     //println!("{:?}", stop_reason);
 
                     /* If we add SIGINT it will fill the whole stack in
-                    case of manual interrupt  */
-                    match stop_reason.is_sigint() {
+                    case of manual interrupt, the same goes for the
+                    reasonless stop following an attach */
+                    match stop_reason.is_sigint() || stop_reason.reason.is_empty() {
                         true => Vec::new(),
-                        false => vec![BacktraceState::from(stop_reason)],
+                        false => {
+                            let mut state = BacktraceState::from(stop_reason);
+
+                            /* Only the faulting thread carries the fault */
+                            if let BacktraceState::State(s) = &mut state {
+                                if stop_reason.thread_id == Some(*th) {
+                                    s.fault = snap.fault.clone();
+                                }
+                            }
+
+                            vec![state]
+                        }
                     }
                 } else {
                     Vec::new()
@@ -375,10 +775,21 @@ impl ProgramSnapshot {
 
                 comp.append(&mut DebugFrame::to_component(thsnap));
 
+                /* Stacks are listed from the innermost frame */
+                if opts.group_by_thread_name {
+                    let name = snap.threads.get(th).and_then(|t| t.name.clone());
+                    comp.push(BacktraceState::Thread(DisplayThread { name }));
+                }
+
+                if let Some(inferior) = &snap.inferior {
+                    comp.push(BacktraceState::Inferior(inferior.clone()));
+                }
+
                 let hash = DebugFrame::hash_component(&comp);
 
-                if let Some((cnt, _)) = components.get_mut(&hash) {
+                if let Some((cnt, target)) = components.get_mut(&hash) {
                     *cnt += 1;
+                    ProgramSnapshot::merge_states(target, &comp);
                 } else {
                     components.insert(hash, (1, comp));
                 }
@@ -388,6 +799,12 @@ impl ProgramSnapshot {
         components
     }
 
+    fn merge_states(target: &mut [BacktraceState], other: &[BacktraceState]) {
+        for (t, o) in target.iter_mut().zip(other.iter()) {
+            t.merge(o);
+        }
+    }
+
     pub fn components_vec(
         components: &HashMap<u64, (u64, Vec<DisplayFrame>)>,
     ) -> Vec<(u64, Vec<DisplayFrame>)> {
@@ -400,8 +817,9 @@ impl ProgramSnapshot {
         if let Some(mut first) = components.pop() {
             for maps in components {
                 for (hash, (cnt, vec)) in maps {
-                    if let Some((targ_cnt, _)) = first.get_mut(&hash) {
+                    if let Some((targ_cnt, target)) = first.get_mut(&hash) {
                         *targ_cnt += cnt;
+                        ProgramSnapshot::merge_states(target, &vec);
                     } else {
                         first.insert(hash, (cnt, vec));
                     }
@@ -433,7 +851,7 @@ pub struct StopReason {
     /// The function in which the program stopped executing
     pub function: Option<String>,
 
-    /// A brief description of what the `function` is (e.g. "main", "func", etc.)
+    /// The meaning of the received signal (e.g. "Segmentation fault")
     pub meaning: Option<String>,
 
     /// Name of the stopping signal
@@ -476,30 +894,109 @@ impl StopReason {
         false
     }
 
-    pub fn new(resp: &str) -> Result<StopReason> {
-        let arg_re = Regex::new("(args=\\[.*\\])")?;
-        let resp_no_arg = arg_re.replace(resp, "");
+    fn empty() -> StopReason {
+        StopReason {
+            reason: String::new(),
+            disp: None,
+            breakpoint_num: None,
+            addr: None,
+            function: None,
+            meaning: None,
+            signal_name: None,
+            file: None,
+            fullname: None,
+            line: None,
+            arch: None,
+            thread_id: None,
+            stopped_threads: None,
+            core: None,
+            exit_code: None,
+        }
+    }
+
+    /// Stop reason of a process loaded from a core file
+    ///
+    /// The signal which terminated the process is reported as received
+    /// if it is not known the stop is reasonless (as after an attach)
+    pub fn core_dumped(signal_name: Option<String>) -> StopReason {
+        let mut ret = StopReason::empty();
+
+        if signal_name.is_some() {
+            ret.reason = "signal-received".to_string();
+            ret.signal_name = signal_name;
+        }
+
+        ret
+    }
+
+    /// Reasonless stop (as after an attach or an interrupt)
+    pub fn stopped() -> StopReason {
+        StopReason::empty()
+    }
+
+    /// Stop reason of an inferior which exited while others remain
+    pub fn inferior_exited(exit_code: Option<i32>) -> StopReason {
+        let mut ret = StopReason::empty();
+
+        ret.reason = match exit_code {
+            Some(code) if code != 0 => "exited".to_string(),
+            _ => "exited-normally".to_string(),
+        };
+        ret.exit_code = exit_code;
+
+        ret
+    }
+
+    /// Stop reason of a process whose debugger died
+    pub fn debugger_died(reason: &str) -> StopReason {
+        let mut ret = StopReason::empty();
+
+        ret.reason = "debugger-died".to_string();
+        ret.meaning = Some(reason.to_string());
+
+        ret
+    }
 
-        let map = parse_gdb_equal_list(&resp_no_arg);
+    /// Creates a new `StopReason` from the results of a GDB-MI `*stopped` record
+    pub fn new(resp: &MiValue) -> Result<StopReason> {
+        let frame = resp.get("frame").cloned().unwrap_or(MiValue::empty());
+
+        /* Watchpoints report their number in a wpt tuple */
+        let breakpoint_num = resp.get_parsed::<u32>("bkptno").or_else(|| {
+            ["wpt", "hw-awpt", "hw-rwpt"]
+                .iter()
+                .find_map(|k| resp.get(k))
+                .and_then(|w| w.get_parsed::<u32>("number"))
+        });
+
+        let stopped_threads = match resp.get("stopped-threads") {
+            Some(MiValue::Const(s)) => Some(s.clone()),
+            Some(list) => Some(
+                list.items()
+                    .iter()
+                    .flat_map(|v| v.as_str())
+                    .collect::<Vec<&str>>()
+                    .join(","),
+            ),
+            None => None,
+        };
 
         let stop_reason = StopReason {
-            reason: map.get("reason").cloned().unwrap_or_default(),
-            disp: map.get("disp").cloned().map(|s| s.to_string()),
-            breakpoint_num: map
-                .get("breakpoint_num")
-                .and_then(|s| s.parse::<u32>().ok()),
-            addr: map.get("addr").cloned(),
-            function: map.get("function").cloned(),
-            meaning: map.get("meaning").cloned(),
-            signal_name: map.get("signal-name").cloned(),
-            file: map.get("file").cloned(),
-            fullname: map.get("fullname").cloned(),
-            line: map.get("line").and_then(|s| s.parse::<u32>().ok()),
-            arch: map.get("arch").cloned(),
-            thread_id: map.get("thread_id").and_then(|s| s.parse::<u32>().ok()),
-            stopped_threads: map.get("stopped_threads").cloned(),
-            core: map.get("core").and_then(|s| s.parse::<u32>().ok()),
-            exit_code: map.get("exit-code").and_then(|s| s.parse::<i32>().ok()),
+            reason: resp.get_str("reason").unwrap_or_default().to_string(),
+            disp: resp.get_str("disp").map(|s| s.to_string()),
+            breakpoint_num,
+            addr: frame.get_str("addr").map(|s| s.to_string()),
+            function: frame.get_str("func").map(|s| s.to_string()),
+            meaning: resp.get_str("signal-meaning").map(|s| s.to_string()),
+            signal_name: resp.get_str("signal-name").map(|s| s.to_string()),
+            file: frame.get_str("file").map(|s| s.to_string()),
+            fullname: frame.get_str("fullname").map(|s| s.to_string()),
+            line: frame.get_parsed::<u32>("line"),
+            arch: frame.get_str("arch").map(|s| s.to_string()),
+            thread_id: resp.get_parsed::<u32>("thread-id"),
+            stopped_threads,
+            core: resp.get_parsed::<u32>("core"),
+            exit_code: resp.get_parsed::<i32>("exit-code"),
         };
 
         Ok(stop_reason)
@@ -515,6 +1012,19 @@ impl StopReason {
 pub enum RunState {
     Stopped(Box<StopReason>),
     Running(String),
+    /// The debugger itself died (with the reason)
+    Dead(String),
+}
+
+impl RunState {
+    /// Is the process gone, either exited or lost with its debugger
+    pub fn exited(&self) -> bool {
+        match self {
+            RunState::Stopped(st) => st.exited(),
+            RunState::Running(_) => false,
+            RunState::Dead(_) => true,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -544,4 +1054,58 @@ impl SymbolTable {
             symbols_per_file: HashMap::new(),
         }
     }
+
+    /// Add the symbols of another table, a symbol already listed
+    /// in a file (same name and line) is not repeated
+    pub fn merge(&mut self, other: SymbolTable) {
+        for (file, symbols) in other.symbols_per_file {
+            let known = self.symbols_per_file.entry(file).or_default();
+            let mut seen: HashSet<(String, Option<i32>)> =
+                known.iter().map(|s| (s.name.clone(), s.line)).collect();
+
+            for s in symbols {
+                if seen.insert((s.name.clone(), s.line)) {
+                    known.push(s);
+                }
+            }
+        }
+    }
+
+    /// Merge the tables of several processes
+    pub fn merge_all(tables: Vec<SymbolTable>) -> SymbolTable {
+        let mut ret = SymbolTable::default();
+
+        for t in tables {
+            ret.merge(t);
+        }
+
+        ret
+    }
+
+    /// Only keep the symbols with a name matching `pattern`
+    pub fn filter(self, pattern: &Regex) -> SymbolTable {
+        let symbols_per_file = self
+            .symbols_per_file
+            .into_iter()
+            .map(|(file, symbols)| {
+                let symbols: Vec<Symbol> = symbols
+                    .into_iter()
+                    .filter(|s| pattern.is_match(&s.name))
+                    .collect();
+                (file, symbols)
+            })
+            .filter(|(_, symbols)| !symbols.is_empty())
+            .collect();
+
+        SymbolTable { symbols_per_file }
+    }
+
+    /// Number of symbols in all files
+    pub fn len(&self) -> usize {
+        self.symbols_per_file.values().map(|s| s.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
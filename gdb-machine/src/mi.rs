@@ -0,0 +1,576 @@
+//! Parser for the GDB-MI output syntax
+//!
+//! This follows the grammar from the "GDB/MI Output Syntax" section of the GDB
+//! manual and turns each output line into a [MiOutput], records carrying their
+//! results as a typed [MiValue] tree.
+
+use std::str::FromStr;
+
+use serde_json::{Map, Value};
+
+/// A value in a GDB-MI record
+#[derive(Debug, Clone, PartialEq)]
+pub enum MiValue {
+    /// A C-string constant (already unescaped)
+    Const(String),
+    /// A `{name=value,...}` tuple, keys may be repeated
+    Tuple(Vec<(String, MiValue)>),
+    /// A `[value,...]` list
+    List(Vec<MiValue>),
+    /// A `[name=value,...]` list
+    ResultList(Vec<(String, MiValue)>),
+}
+
+impl MiValue {
+    pub fn empty() -> MiValue {
+        MiValue::Tuple(Vec::new())
+    }
+
+    /// Get the first value named `key` in a tuple or a result list
+    pub fn get(&self, key: &str) -> Option<&MiValue> {
+        match self {
+            MiValue::Tuple(v) | MiValue::ResultList(v) => {
+                v.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+            }
+            _ => None,
+        }
+    }
+
+    /// Get the string constant named `key`
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(|v| v.as_str())
+    }
+
+    /// Get the string constant named `key` parsed as `T`
+    pub fn get_parsed<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.get_str(key).and_then(|v| v.parse::<T>().ok())
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            MiValue::Const(s) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Values contained in a list or a tuple (names are dropped)
+    pub fn items(&self) -> Vec<&MiValue> {
+        match self {
+            MiValue::Const(_) => Vec::new(),
+            MiValue::List(v) => v.iter().collect(),
+            MiValue::Tuple(v) | MiValue::ResultList(v) => v.iter().map(|(_, v)| v).collect(),
+        }
+    }
+
+    /// Convert to JSON for deserialization with serde
+    ///
+    /// Tuples become objects (first value wins for repeated keys)
+    /// and result lists become arrays of their values
+    pub fn to_json(&self) -> Value {
+        match self {
+            MiValue::Const(s) => Value::String(s.clone()),
+            MiValue::Tuple(v) => {
+                let mut map = Map::new();
+                for (k, v) in v {
+                    if !map.contains_key(k) {
+                        map.insert(k.clone(), v.to_json());
+                    }
+                }
+                Value::Object(map)
+            }
+            MiValue::List(v) => Value::Array(v.iter().map(|v| v.to_json()).collect()),
+            MiValue::ResultList(v) => Value::Array(v.iter().map(|(_, v)| v.to_json()).collect()),
+        }
+    }
+}
+
+/// The kind of an out-of-band or result record
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MiRecordKind {
+    /// `^` command results
+    Result,
+    /// `*` execution state changes
+    Exec,
+    /// `+` progress notifications
+    Status,
+    /// `=` supplementary notifications
+    Notify,
+}
+
+/// A record with its optional token, class and results
+#[derive(Debug, Clone)]
+pub struct MiRecord {
+    pub token: Option<u64>,
+    pub kind: MiRecordKind,
+    pub class: String,
+    /// Results as a [MiValue::Tuple]
+    pub results: MiValue,
+}
+
+/// A single line of GDB-MI output
+#[derive(Debug, Clone)]
+pub enum MiOutput {
+    Record(MiRecord),
+    /// `~` console stream
+    Console(String),
+    /// `@` target stream
+    Target(String),
+    /// `&` log stream
+    Log(String),
+    /// The `(gdb)` prompt
+    Prompt,
+    /// Anything which is not MI (for example inferior output)
+    Other(String),
+}
+
+/// Deepest nesting of tuples and lists parsed, the parser being recursive
+const MAX_DEPTH: usize = 256;
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// Tuples and lists being parsed
+    depth: usize,
+    /// Was [MAX_DEPTH] reached
+    too_deep: bool,
+}
+
+impl<'a> Parser<'a> {
+    fn new(line: &'a str) -> Parser<'a> {
+        Parser {
+            data: line.as_bytes(),
+            pos: 0,
+            depth: 0,
+            too_deep: false,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            return true;
+        }
+
+        false
+    }
+
+    fn done(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn token(&mut self) -> Option<u64> {
+        let start = self.pos;
+
+        while matches!(self.peek(), Some(b'0'..=b'9')) {
+            self.pos += 1;
+        }
+
+        std::str::from_utf8(&self.data[start..self.pos])
+            .ok()
+            .and_then(|v| v.parse::<u64>().ok())
+    }
+
+    fn word(&mut self) -> String {
+        let start = self.pos;
+
+        while let Some(c) = self.peek() {
+            if c == b'=' || c == b',' || c == b'{' || c == b'}' || c == b'[' || c == b']' {
+                break;
+            }
+            self.pos += 1;
+        }
+
+        String::from_utf8_lossy(&self.data[start..self.pos]).to_string()
+    }
+
+    fn cstring(&mut self) -> Option<String> {
+        if !self.eat(b'"') {
+            return None;
+        }
+
+        let mut out: Vec<u8> = Vec::new();
+
+        loop {
+            let c = self.peek()?;
+            self.pos += 1;
+
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let e = self.peek()?;
+                    self.pos += 1;
+
+                    match e {
+                        b'n' => out.push(b'\n'),
+                        b't' => out.push(b'\t'),
+                        b'r' => out.push(b'\r'),
+                        b'e' => out.push(0x1b),
+                        b'a' => out.push(0x07),
+                        b'b' => out.push(0x08),
+                        b'f' => out.push(0x0c),
+                        b'v' => out.push(0x0b),
+                        b'0'..=b'7' => {
+                            /* Octal escape of up to three digits */
+                            let mut v: u32 = (e - b'0') as u32;
+                            for _ in 0..2 {
+                                match self.peek() {
+                                    Some(d @ b'0'..=b'7') => {
+                                        v = v * 8 + (d - b'0') as u32;
+                                        self.pos += 1;
+                                    }
+                                    _ => break,
+                                }
+                            }
+                            out.push(v as u8);
+                        }
+                        other => out.push(other),
+                    }
+                }
+                other => out.push(other),
+            }
+        }
+
+        Some(String::from_utf8_lossy(&out).to_string())
+    }
+
+    fn value(&mut self) -> Option<MiValue> {
+        let open = self.peek()?;
+
+        if open == b'"' {
+            return self.cstring().map(MiValue::Const);
+        }
+
+        if self.depth == MAX_DEPTH {
+            self.too_deep = true;
+            return None;
+        }
+
+        self.depth += 1;
+
+        let ret = match open {
+            b'{' => {
+                self.pos += 1;
+                self.results(b'}').map(MiValue::Tuple)
+            }
+            b'[' => {
+                self.pos += 1;
+                self.list()
+            }
+            _ => None,
+        };
+
+        self.depth -= 1;
+
+        ret
+    }
+
+    fn result(&mut self) -> Option<(String, MiValue)> {
+        /* Some records (e.g. multi-location breakpoints) contain bare values */
+        if matches!(self.peek(), Some(b'"' | b'{' | b'[')) {
+            return self.value().map(|v| (String::new(), v));
+        }
+
+        let name = self.word();
+
+        if !self.eat(b'=') {
+            return None;
+        }
+
+        let value = self.value()?;
+
+        Some((name, value))
+    }
+
+    /// Comma separated results up to the closing delimiter
+    fn results(&mut self, close: u8) -> Option<Vec<(String, MiValue)>> {
+        let mut ret = Vec::new();
+
+        if self.eat(close) {
+            return Some(ret);
+        }
+
+        loop {
+            ret.push(self.result()?);
+
+            if self.eat(close) {
+                return Some(ret);
+            }
+
+            if !self.eat(b',') {
+                return None;
+            }
+        }
+    }
+
+    fn list(&mut self) -> Option<MiValue> {
+        if self.eat(b']') {
+            return Some(MiValue::List(Vec::new()));
+        }
+
+        /* A list holds either values or results */
+        if matches!(self.peek(), Some(b'"' | b'{' | b'[')) {
+            let mut ret = Vec::new();
+
+            loop {
+                ret.push(self.value()?);
+
+                if self.eat(b']') {
+                    return Some(MiValue::List(ret));
+                }
+
+                if !self.eat(b',') {
+                    return None;
+                }
+            }
+        }
+
+        self.results(b']').map(MiValue::ResultList)
+    }
+
+    fn record(&mut self) -> Option<MiRecord> {
+        let token = self.token();
+
+        let kind = match self.peek()? {
+            b'^' => MiRecordKind::Result,
+            b'*' => MiRecordKind::Exec,
+            b'+' => MiRecordKind::Status,
+            b'=' => MiRecordKind::Notify,
+            _ => return None,
+        };
+        self.pos += 1;
+
+        let class = self.word();
+
+        if class.is_empty() {
+            return None;
+        }
+
+        let mut results = Vec::new();
+
+        while self.eat(b',') {
+            match self.result() {
+                Some(r) => results.push(r),
+                /* Still answer the command for it not to wait for a response */
+                None if self.too_deep && kind == MiRecordKind::Result => {
+                    return Some(MiRecord {
+                        token,
+                        kind,
+                        class: "error".to_string(),
+                        results: MiValue::Tuple(vec![(
+                            "msg".to_string(),
+                            MiValue::Const(format!(
+                                "Output nested deeper than {} levels",
+                                MAX_DEPTH
+                            )),
+                        )]),
+                    });
+                }
+                None => return None,
+            }
+        }
+
+        if !self.done() {
+            return None;
+        }
+
+        Some(MiRecord {
+            token,
+            kind,
+            class,
+            results: MiValue::Tuple(results),
+        })
+    }
+
+    fn stream(&mut self) -> Option<String> {
+        self.pos += 1;
+        let ret = self.cstring()?;
+
+        if !self.done() {
+            return None;
+        }
+
+        Some(ret)
+    }
+}
+
+/// Quote a parameter of a GDB-MI command as a C-string
+pub fn quote(value: &str) -> String {
+    let mut ret = String::with_capacity(value.len() + 2);
+
+    ret.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                ret.push('\\');
+                ret.push(c);
+            }
+            '\n' => ret.push_str("\\n"),
+            other => ret.push(other),
+        }
+    }
+
+    ret.push('"');
+
+    ret
+}
+
+/// Parse a line of GDB-MI output
+pub fn parse_line(line: &str) -> MiOutput {
+    let trimmed = line.trim_end_matches(['\n', '\r']);
+
+    if trimmed.trim_end() == "(gdb)" {
+        return MiOutput::Prompt;
+    }
+
+    let mut p = Parser::new(trimmed);
+
+    let stream = match p.peek() {
+        Some(b'~') => p.stream().map(MiOutput::Console),
+        Some(b'@') => p.stream().map(MiOutput::Target),
+        Some(b'&') => p.stream().map(MiOutput::Log),
+        _ => p.record().map(MiOutput::Record),
+    };
+
+    stream.unwrap_or(MiOutput::Other(line.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(line: &str) -> MiRecord {
+        match parse_line(line) {
+            MiOutput::Record(r) => r,
+            other => panic!("{} parsed as {:?}", line, other),
+        }
+    }
+
+    #[test]
+    fn result_record_with_token() {
+        let r = record("12^done,value=\"42\"\n");
+
+        assert_eq!(r.token, Some(12));
+        assert_eq!(r.kind, MiRecordKind::Result);
+        assert_eq!(r.class, "done");
+        assert_eq!(r.results.get_str("value"), Some("42"));
+    }
+
+    #[test]
+    fn braces_in_quoted_strings() {
+        let r = record(r#"^done,value="{a = 1, b = [2]}",next="}{""#);
+
+        assert_eq!(r.results.get_str("value"), Some("{a = 1, b = [2]}"));
+        assert_eq!(r.results.get_str("next"), Some("}{"));
+    }
+
+    #[test]
+    fn escaped_quotes() {
+        let r = record(r#"^done,value="0x4006f4 \"he said \\\"hi\\\"\"",tab="a\tb""#);
+
+        assert_eq!(
+            r.results.get_str("value"),
+            Some(r#"0x4006f4 "he said \"hi\"""#)
+        );
+        assert_eq!(r.results.get_str("tab"), Some("a\tb"));
+
+        match parse_line("~\"Reading symbols from \\\"a.out\\\"...\\n\"") {
+            MiOutput::Console(s) => assert_eq!(s, "Reading symbols from \"a.out\"...\n"),
+            other => panic!("Not a console stream: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn nested_tuples_and_lists() {
+        let r = record(concat!(
+            r#"^done,stack=[frame={level="0",func="wait"},frame={level="1",func="main"}],"#,
+            r#"groups=[{id="i1",cores=["0","1"]},{id="i2",cores=[]}],empty={}"#
+        ));
+
+        let stack = r.results.get("stack").unwrap();
+        assert!(matches!(stack, MiValue::ResultList(_)));
+
+        let funcs: Vec<&str> = stack
+            .items()
+            .iter()
+            .filter_map(|f| f.get_str("func"))
+            .collect();
+        assert_eq!(funcs, vec!["wait", "main"]);
+
+        let groups = r.results.get("groups").unwrap().items();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].get("cores").unwrap().items().len(), 2);
+        assert_eq!(groups[1].get("cores"), Some(&MiValue::List(Vec::new())));
+        assert_eq!(r.results.get("empty"), Some(&MiValue::empty()));
+    }
+
+    #[test]
+    fn cpp_names_with_braces() {
+        let r = record(concat!(
+            r#"*stopped,reason="signal-received",frame={addr="0x401136","#,
+            r#"func="std::function<void ()>::operator()() const::{lambda()#1}","#,
+            r#"args=[{name="this",value="0x7ffc {...}"}]}"#
+        ));
+
+        let frame = r.results.get("frame").unwrap();
+
+        assert_eq!(r.kind, MiRecordKind::Exec);
+        assert_eq!(
+            frame.get_str("func"),
+            Some("std::function<void ()>::operator()() const::{lambda()#1}")
+        );
+        assert_eq!(
+            frame.get("args").unwrap().items()[0].get_str("value"),
+            Some("0x7ffc {...}")
+        );
+    }
+
+    #[test]
+    fn deep_nesting_is_refused() {
+        let deep = |n: usize| format!("{}\"1\"{}", "{a=".repeat(n), "}".repeat(n));
+
+        let r = record(&format!("^done,v={}", deep(MAX_DEPTH)));
+        assert_eq!(r.class, "done");
+
+        /* Far deeper than the stack would allow */
+        let r = record(&format!("7^done,v={}", deep(100_000)));
+        assert_eq!(r.token, Some(7));
+        assert_eq!(r.class, "error");
+        assert!(r.results.get_str("msg").unwrap().contains("nested"));
+
+        let lists = format!("*stopped,v={}", "[".repeat(100_000));
+        assert!(matches!(parse_line(&lists), MiOutput::Other(_)));
+    }
+
+    #[test]
+    fn not_mi() {
+        assert!(matches!(parse_line("(gdb) \n"), MiOutput::Prompt));
+        assert!(matches!(
+            parse_line("Hello {world}\n"),
+            MiOutput::Other(s) if s == "Hello {world}\n"
+        ));
+        /* Unterminated tuple */
+        assert!(matches!(
+            parse_line("^done,frame={level=\"0\""),
+            MiOutput::Other(_)
+        ));
+    }
+
+    #[test]
+    fn quoted_parameters() {
+        let path = "/tmp/a \"b\"\\c";
+        let quoted = quote(path);
+
+        assert_eq!(quoted, r#""/tmp/a \"b\"\\c""#);
+        assert_eq!(
+            record(&format!("^done,path={}", quoted))
+                .results
+                .get_str("path"),
+            Some(path)
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use anyhow::Result;
@@ -7,8 +8,11 @@ use serde::Serialize;
 
 use crate::metadata::BacktraceState;
 use crate::metadata::ProcessInfo;
+use crate::metadata::ProcessOutput;
 use crate::metadata::RunState;
+use crate::metadata::SnapshotOptions;
 use crate::metadata::SymbolTable;
+use crate::metadata::WatchKind;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum GdbMachineCommand {
@@ -16,12 +20,43 @@ pub enum GdbMachineCommand {
     Count,
     Stop,
     Continue,
+    Detach,
+    SetBreakpoint(String),
+    SetWatchpoint(String, WatchKind),
+    DeleteBreakpoint(u32),
     GetState,
-    GetSnapshot,
-    GetSymbols,
+    GetSnapshot(SnapshotOptions),
+    /* Regex on function names (None for the whole table) */
+    GetSymbols(Option<String>),
+    GetOutput,
     /* Process Info, Server Address */
     Pivot(ProcessInfo, String),
-    Join(String),
+    /* Tree ID, Process Info, Server Address */
+    Join(u64, ProcessInfo, String),
+    /* Public key of a node to sign with the session CA (on the root) */
+    Certify(Vec<u8>),
+}
+
+/// Extra time given to start processes as their program is loaded
+const START_DEADLINE_EXTRA: Duration = Duration::from_secs(600);
+
+impl GdbMachineCommand {
+    /// Time given to the whole tree to answer, `timeout`
+    /// being the one of regular commands
+    pub fn deadline(&self, timeout: Duration) -> Duration {
+        match self {
+            GdbMachineCommand::Start => timeout + START_DEADLINE_EXTRA,
+            _ => timeout,
+        }
+    }
+}
+
+/// A command with the time left to answer it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GdbMachineRequest {
+    pub cmd: GdbMachineCommand,
+    /// In milliseconds, None to wait as long as needed
+    pub budget: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -29,11 +64,16 @@ pub enum GdbMachineResponse {
     Error(String),
     Ok,
     State(HashMap<u64, RunState>),
-    Snapshot(HashMap<u64, (u64, Vec<BacktraceState>)>),
+    Snapshot(#[serde(with = "interned")] HashMap<u64, (u64, Vec<BacktraceState>)>),
     Symbols(SymbolTable),
+    Output(HashMap<u64, ProcessOutput>),
+    /* Breakpoint number (0 if no process) */
+    Breakpoint(u32),
     /* Returns Join URL and TreeDynamic */
     Pivot(u64, String),
     Count(u64),
+    /* Certificate signed for a node (PEM) */
+    Certificate(String),
 }
 
 impl GdbMachineResponse {
@@ -59,6 +99,13 @@ impl GdbMachineResponse {
         }
     }
 
+    pub fn breakpoint_from_result(res: Result<u32>) -> GdbMachineResponse {
+        match res {
+            Ok(num) => GdbMachineResponse::Breakpoint(num),
+            Err(e) => GdbMachineResponse::Error(e.to_string()),
+        }
+    }
+
     pub fn snapshot_from_result(
         ret: Result<HashMap<u64, (u64, Vec<BacktraceState>)>>,
     ) -> GdbMachineResponse {
@@ -75,6 +122,13 @@ impl GdbMachineResponse {
         }
     }
 
+    pub fn output_from_result(ret: Result<HashMap<u64, ProcessOutput>>) -> GdbMachineResponse {
+        match ret {
+            Ok(out) => GdbMachineResponse::Output(out),
+            Err(e) => GdbMachineResponse::Error(e.to_string()),
+        }
+    }
+
     pub fn state(self) -> HashMap<u64, RunState> {
         if let GdbMachineResponse::State(st) = self {
             return st;
@@ -91,11 +145,243 @@ impl GdbMachineResponse {
         Err(anyhow!("Failed to retrieve snapshot from command"))
     }
 
+    pub fn breakpoint(self) -> Result<u32> {
+        match self {
+            GdbMachineResponse::Breakpoint(num) => Ok(num),
+            GdbMachineResponse::Error(e) => Err(anyhow!("Error: {}", e)),
+            _ => Err(anyhow!("Failed to retrieve breakpoint from command")),
+        }
+    }
+
+    pub fn output(self) -> Result<HashMap<u64, ProcessOutput>> {
+        if let GdbMachineResponse::Output(out) = self {
+            return Ok(out);
+        }
+
+        Err(anyhow!("Failed to retrieve output from command"))
+    }
+
     pub fn symbols(self) -> Result<SymbolTable> {
-        if let GdbMachineResponse::Symbols(sy) = self {
-            return Ok(sy);
+        match self {
+            GdbMachineResponse::Symbols(sy) => Ok(sy),
+            GdbMachineResponse::Error(e) => Err(anyhow!(e)),
+            _ => Err(anyhow!("Failed to retrieve symbols from command")),
         }
+    }
+}
 
-        Err(anyhow!("Failed to retrieve snapshot from command"))
+/// Snapshots are sent with function and file names interned as
+/// they repeat across the stacks of all processes
+mod interned {
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::metadata::{BacktraceState, DisplayFrame, DisplayVariable};
+
+    /* Hash to (count, states) as produced by snapshots */
+    type Components = HashMap<u64, (u64, Vec<BacktraceState>)>;
+
+    #[derive(Serialize, Deserialize)]
+    struct InternedFrame<'a> {
+        func: u32,
+        file: Option<u32>,
+        line: Option<u32>,
+        vars: Cow<'a, [DisplayVariable]>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    enum InternedState<'a> {
+        Frame(InternedFrame<'a>),
+        Other(Cow<'a, BacktraceState>),
+    }
+
+    #[derive(Serialize)]
+    struct InternedComponents<'a> {
+        strings: Vec<&'a str>,
+        /* (hash, count, states) */
+        components: Vec<(u64, u64, Vec<InternedState<'a>>)>,
+    }
+
+    #[derive(Deserialize)]
+    struct OwnedComponents<'a> {
+        strings: Vec<String>,
+        components: Vec<(u64, u64, Vec<InternedState<'a>>)>,
+    }
+
+    #[derive(Default)]
+    struct Interner<'a> {
+        strings: Vec<&'a str>,
+        index: HashMap<&'a str, u32>,
+    }
+
+    impl<'a> Interner<'a> {
+        fn intern(&mut self, s: &'a str) -> u32 {
+            if let Some(i) = self.index.get(s) {
+                return *i;
+            }
+
+            let i = self.strings.len() as u32;
+            self.strings.push(s);
+            self.index.insert(s, i);
+            i
+        }
+    }
+
+    pub fn serialize<S: Serializer>(
+        components: &Components,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut interner = Interner::default();
+
+        let components = components
+            .iter()
+            .map(|(hash, (cnt, states))| {
+                let states = states
+                    .iter()
+                    .map(|st| match st {
+                        BacktraceState::Frame(f) => InternedState::Frame(InternedFrame {
+                            func: interner.intern(&f.func),
+                            file: f.file.as_deref().map(|file| interner.intern(file)),
+                            line: f.line,
+                            vars: Cow::Borrowed(&f.vars),
+                        }),
+                        other => InternedState::Other(Cow::Borrowed(other)),
+                    })
+                    .collect();
+
+                (*hash, *cnt, states)
+            })
+            .collect();
+
+        InternedComponents {
+            strings: interner.strings,
+            components,
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Components, D::Error> {
+        let interned = OwnedComponents::deserialize(deserializer)?;
+
+        let string = |i: u32| {
+            interned
+                .strings
+                .get(i as usize)
+                .cloned()
+                .ok_or_else(|| D::Error::custom(format!("No interned string {}", i)))
+        };
+
+        let mut ret = HashMap::new();
+
+        for (hash, cnt, states) in interned.components.iter() {
+            let states = states
+                .iter()
+                .map(|st| {
+                    Ok(match st {
+                        InternedState::Frame(f) => BacktraceState::Frame(DisplayFrame {
+                            func: string(f.func)?,
+                            file: f.file.map(string).transpose()?,
+                            line: f.line,
+                            vars: f.vars.to_vec(),
+                        }),
+                        InternedState::Other(o) => o.clone().into_owned(),
+                    })
+                })
+                .collect::<Result<Vec<_>, D::Error>>()?;
+
+            ret.insert(*hash, (*cnt, states));
+        }
+
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::{DisplayFrame, DisplayMissing, DisplayState, DisplayVariable};
+
+    fn frame(func: &str, line: u32) -> BacktraceState {
+        BacktraceState::Frame(DisplayFrame {
+            func: func.to_string(),
+            file: Some("/src/solver.c".to_string()),
+            line: Some(line),
+            vars: vec![DisplayVariable {
+                name: "n".to_string(),
+                arg: true,
+                values: vec![("4".to_string(), 2), ("5".to_string(), 1)],
+            }],
+        })
+    }
+
+    fn components() -> HashMap<u64, (u64, Vec<BacktraceState>)> {
+        let state = BacktraceState::State(DisplayState {
+            reason: "signal-received".to_string(),
+            signal_name: Some("SIGSEGV".to_string()),
+            exit_code: None,
+            breakpoint_num: None,
+            fault: None,
+            detail: None,
+        });
+
+        HashMap::from([
+            (1, (3, vec![state, frame("main", 12), frame("solve", 40)])),
+            (2, (1, vec![frame("main", 12), frame("wait", 7)])),
+            (
+                3,
+                (
+                    2,
+                    vec![BacktraceState::Missing(DisplayMissing {
+                        error: "No answer within 1.0s".to_string(),
+                        processes: vec!["rank 3".to_string(), "rank 4".to_string()],
+                    })],
+                ),
+            ),
+        ])
+    }
+
+    fn snapshot(resp: GdbMachineResponse) -> HashMap<u64, (u64, Vec<BacktraceState>)> {
+        match resp {
+            GdbMachineResponse::Snapshot(s) => s,
+            other => panic!("Unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    fn interned_snapshot_round_trip() {
+        let expected = serde_json::to_value(components()).unwrap();
+
+        let bin = bincode::serialize(&GdbMachineResponse::Snapshot(components())).unwrap();
+        let resp = bincode::deserialize(&bin).unwrap();
+        assert_eq!(serde_json::to_value(snapshot(resp)).unwrap(), expected);
+
+        let json = serde_json::to_vec(&GdbMachineResponse::Snapshot(components())).unwrap();
+        let resp = serde_json::from_slice(&json).unwrap();
+        assert_eq!(serde_json::to_value(snapshot(resp)).unwrap(), expected);
+    }
+
+    #[test]
+    fn interned_strings_are_sent_once() {
+        let json = serde_json::to_value(GdbMachineResponse::Snapshot(components())).unwrap();
+
+        let mut strings: Vec<&str> = json["Snapshot"]["strings"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| s.as_str().unwrap())
+            .collect();
+        strings.sort();
+
+        assert_eq!(strings, vec!["/src/solver.c", "main", "solve", "wait"]);
+    }
+
+    #[test]
+    fn unknown_interned_string_is_an_error() {
+        let json = r#"{"Snapshot":{"strings":["main"],"components":[[1,1,[{"Frame":{"func":1,"file":null,"line":null,"vars":[]}}]]]}}"#;
+
+        assert!(serde_json::from_str::<GdbMachineResponse>(json).is_err());
     }
 }
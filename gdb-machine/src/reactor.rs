@@ -0,0 +1,402 @@
+//! Event loops driving the links of a node
+//!
+//! A node serves all its connections (its parent, joining processes and
+//! observers) from a single thread polling them with mio, each command being
+//! run by a short-lived thread while the loop keeps serving the others.
+//! Commands sent to the children of a node are written to all of them at
+//! once and their answers are collected as they arrive ([exchange]), a node
+//! thus needs no thread per connection nor per child.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::mem;
+use std::os::fd::AsRawFd;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Token, Waker};
+
+use crate::link::{Accepting, Link, Received};
+use crate::protocol::{GdbMachineRequest, GdbMachineResponse};
+use crate::transport::{Listener, Stream};
+
+/// Events handled per wake up of a loop
+const EVENT_CAPACITY: usize = 1024;
+
+/// Commands run at once by a node, the others wait for their turn
+const MAX_RUNNING_COMMANDS: usize = 16;
+
+/// Wait before accepting again after a failure (such as running out of fds)
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Woken up when a command completed
+const WAKER: Token = Token(0);
+/// Listening sockets come after the waker, then connections
+const FIRST_LISTENER: usize = 1;
+
+/// Runs the commands received by a node
+pub(crate) type Handler = dyn Fn(GdbMachineRequest) -> GdbMachineResponse + Send + Sync;
+
+fn poll_events(poll: &mut Poll, events: &mut Events, deadline: Option<Instant>) -> Result<()> {
+    let timeout = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+
+    match poll.poll(events, timeout) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::Interrupted => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn register(poll: &Poll, stream: &Stream, token: Token) -> Result<()> {
+    stream.set_nonblocking(true)?;
+    poll.registry().register(
+        &mut SourceFd(&stream.as_raw_fd()),
+        token,
+        Interest::READABLE | Interest::WRITABLE,
+    )?;
+
+    Ok(())
+}
+
+struct Connection {
+    link: Link<Stream>,
+    setup: Accepting,
+    /// Requests waiting for the previous one to be answered
+    requests: VecDeque<GdbMachineRequest>,
+    /// Is a command of this connection running
+    busy: bool,
+}
+
+impl Connection {
+    /// Write what can be, then read what arrived, returns false once closed
+    fn progress(&mut self) -> Result<bool> {
+        self.link.flush()?;
+
+        if !matches!(self.setup, Accepting::Ready) {
+            let step = mem::replace(&mut self.setup, Accepting::Ready);
+
+            match self.link.accept_step(step) {
+                Ok(next) => self.setup = next,
+                Err(e) => {
+                    /* Let the peer know why it is refused */
+                    let _ = self.link.flush();
+                    return Err(e);
+                }
+            }
+
+            self.link.flush()?;
+
+            if !matches!(self.setup, Accepting::Ready) {
+                return Ok(true);
+            }
+        }
+
+        loop {
+            match self.link.try_recv::<GdbMachineRequest>()? {
+                Received::Message(req) => {
+                    log::debug!("INBOUND: {:?}", req);
+                    self.requests.push_back(req);
+                }
+                Received::Pending => return Ok(true),
+                Received::Closed => return Ok(false),
+            }
+        }
+    }
+}
+
+struct Server<'a> {
+    listener: &'a Listener,
+    poll: Poll,
+    waker: Arc<Waker>,
+    handler: Arc<Handler>,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    done_tx: Sender<(Token, GdbMachineResponse)>,
+    done_rx: Receiver<(Token, GdbMachineResponse)>,
+    running: usize,
+    /// Connections with a request waiting for a command to complete
+    waiting: VecDeque<Token>,
+    /// Listening sockets which failed to accept, retried at `retry_accept`
+    stalled: Vec<usize>,
+    retry_accept: Option<Instant>,
+}
+
+impl Server<'_> {
+    fn close(&mut self, token: Token) {
+        if let Some(conn) = self.connections.remove(&token) {
+            let _ = self
+                .poll
+                .registry()
+                .deregister(&mut SourceFd(&conn.link.get_ref().as_raw_fd()));
+        }
+    }
+
+    fn accept(&mut self, index: usize) {
+        loop {
+            let stream = match self.listener.accept(index) {
+                Ok(Some(stream)) => stream,
+                Ok(None) => return,
+                Err(e) => {
+                    /* Pending connections raise no new event, they are accepted later */
+                    log::error!(
+                        "Failed to accept a connection, retrying in {}ms: {}",
+                        ACCEPT_RETRY_DELAY.as_millis(),
+                        e
+                    );
+
+                    if !self.stalled.contains(&index) {
+                        self.stalled.push(index);
+                    }
+
+                    self.retry_accept
+                        .get_or_insert(Instant::now() + ACCEPT_RETRY_DELAY);
+                    return;
+                }
+            };
+
+            let token = Token(self.next_token);
+            self.next_token += 1;
+
+            if let Err(e) = register(&self.poll, &stream, token) {
+                log::error!("Failed to watch a connection: {}", e);
+                continue;
+            }
+
+            let (link, setup) = Link::accept(stream);
+
+            self.connections.insert(
+                token,
+                Connection {
+                    link,
+                    setup,
+                    requests: VecDeque::new(),
+                    busy: false,
+                },
+            );
+
+            self.progress(token);
+        }
+    }
+
+    fn progress(&mut self, token: Token) {
+        let Some(conn) = self.connections.get_mut(&token) else {
+            return;
+        };
+
+        match conn.progress() {
+            Ok(true) => self.dispatch(token),
+            Ok(false) => self.close(token),
+            Err(e) => {
+                log::warn!("Error processing client request : {}", e);
+                self.close(token);
+            }
+        }
+    }
+
+    /// Run the next request of a connection if it is not already running one
+    fn dispatch(&mut self, token: Token) {
+        let Some(conn) = self.connections.get_mut(&token) else {
+            return;
+        };
+
+        if conn.busy || conn.requests.is_empty() {
+            return;
+        }
+
+        if self.running >= MAX_RUNNING_COMMANDS {
+            if !self.waiting.contains(&token) {
+                self.waiting.push_back(token);
+            }
+            return;
+        }
+
+        let req = conn.requests.pop_front().unwrap();
+        conn.busy = true;
+        self.running += 1;
+
+        let handler = self.handler.clone();
+        let done = self.done_tx.clone();
+        let waker = self.waker.clone();
+
+        let spawned = thread::Builder::new()
+            .name("snapped-command".to_string())
+            .spawn(move || {
+                let resp = panic::catch_unwind(AssertUnwindSafe(|| handler(req)))
+                    .unwrap_or(GdbMachineResponse::Error("Command panicked".to_string()));
+
+                if done.send((token, resp)).is_ok() {
+                    let _ = waker.wake();
+                }
+            });
+
+        if let Err(e) = spawned {
+            let _ = self.done_tx.send((
+                token,
+                GdbMachineResponse::Error(format!("Failed to run command: {}", e)),
+            ));
+            let _ = self.waker.wake();
+        }
+    }
+
+    /// Send the responses of the completed commands
+    fn complete(&mut self) {
+        while let Ok((token, resp)) = self.done_rx.try_recv() {
+            self.running -= 1;
+
+            let Some(conn) = self.connections.get_mut(&token) else {
+                continue;
+            };
+
+            log::debug!("OUTBOUND: {:?}", resp);
+
+            conn.busy = false;
+
+            match conn.link.queue(&resp).and_then(|_| conn.link.flush()) {
+                Ok(_) => self.dispatch(token),
+                Err(e) => {
+                    log::warn!("Failed to answer a client : {}", e);
+                    self.close(token);
+                }
+            }
+        }
+
+        while self.running < MAX_RUNNING_COMMANDS {
+            match self.waiting.pop_front() {
+                Some(token) => self.dispatch(token),
+                None => break,
+            }
+        }
+    }
+}
+
+/// Serve the connections of `listener`, running their requests with `handler`
+pub(crate) fn serve(listener: &Listener, handler: Arc<Handler>) -> Result<()> {
+    let poll = Poll::new()?;
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+
+    listener.set_nonblocking()?;
+
+    let fds = listener.fds();
+
+    for (i, fd) in fds.iter().enumerate() {
+        poll.registry().register(
+            &mut SourceFd(fd),
+            Token(FIRST_LISTENER + i),
+            Interest::READABLE,
+        )?;
+    }
+
+    let (done_tx, done_rx) = channel();
+
+    let mut server = Server {
+        listener,
+        poll,
+        waker,
+        handler,
+        connections: HashMap::new(),
+        next_token: FIRST_LISTENER + fds.len(),
+        done_tx,
+        done_rx,
+        running: 0,
+        waiting: VecDeque::new(),
+        stalled: Vec::new(),
+        retry_accept: None,
+    };
+
+    let mut events = Events::with_capacity(EVENT_CAPACITY);
+
+    loop {
+        poll_events(&mut server.poll, &mut events, server.retry_accept)?;
+
+        for event in events.iter() {
+            match event.token() {
+                WAKER => {}
+                Token(t) if t < FIRST_LISTENER + fds.len() => server.accept(t - FIRST_LISTENER),
+                token => server.progress(token),
+            }
+        }
+
+        server.complete();
+
+        if server.retry_accept.is_some_and(|t| t <= Instant::now()) {
+            server.retry_accept = None;
+
+            for index in mem::take(&mut server.stalled) {
+                server.accept(index);
+            }
+        }
+    }
+}
+
+/// Write the request of a link and read its response if complete
+fn answer(link: &mut Link<Stream>) -> Result<Option<GdbMachineResponse>> {
+    link.flush()?;
+
+    match link.try_recv()? {
+        Received::Message(resp) => Ok(Some(resp)),
+        Received::Pending => Ok(None),
+        Received::Closed => Err(anyhow!("Connection closed before a response")),
+    }
+}
+
+/// Send `req` on all `links` at once and collect the responses as they
+/// arrive until `deadline`, links which did not answer in time get None
+pub(crate) fn exchange(
+    links: &mut [&mut Link<Stream>],
+    req: &GdbMachineRequest,
+    deadline: Option<Instant>,
+) -> Result<Vec<Option<Result<GdbMachineResponse>>>> {
+    let mut poll = Poll::new()?;
+    let mut events = Events::with_capacity(EVENT_CAPACITY);
+
+    let mut ret: Vec<Option<Result<GdbMachineResponse>>> = Vec::with_capacity(links.len());
+    let mut pending = 0;
+
+    for (i, link) in links.iter_mut().enumerate() {
+        match register(&poll, link.get_ref(), Token(i)).and_then(|_| link.queue(req)) {
+            Ok(_) => {
+                pending += 1;
+                ret.push(None);
+            }
+            Err(e) => ret.push(Some(Err(e))),
+        }
+    }
+
+    /* Registered streams report their readiness at once, writing the requests */
+    while pending > 0 {
+        if deadline.is_some_and(|d| d <= Instant::now()) {
+            break;
+        }
+
+        poll_events(&mut poll, &mut events, deadline)?;
+
+        for event in events.iter() {
+            let i = event.token().0;
+
+            if ret[i].is_some() {
+                continue;
+            }
+
+            ret[i] = answer(links[i]).transpose();
+
+            if ret[i].is_some() {
+                pending -= 1;
+            }
+        }
+    }
+
+    for link in links.iter_mut() {
+        let stream = link.get_ref();
+        let _ = poll
+            .registry()
+            .deregister(&mut SourceFd(&stream.as_raw_fd()));
+        let _ = stream.set_nonblocking(false);
+    }
+
+    Ok(ret)
+}
@@ -0,0 +1,261 @@
+//! Optional encryption of the links between tree nodes
+//!
+//! The root generates a self-signed CA for the session whose certificate is
+//! given to the processes it launches through the environment (as the session
+//! token), its key never leaves the root. Each node generates its own key and
+//! has the root sign its certificate once authenticated ([TlsSession::sign]),
+//! connecting nodes only trust servers presenting a certificate of the session.
+
+use std::env;
+use std::sync::{Arc, OnceLock};
+
+use anyhow::{anyhow, Result};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair,
+    KeyUsagePurpose, PublicKeyData, SignatureAlgorithm, PKCS_ECDSA_P256_SHA256,
+};
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+
+/* Certificate of the session CA (PEM), links are encrypted when set */
+pub const ENV_TLS_CA: &str = "GDBW_TLS_CA";
+
+/// Name given to all the nodes in their certificate
+const SESSION_NAME: &str = "snapped.session";
+
+/// Algorithm of the keys of the nodes (see [KeyPair::generate])
+static NODE_KEY_ALGORITHM: &SignatureAlgorithm = &PKCS_ECDSA_P256_SHA256;
+
+static SESSION: OnceLock<Option<TlsSession>> = OnceLock::new();
+
+/* Only set on the root */
+static AUTHORITY: OnceLock<Authority> = OnceLock::new();
+
+/// The session CA signing the certificates of the nodes
+struct Authority {
+    cert: Certificate,
+    key: KeyPair,
+}
+
+/// Public key of a node to be certified
+struct NodeKey<'a>(&'a [u8]);
+
+impl PublicKeyData for NodeKey<'_> {
+    fn der_bytes(&self) -> &[u8] {
+        self.0
+    }
+
+    fn algorithm(&self) -> &SignatureAlgorithm {
+        NODE_KEY_ALGORITHM
+    }
+}
+
+/// TLS settings shared by all the links of a node
+pub(crate) struct TlsSession {
+    pub(crate) client: Arc<ClientConfig>,
+    /// Set once the certificate of the node is signed
+    server: OnceLock<Arc<ServerConfig>>,
+    ca_der: CertificateDer<'static>,
+    key: KeyPair,
+}
+
+fn ca_params() -> Result<CertificateParams> {
+    let mut params = CertificateParams::new(Vec::new())?;
+    let mut name = DistinguishedName::new();
+    name.push(DnType::CommonName, "snapped session CA");
+    params.distinguished_name = name;
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign];
+
+    Ok(params)
+}
+
+impl Authority {
+    fn generate() -> Result<Authority> {
+        let key = KeyPair::generate()?;
+        let cert = ca_params()?.self_signed(&key)?;
+
+        Ok(Authority { cert, key })
+    }
+}
+
+/// Generate the CA of the session, its certificate is exported
+/// for the processes launched by the root and its key kept here
+pub fn export_session_ca() -> Result<()> {
+    let authority = Authority::generate()?;
+
+    env::set_var(ENV_TLS_CA, authority.cert.pem());
+
+    AUTHORITY
+        .set(authority)
+        .map_err(|_| anyhow!("The session CA was already generated"))
+}
+
+impl TlsSession {
+    fn from_env() -> Result<Option<TlsSession>> {
+        let Ok(ca_pem) = env::var(ENV_TLS_CA) else {
+            return Ok(None);
+        };
+
+        let session = TlsSession::new(&ca_pem)?;
+
+        /* The root certifies itself */
+        if AUTHORITY.get().is_some() {
+            let cert = TlsSession::sign(&session.public_key())?;
+            session.set_certificate(&cert)?;
+        }
+
+        Ok(Some(session))
+    }
+
+    /// Settings of a node trusting the CA `ca_pem`, with no certificate yet
+    fn new(ca_pem: &str) -> Result<TlsSession> {
+        let ca_der = CertificateDer::from_pem_slice(ca_pem.as_bytes())
+            .map_err(|e| anyhow!("Bad session CA in {}: {}", ENV_TLS_CA, e))?;
+
+        let provider = Arc::new(ring::default_provider());
+
+        let mut roots = RootCertStore::empty();
+        roots.add(ca_der.clone())?;
+
+        let client = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Ok(TlsSession {
+            client: Arc::new(client),
+            server: OnceLock::new(),
+            ca_der,
+            key: KeyPair::generate_for(NODE_KEY_ALGORITHM)?,
+        })
+    }
+
+    /// Sign the certificate of a node holding `public_key`, only the root can
+    pub(crate) fn sign(public_key: &[u8]) -> Result<String> {
+        let authority = AUTHORITY
+            .get()
+            .ok_or(anyhow!("Only the root signs certificates"))?;
+
+        /* Uncompressed P-256 points */
+        if public_key.len() != 65 || public_key[0] != 4 {
+            return Err(anyhow!("Bad public key of {} bytes", public_key.len()));
+        }
+
+        let cert = CertificateParams::new(vec![SESSION_NAME.to_string()])?.signed_by(
+            &NodeKey(public_key),
+            &authority.cert,
+            &authority.key,
+        )?;
+
+        Ok(cert.pem())
+    }
+
+    /// Public key of the node to have its certificate signed
+    pub(crate) fn public_key(&self) -> Vec<u8> {
+        self.key.public_key_raw().to_vec()
+    }
+
+    /// Does the node hold its certificate
+    pub(crate) fn certified(&self) -> bool {
+        self.server.get().is_some()
+    }
+
+    /// Serve links with the certificate signed for this node
+    pub(crate) fn set_certificate(&self, cert_pem: &str) -> Result<()> {
+        let cert = CertificateDer::from_pem_slice(cert_pem.as_bytes())
+            .map_err(|e| anyhow!("Bad node certificate: {}", e))?;
+
+        let server = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert, self.ca_der.clone()],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(self.key.serialize_der())),
+            )?;
+
+        self.server
+            .set(Arc::new(server))
+            .map_err(|_| anyhow!("The node certificate was already set"))
+    }
+
+    /// Settings to serve links, the node must hold its certificate
+    pub(crate) fn server(&self) -> Result<Arc<ServerConfig>> {
+        self.server
+            .get()
+            .cloned()
+            .ok_or(anyhow!("This node has no certificate yet"))
+    }
+
+    /// Settings of this node, None when links are not encrypted
+    pub(crate) fn get() -> Result<Option<&'static TlsSession>> {
+        if let Some(session) = SESSION.get() {
+            return Ok(session.as_ref());
+        }
+
+        let session = TlsSession::from_env()?;
+
+        Ok(SESSION.get_or_init(|| session).as_ref())
+    }
+
+    pub(crate) fn server_name() -> ServerName<'static> {
+        ServerName::try_from(SESSION_NAME).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{ClientConnection, ServerConnection, StreamOwned};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    /// The root CA without exporting it, tests share the environment
+    fn authority() -> &'static Authority {
+        AUTHORITY.get_or_init(|| Authority::generate().unwrap())
+    }
+
+    #[test]
+    fn nodes_are_certified_by_the_root() {
+        let ca_pem = authority().cert.pem();
+
+        let node = TlsSession::new(&ca_pem).unwrap();
+        assert!(node.server().is_err());
+
+        node.set_certificate(&TlsSession::sign(&node.public_key()).unwrap())
+            .unwrap();
+
+        /* A peer only knowing the CA trusts the node */
+        let peer = TlsSession::new(&ca_pem).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = node.server().unwrap();
+
+        let serving = thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let mut tls = StreamOwned::new(ServerConnection::new(server).unwrap(), sock);
+            let mut buf = [0; 5];
+            tls.read_exact(&mut buf).unwrap();
+            buf
+        });
+
+        let conn = ClientConnection::new(peer.client.clone(), TlsSession::server_name()).unwrap();
+        let mut tls = StreamOwned::new(conn, TcpStream::connect(addr).unwrap());
+        tls.write_all(b"hello").unwrap();
+        tls.flush().unwrap();
+
+        assert_eq!(&serving.join().unwrap(), b"hello");
+    }
+
+    #[test]
+    fn bad_public_keys_are_not_signed() {
+        authority();
+
+        assert!(TlsSession::sign(&[4; 12]).is_err());
+        assert!(TlsSession::sign(&[0; 65]).is_err());
+    }
+}
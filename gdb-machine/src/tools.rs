@@ -76,73 +76,58 @@ pub fn strdistance(a: &String, b: &String) -> u64 {
     ret
 }
 
-pub fn parse_gdb_equal_list(list: &str) -> HashMap<String, String> {
-    let re = Regex::new("([a-z\\-]+)=\"([^\"]+)\"").unwrap();
-
-    let cap: Vec<(String, String)> = re
-        .captures_iter(list)
-        .flat_map(|b| {
-            if let (Some(k), Some(v)) = (b.get(1), b.get(2)) {
-                Some((k.as_str().to_string(), v.as_str().to_string()))
-            } else {
-                None
+/// Shorten a value printed by GDB
+///
+/// Aggregates nested deeper than `max_depth` are replaced by `{...}`
+/// and the result is cut to `max_len` characters
+pub fn truncate_value(value: &str, max_depth: u32, max_len: usize) -> String {
+    let mut ret = String::new();
+    let mut depth: u32 = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for c in value.chars() {
+        let visible = depth <= max_depth;
+
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
             }
-        })
-        .collect();
-    HashMap::from_iter(cap)
-}
-
-pub fn extract_gdb_group(list: &str) -> Vec<String> {
-    let re = Regex::new("\\{([^{}]*)\\}").unwrap();
-
-    re.captures_iter(list)
-        .flat_map(|v| v.get(1))
-        .map(|v| v.as_str().to_string())
-        .collect()
-}
-
-pub fn parse_response_with_token(marker: &str, resp: &str) -> Option<(u64, String)> {
-    let re = Regex::new(format!("^([0-9]+){}(.*)\n", marker).as_str()).ok()?;
-
-    let cap = re.captures(resp)?;
+        } else if c == '"' {
+            in_string = true;
+        } else if c == '{' {
+            depth += 1;
+            if depth == max_depth + 1 {
+                ret.push_str("{...");
+            }
+        } else if c == '}' {
+            depth = depth.saturating_sub(1);
+            if depth == max_depth {
+                ret.push('}');
+                continue;
+            }
+        }
 
-    if let (Some(id), Some(resp)) = (cap.get(1), cap.get(2)) {
-        let id = id.as_str().parse::<u64>().ok()?;
-        return Some((id, resp.as_str().to_string()));
+        if visible && depth <= max_depth {
+            ret.push(c);
+        }
     }
 
-    None
-}
-
-pub fn gdb_output_to_json_repr(resp: &str) -> Result<String> {
-    let re = Regex::new("([a-zA-Z]+)=")?;
-
-    let resp_json = re.replace_all(resp, "\"$1\" : ");
+    if ret.chars().count() > max_len {
+        ret = ret.chars().take(max_len).collect();
+        ret.push_str("...");
+    }
 
-    Ok(resp_json.to_string())
+    ret
 }
 
-pub fn read_until_null(stream: &mut impl Read) -> Result<String> {
-    let mut ret: String = String::new();
-
-    loop {
-        let mut data = [0; 1];
-        match stream.read(&mut data) {
-            Ok(0) => {
-                return Ok(ret);
-            }
-            Ok(n) => {
-                for i in 0..n {
-                    if data[i] as char == '\0' {
-                        return Ok(ret);
-                    } else {
-                        ret.push_str(std::str::from_utf8(&data[i..i + 1])?);
-                    }
-                }
-            }
-            Err(e) => {
-                return Err(anyhow!(e));
-            }
-        }
-    }
+/// Parse an hexadecimal address as printed by GDB (e.g. `0x401136`)
+pub fn parse_address(addr: &str) -> Option<u64> {
+    let hex = addr.split_whitespace().next()?;
+    let hex = hex.strip_prefix("0x").unwrap_or(hex);
+    u64::from_str_radix(hex, 16).ok()
 }
@@ -0,0 +1,460 @@
+//! Placement of the processes joining the tree
+//!
+//! All processes first contact the root which picks their parent in the tree
+//! according to a [Placement] strategy, the root and every node having up to
+//! [TopologyOptions::arity] children (aggregators of a host also take all the
+//! processes of their host with [Placement::Host]).
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+
+use crate::metadata::{ProcessInfo, TreeIdFactory, TREE_ARITY};
+use crate::tools::strdistance;
+
+/// Processes of a host taken by its aggregator
+const MAX_HOST_PROCESSES: u64 = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Placement {
+    /// Closest locality descriptor (host, NUMA node and pid)
+    Locality,
+    /// One aggregator per host, aggregators forming a k-ary tree
+    Host,
+    /// k-ary tree in rank order
+    Rank,
+    /// All processes under the root
+    Flat,
+}
+
+impl Placement {
+    pub const ALL: [Placement; 4] = [
+        Placement::Locality,
+        Placement::Host,
+        Placement::Rank,
+        Placement::Flat,
+    ];
+
+    /// Name of the placement on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            Placement::Locality => "locality",
+            Placement::Host => "host",
+            Placement::Rank => "rank",
+            Placement::Flat => "flat",
+        }
+    }
+}
+
+impl fmt::Display for Placement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Placement {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Placement> {
+        Placement::ALL
+            .into_iter()
+            .find(|p| p.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<&str> = Placement::ALL.iter().map(|p| p.name()).collect();
+                anyhow!("Unknown placement {} (expected {})", s, names.join(", "))
+            })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TopologyOptions {
+    pub placement: Placement,
+    /// Children of each node
+    pub arity: u64,
+    /// Processes expected to join (all under the root for [Placement::Flat])
+    pub size: u64,
+}
+
+impl Default for TopologyOptions {
+    fn default() -> TopologyOptions {
+        TopologyOptions {
+            placement: Placement::Locality,
+            arity: TREE_ARITY,
+            size: 0,
+        }
+    }
+}
+
+/// A process of the tree as shown to users
+#[derive(Clone, Debug)]
+pub struct TopologyNode {
+    /// See [ProcessInfo::tag] ("root" for the root)
+    pub tag: String,
+    pub hostname: String,
+    /// Index of the parent in the nodes (None for the root)
+    pub parent: Option<usize>,
+    /// Does it aggregate the processes of its host
+    pub aggregator: bool,
+}
+
+struct Node {
+    info: Option<ProcessInfo>,
+    url: String,
+    ids: TreeIdFactory,
+    parent: Option<usize>,
+    /// Aggregators placed under this one ([Placement::Host])
+    child_aggregators: u64,
+    aggregator: bool,
+}
+
+/// Tree built by the root as processes join
+pub struct Topology {
+    opts: TopologyOptions,
+    /// The root comes first then processes in their order of arrival
+    nodes: Vec<Node>,
+    by_locator: HashMap<String, usize>,
+    by_rank: HashMap<u32, usize>,
+    aggregators: HashMap<String, usize>,
+}
+
+impl Topology {
+    pub fn new(root_url: String, opts: TopologyOptions) -> Topology {
+        let arity = match opts.placement {
+            Placement::Flat => opts.size.max(opts.arity),
+            _ => opts.arity,
+        };
+
+        let root = Node {
+            info: None,
+            url: root_url,
+            ids: TreeIdFactory::new(arity),
+            parent: None,
+            child_aggregators: 0,
+            aggregator: false,
+        };
+
+        Topology {
+            opts,
+            nodes: vec![root],
+            by_locator: HashMap::new(),
+            by_rank: HashMap::new(),
+            aggregators: HashMap::new(),
+        }
+    }
+
+    /// Number of processes in the tree (root included)
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Never true as the root is part of the tree
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    fn available(&self, idx: usize) -> bool {
+        !self.nodes[idx].ids.full()
+    }
+
+    /// Node with the closest locality descriptor, the root being filled first
+    fn parent_by_locality(&self, info: &ProcessInfo) -> Option<usize> {
+        if self.available(0) {
+            return Some(0);
+        }
+
+        (1..self.nodes.len())
+            .filter(|i| self.available(*i))
+            .min_by_key(|i| {
+                let locator = &self.nodes[*i].info.as_ref().unwrap().locality_descriptor;
+                strdistance(locator, &info.locality_descriptor)
+            })
+    }
+
+    /// Parent in a k-ary heap of ranks (children of rank p being
+    /// (p + 1) * k to (p + 1) * k + k - 1), otherwise the closest rank
+    fn parent_by_rank(&self, info: &ProcessInfo) -> Option<usize> {
+        let arity = self.opts.arity.max(1);
+        /* Processes without rank are placed in their order of arrival */
+        let rank = info
+            .mpirank
+            .map(|r| r as u64)
+            .unwrap_or(self.nodes.len() as u64 - 1);
+
+        let preferred = match rank / arity {
+            0 => Some(0),
+            p => self.by_rank.get(&((p - 1) as u32)).copied(),
+        };
+
+        if let Some(idx) = preferred.filter(|i| self.available(*i)) {
+            return Some(idx);
+        }
+
+        (0..self.nodes.len())
+            .filter(|i| self.available(*i))
+            .min_by_key(|i| match self.nodes[*i].info.as_ref() {
+                Some(ProcessInfo {
+                    mpirank: Some(r), ..
+                }) => (*r as u64).abs_diff(rank),
+                Some(_) => u64::MAX,
+                /* The root is at rank "-1" */
+                None => rank + 1,
+            })
+    }
+
+    /// Aggregator of the host if any, otherwise the first node with
+    /// room for a new aggregator (in order of arrival)
+    fn parent_by_host(&self, info: &ProcessInfo) -> Option<(usize, bool)> {
+        if let Some(idx) = self.aggregators.get(&info.hostname) {
+            return self.available(*idx).then_some((*idx, false));
+        }
+
+        let arity = self.opts.arity.max(1);
+
+        if self.available(0) {
+            return Some((0, true));
+        }
+
+        (1..self.nodes.len())
+            .find(|i| {
+                let n = &self.nodes[*i];
+                n.aggregator && n.child_aggregators < arity && self.available(*i)
+            })
+            .map(|i| (i, true))
+    }
+
+    /// Place a process listening on `url`, returns its ID and the URL of its parent
+    pub fn place(&mut self, info: &ProcessInfo, url: String) -> Result<(u64, String)> {
+        if self.by_locator.contains_key(&info.locality_descriptor) {
+            return Err(anyhow!(
+                "Process {:?} is already registered",
+                info.locality_descriptor
+            ));
+        }
+
+        let (parent, aggregator) = match self.opts.placement {
+            Placement::Locality => (self.parent_by_locality(info), false),
+            Placement::Rank => (self.parent_by_rank(info), false),
+            Placement::Flat => (Some(0).filter(|_| self.available(0)), false),
+            Placement::Host => match self.parent_by_host(info) {
+                Some((p, a)) => (Some(p), a),
+                None => (None, false),
+            },
+        };
+
+        let parent = parent.ok_or(anyhow!(
+            "No room left in the tree for {} (increase the arity)",
+            info.tag()
+        ))?;
+
+        let arity = match aggregator {
+            true => self.opts.arity + MAX_HOST_PROCESSES,
+            false => self.opts.arity,
+        };
+
+        let ids = self.nodes[parent].ids.inherit(arity)?;
+        let id = ids.id();
+
+        if aggregator {
+            self.nodes[parent].child_aggregators += 1;
+            self.aggregators
+                .insert(info.hostname.clone(), self.nodes.len());
+        }
+
+        log::info!(
+            "PIVOT {} is joining {}",
+            info.tag(),
+            self.nodes[parent]
+                .info
+                .as_ref()
+                .map(|i| i.tag())
+                .unwrap_or("ROOT".to_string())
+        );
+
+        let parent_url = self.nodes[parent].url.clone();
+
+        self.by_locator
+            .insert(info.locality_descriptor.clone(), self.nodes.len());
+
+        if let Some(rank) = info.mpirank {
+            self.by_rank.insert(rank, self.nodes.len());
+        }
+
+        self.nodes.push(Node {
+            info: Some(info.clone()),
+            url,
+            ids,
+            parent: Some(parent),
+            child_aggregators: 0,
+            aggregator,
+        });
+
+        Ok((id, parent_url))
+    }
+
+    /// The processes of the tree with their parent
+    pub fn nodes(&self) -> Vec<TopologyNode> {
+        self.nodes
+            .iter()
+            .map(|n| TopologyNode {
+                tag: n
+                    .info
+                    .as_ref()
+                    .map(|i| i.tag())
+                    .unwrap_or("root".to_string()),
+                hostname: n
+                    .info
+                    .as_ref()
+                    .map(|i| i.hostname.clone())
+                    .unwrap_or_default(),
+                parent: n.parent,
+                aggregator: n.aggregator,
+            })
+            .collect()
+    }
+
+    /// Depth of the tree and largest number of children of a node
+    pub fn shape(&self) -> (usize, u64) {
+        let mut depth = 0;
+
+        for n in self.nodes.iter() {
+            let mut d = 0;
+            let mut cur = n.parent;
+
+            while let Some(p) = cur {
+                d += 1;
+                cur = self.nodes[p].parent;
+            }
+
+            depth = depth.max(d);
+        }
+
+        let fan_out = self.nodes.iter().map(|n| n.ids.children()).max();
+
+        (depth, fan_out.unwrap_or(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn process(hostname: &str, rank: Option<u32>, pid: u64) -> ProcessInfo {
+        ProcessInfo {
+            mpirank: rank,
+            pid,
+            hostname: hostname.to_string(),
+            locality_descriptor: format!("{}-0-{}", hostname, pid),
+        }
+    }
+
+    fn topology(placement: Placement, arity: u64) -> Topology {
+        Topology::new(
+            "root:1".to_string(),
+            TopologyOptions {
+                placement,
+                arity,
+                size: 0,
+            },
+        )
+    }
+
+    /// Tag of the parent of the last placed process
+    fn last_parent(t: &Topology) -> String {
+        let nodes = t.nodes();
+        let parent = nodes.last().unwrap().parent.unwrap();
+        nodes[parent].tag.clone()
+    }
+
+    #[test]
+    fn placements_are_parsed_from_their_names() {
+        for p in Placement::ALL {
+            assert_eq!(p.to_string().parse::<Placement>().unwrap(), p);
+        }
+        assert!("tree".parse::<Placement>().is_err());
+    }
+
+    #[test]
+    fn ranks_form_a_heap() {
+        let mut t = topology(Placement::Rank, 2);
+
+        /* Children of rank p are (p + 1) * 2 and (p + 1) * 2 + 1 */
+        let expected = [
+            "root", "root", "rank 0", "rank 0", "rank 1", "rank 1", "rank 2",
+        ];
+
+        for (rank, parent) in expected.iter().enumerate() {
+            t.place(
+                &process("n0", Some(rank as u32), rank as u64),
+                format!("n0:{}", rank),
+            )
+            .unwrap();
+            assert_eq!(last_parent(&t), *parent);
+        }
+
+        assert_eq!(t.shape(), (3, 2));
+    }
+
+    #[test]
+    fn early_ranks_take_the_closest_parent() {
+        let mut t = topology(Placement::Rank, 2);
+
+        t.place(&process("n0", Some(0), 0), "n0:0".to_string())
+            .unwrap();
+
+        /* Rank 1 (the parent of rank 5) did not join yet, the root being rank -1 */
+        t.place(&process("n0", Some(5), 5), "n0:5".to_string())
+            .unwrap();
+        assert_eq!(last_parent(&t), "rank 0");
+
+        /* Rank 2 (the parent of rank 6) neither */
+        t.place(&process("n0", Some(6), 6), "n0:6".to_string())
+            .unwrap();
+        assert_eq!(last_parent(&t), "rank 5");
+    }
+
+    #[test]
+    fn hosts_get_one_aggregator() {
+        let mut t = topology(Placement::Host, 2);
+
+        let joins = [
+            ("a", "root", true),
+            ("a", "rank 0", false),
+            ("b", "root", true),
+            /* The root is full, aggregators go under the first aggregator */
+            ("c", "rank 0", true),
+            ("d", "rank 0", true),
+            ("e", "rank 2", true),
+            ("b", "rank 2", false),
+        ];
+
+        for (i, (host, parent, aggregator)) in joins.iter().enumerate() {
+            t.place(
+                &process(host, Some(i as u32), i as u64),
+                format!("{}:{}", host, i),
+            )
+            .unwrap();
+            assert_eq!(last_parent(&t), *parent);
+            assert_eq!(t.nodes().last().unwrap().aggregator, *aggregator);
+        }
+    }
+
+    #[test]
+    fn full_host_aggregator_refuses_processes() {
+        let mut t = topology(Placement::Host, 2);
+        let capacity = 2 + MAX_HOST_PROCESSES;
+
+        for pid in 0..=capacity {
+            t.place(&process("a", None, pid), format!("a:{}", pid))
+                .unwrap();
+        }
+
+        let overflow = process("a", None, capacity + 1);
+        assert!(t.place(&overflow, "a:overflow".to_string()).is_err());
+
+        /* Other hosts still have room */
+        t.place(&process("b", None, 0), "b:0".to_string()).unwrap();
+        assert_eq!(last_parent(&t), "root");
+    }
+}
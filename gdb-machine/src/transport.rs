@@ -0,0 +1,275 @@
+//! Streams carrying the links between tree nodes
+//!
+//! Links are plain TCP connections or TLS over TCP when the
+//! session is encrypted (see [crate::tls]). Nodes also listen on a Unix
+//! socket (abstract on Linux) which is preferred by peers of the same host,
+//! links over Unix sockets do not leave the host and are never encrypted.
+//!
+//! The URL of a node is `host:port` followed by `,unix=NAME` when it
+//! listens on a Unix socket (NAME starting with @ for abstract sockets),
+//! URLs can thus be passed to a shell without quotes.
+
+use std::env;
+use std::io::{ErrorKind, Read, Result, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::{self, UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use rustls::{ClientConnection, ServerConnection, StreamOwned};
+
+use crate::tls::TlsSession;
+
+/* Set to 0 to only use TCP */
+const ENV_UNIX_SOCKETS: &str = "GDBW_UNIX_SOCKETS";
+
+/// Separates the TCP address of a node from its Unix socket in its URL
+const UNIX_URL_SEPARATOR: &str = ",unix=";
+
+fn unix_sockets_enabled() -> bool {
+    !matches!(env::var(ENV_UNIX_SOCKETS), Ok(v) if v == "0")
+}
+
+fn local_hostname() -> String {
+    gethostname::gethostname().to_string_lossy().to_string()
+}
+
+#[cfg(target_os = "linux")]
+fn unix_addr(name: &str) -> Result<net::SocketAddr> {
+    use std::os::linux::net::SocketAddrExt;
+
+    match name.strip_prefix('@') {
+        Some(abs) => net::SocketAddr::from_abstract_name(abs),
+        None => net::SocketAddr::from_pathname(name),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unix_addr(name: &str) -> Result<net::SocketAddr> {
+    net::SocketAddr::from_pathname(name)
+}
+
+/// Name of the Unix socket of the node listening on TCP `port`
+fn unix_name(port: u16) -> String {
+    let name = format!("snapped-{}-{}", std::process::id(), port);
+
+    if cfg!(target_os = "linux") {
+        return format!("@{}", name);
+    }
+
+    let path: PathBuf = env::temp_dir().join(format!("{}.sock", name));
+    /* A stale socket of a previous process with the same pid */
+    let _ = std::fs::remove_file(&path);
+    path.to_string_lossy().to_string()
+}
+
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    TlsClient(Box<StreamOwned<ClientConnection, TcpStream>>),
+    TlsServer(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl Stream {
+    /// Connect to the node with the given URL, over its
+    /// Unix socket if it runs on this host
+    pub(crate) fn connect(url: &str) -> anyhow::Result<Stream> {
+        let (addr, unix) = match url.split_once(UNIX_URL_SEPARATOR) {
+            Some((addr, unix)) => (addr, Some(unix)),
+            None => (url, None),
+        };
+
+        let same_host = addr
+            .rsplit_once(':')
+            .is_some_and(|(host, _)| host == local_hostname());
+
+        if let Some(name) = unix.filter(|_| same_host && unix_sockets_enabled()) {
+            /* The peer may be in another network namespace */
+            match unix_addr(name).and_then(|a| UnixStream::connect_addr(&a)) {
+                Ok(sock) => return Ok(Stream::Unix(sock)),
+                Err(e) => log::debug!("Falling back to TCP for {}: {}", url, e),
+            }
+        }
+
+        let sock = TcpStream::connect(addr)?;
+
+        Ok(match TlsSession::get()? {
+            Some(tls) => {
+                let conn = ClientConnection::new(tls.client.clone(), TlsSession::server_name())?;
+                Stream::TlsClient(Box::new(StreamOwned::new(conn, sock)))
+            }
+            None => Stream::Tcp(sock),
+        })
+    }
+
+    /// Wrap a TCP connection accepted by a listening node
+    fn accept(sock: TcpStream) -> anyhow::Result<Stream> {
+        Ok(match TlsSession::get()? {
+            Some(tls) => {
+                let conn = ServerConnection::new(tls.server()?)?;
+                Stream::TlsServer(Box::new(StreamOwned::new(conn, sock)))
+            }
+            None => Stream::Tcp(sock),
+        })
+    }
+
+    /// Bound the time spent waiting for the peer (None to wait forever)
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_read_timeout(timeout),
+            Stream::Unix(s) => s.set_read_timeout(timeout),
+            Stream::TlsClient(s) => s.sock.set_read_timeout(timeout),
+            Stream::TlsServer(s) => s.sock.set_read_timeout(timeout),
+        }
+    }
+
+    /// Switch to non-blocking I/O for an event loop
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_nonblocking(nonblocking),
+            Stream::Unix(s) => s.set_nonblocking(nonblocking),
+            Stream::TlsClient(s) => s.sock.set_nonblocking(nonblocking),
+            Stream::TlsServer(s) => s.sock.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Tcp(s) => s.as_raw_fd(),
+            Stream::Unix(s) => s.as_raw_fd(),
+            Stream::TlsClient(s) => s.sock.as_raw_fd(),
+            Stream::TlsServer(s) => s.sock.as_raw_fd(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            Stream::Unix(s) => s.read(buf),
+            Stream::TlsClient(s) => s.read(buf),
+            Stream::TlsServer(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            Stream::Unix(s) => s.write(buf),
+            Stream::TlsClient(s) => s.write(buf),
+            Stream::TlsServer(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            Stream::Unix(s) => s.flush(),
+            Stream::TlsClient(s) => s.flush(),
+            Stream::TlsServer(s) => s.flush(),
+        }
+    }
+}
+
+/// Sockets a node listens on for links
+pub(crate) struct Listener {
+    tcp: TcpListener,
+    /// Socket for the peers of the same host with its name
+    unix: Option<(UnixListener, String)>,
+}
+
+impl Listener {
+    pub(crate) fn bind(addr: SocketAddr) -> anyhow::Result<Listener> {
+        let tcp = TcpListener::bind(addr)?;
+        let mut unix = None;
+
+        if unix_sockets_enabled() {
+            let name = unix_name(tcp.local_addr()?.port());
+
+            match unix_addr(&name).and_then(|a| UnixListener::bind_addr(&a)) {
+                Ok(sock) => unix = Some((sock, name)),
+                Err(e) => log::warn!("Only listening on TCP, cannot bind {}: {}", name, e),
+            }
+        }
+
+        Ok(Listener { tcp, unix })
+    }
+
+    /// URL of this node for peers to connect to it
+    pub(crate) fn url(&self, host: &str) -> anyhow::Result<String> {
+        let mut ret = format!("{}:{}", host, self.tcp.local_addr()?.port());
+
+        if let Some((_, name)) = &self.unix {
+            ret.push_str(UNIX_URL_SEPARATOR);
+            ret.push_str(name);
+        }
+
+        Ok(ret)
+    }
+
+    /// Sockets to watch for connections, the Unix socket (if any) coming second
+    pub(crate) fn fds(&self) -> Vec<RawFd> {
+        let mut ret = vec![self.tcp.as_raw_fd()];
+        ret.extend(self.unix.iter().map(|(sock, _)| sock.as_raw_fd()));
+        ret
+    }
+
+    /// Only return pending connections from [Listener::accept]
+    pub(crate) fn set_nonblocking(&self) -> Result<()> {
+        self.tcp.set_nonblocking(true)?;
+
+        if let Some((sock, _)) = &self.unix {
+            sock.set_nonblocking(true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Next pending connection on the socket at `index` in [Listener::fds],
+    /// None if there is none
+    pub(crate) fn accept(&self, index: usize) -> anyhow::Result<Option<Stream>> {
+        let ret = match (index, &self.unix) {
+            (0, _) => self.tcp.accept().map(|(sock, _)| Stream::accept(sock)),
+            (_, Some((unix, _))) => unix.accept().map(|(sock, _)| Ok(Stream::Unix(sock))),
+            (_, None) => return Ok(None),
+        };
+
+        match ret {
+            Ok(stream) => Ok(Some(stream?)),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_host_peers_use_the_unix_socket() {
+        let listener = Listener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let url = listener.url(&local_hostname()).unwrap();
+
+        /* Nothing a shell would interpret */
+        assert!(!url.contains([';', ' ', '&', '|']));
+
+        match Stream::connect(&url).unwrap() {
+            Stream::Unix(_) => {}
+            _ => panic!("{} was not reached over its Unix socket", url),
+        }
+
+        /* Only the TCP part is used for another host */
+        let port = url.split_once(UNIX_URL_SEPARATOR).unwrap().0;
+        let port = port.rsplit_once(':').unwrap().1;
+        let remote = format!("127.0.0.1:{}{}@nowhere", port, UNIX_URL_SEPARATOR);
+
+        assert!(matches!(Stream::connect(&remote).unwrap(), Stream::Tcp(_)));
+    }
+}
@@ -0,0 +1,190 @@
+//! Pseudo terminal on which inferiors are run
+//!
+//! The inferior output is read from the master side of the terminal,
+//! echoed tagged with the process it comes from and its last lines are kept
+//! so that they can be shown under the stacks of the snapshot.
+
+use std::collections::VecDeque;
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use anyhow::{anyhow, Result};
+
+/// Number of output lines kept per process
+pub const OUTPUT_LINES: usize = 16;
+
+pub struct InferiorTty {
+    path: String,
+    lines: Arc<Mutex<VecDeque<String>>>,
+    /* Kept open for reads on the master not to fail when the inferior
+    has not opened the terminal yet, closed once it has (`close_slave`) */
+    slave: Option<File>,
+    _reader: JoinHandle<()>,
+}
+
+impl InferiorTty {
+    /// Open a new terminal, output lines are echoed prefixed by `tag`
+    pub fn new(tag: String) -> Result<InferiorTty> {
+        let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+
+        if fd < 0 {
+            return Err(anyhow!(
+                "Failed to open a pseudo terminal: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+
+        let master = unsafe { File::from_raw_fd(fd) };
+
+        if unsafe { libc::grantpt(master.as_raw_fd()) } != 0
+            || unsafe { libc::unlockpt(master.as_raw_fd()) } != 0
+        {
+            return Err(anyhow!(
+                "Failed to unlock pseudo terminal: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+
+        let mut name: [libc::c_char; 128] = [0; 128];
+
+        if unsafe { libc::ptsname_r(master.as_raw_fd(), name.as_mut_ptr(), name.len()) } != 0 {
+            return Err(anyhow!("Failed to get pseudo terminal name"));
+        }
+
+        let path = unsafe { CStr::from_ptr(name.as_ptr()) }
+            .to_string_lossy()
+            .to_string();
+
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)?;
+
+        let lines = Arc::new(Mutex::new(VecDeque::new()));
+
+        let plines = lines.clone();
+        let reader = thread::spawn(move || InferiorTty::read_loop(master, plines, tag));
+
+        Ok(InferiorTty {
+            path,
+            lines,
+            slave: Some(slave),
+            _reader: reader,
+        })
+    }
+
+    /// Close our side of the terminal once the inferior holds it, the
+    /// reader then stops when the last process using it exits
+    pub fn close_slave(&mut self) {
+        self.slave = None;
+    }
+
+    /// Keep `line` among the last [OUTPUT_LINES] ones
+    pub(crate) fn push_line(lines: &mut VecDeque<String>, line: String) {
+        if lines.len() == OUTPUT_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    fn read_loop(master: File, lines: Arc<Mutex<VecDeque<String>>>, tag: String) {
+        let reader = BufReader::new(master);
+
+        /* Once all slaves are closed Linux fails reads with EIO */
+        for line in reader.split(b'\n') {
+            let line = match line {
+                Ok(l) => l,
+                Err(_) => break,
+            };
+
+            /* The terminal translates \n to \r\n */
+            let line = String::from_utf8_lossy(&line)
+                .trim_end_matches('\r')
+                .to_string();
+
+            let _ = writeln!(std::io::stdout(), "[{}] {}", tag, line);
+
+            if let Ok(l) = lines.lock().as_mut() {
+                InferiorTty::push_line(l, line);
+            }
+        }
+    }
+
+    /// Path of the terminal to give to GDB
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Last output lines of the inferior
+    pub fn lines(&self) -> Vec<String> {
+        match self.lines.lock() {
+            Ok(l) => l.iter().cloned().collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn last_lines_are_kept() {
+        let mut lines = VecDeque::new();
+
+        for i in 0..OUTPUT_LINES {
+            InferiorTty::push_line(&mut lines, i.to_string());
+        }
+        assert_eq!(lines.len(), OUTPUT_LINES);
+        assert_eq!(lines.front().unwrap(), "0");
+
+        /* The oldest lines are dropped as new ones come */
+        for i in OUTPUT_LINES..OUTPUT_LINES + 3 {
+            InferiorTty::push_line(&mut lines, i.to_string());
+        }
+        assert_eq!(lines.len(), OUTPUT_LINES);
+        assert_eq!(lines.front().unwrap(), "3");
+        assert_eq!(lines.back().unwrap(), &(OUTPUT_LINES + 2).to_string());
+    }
+
+    #[test]
+    fn terminal_output_wraps_around() {
+        let mut tty = InferiorTty::new("test".to_string()).unwrap();
+
+        /* Stands for the inferior */
+        let mut inferior = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(tty.path())
+            .unwrap();
+
+        for i in 0..OUTPUT_LINES + 4 {
+            writeln!(inferior, "line {}", i).unwrap();
+        }
+
+        tty.close_slave();
+        drop(inferior);
+
+        /* The reader stops once no process holds the terminal */
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !tty._reader.is_finished() {
+            assert!(Instant::now() < deadline, "No end of output seen");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let lines = tty.lines();
+        assert_eq!(lines.len(), OUTPUT_LINES);
+        assert_eq!(lines[0], "line 4");
+        assert_eq!(
+            lines[OUTPUT_LINES - 1],
+            format!("line {}", OUTPUT_LINES + 3)
+        );
+    }
+}
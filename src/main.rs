@@ -21,13 +21,46 @@
 //! Naturally, the same syntax applies to parallel runs using `srun`:
 //!
 //! ̀`snapped -p 1000 srun -n 1000 -p rome ./snapped a.out`
+//!
+//! # Attach Usage
+//!
+//! Already running processes can be snapshotted and then left running:
+//!
+//! `snapped -a 1234,1235`
+//!
+//! # Remote Usage
+//!
+//! A gdbserver running next to the application can be driven instead of a local
+//! gdb, also when running as a leaf of a parallel run:
+//!
+//! `snapped -g node12:1234`
+//!
+//! A gdbserver started with `--multi` and holding no process runs the command:
+//!
+//! `snapped -g node12:1234 /remote/path/a.out`
+//!
+//! # Post-mortem Usage
+//!
+//! Core files (a single one or a directory with one per rank) can be merged
+//! in the same tree, the command is the executable which produced them:
+//!
+//! `snapped -c ./cores ./a.out`
 
 use anyhow::{anyhow, Result};
+use clap::builder::{PossibleValuesParser, TypedValueParser};
 use clap::Parser;
 use colored::*;
+use gdb_machine::auth;
+use gdb_machine::config::{parse_substitution, DebuggerConfig};
 use gdb_machine::debugger::Debugger;
+use gdb_machine::gdbmi::GdbMiRemote;
+use gdb_machine::metadata::{SnapshotOptions, WatchKind};
+use gdb_machine::tls;
+use gdb_machine::topology::{Placement, TopologyOptions};
 use gdb_machine::{GdbMachine, RootDebugger};
+use regex::Regex;
 use render::Renderer;
+use std::path::{Path, PathBuf};
 use std::process::{exit, Command, Stdio};
 use std::sync::Mutex;
 use std::time::Duration;
@@ -90,6 +123,87 @@ struct Arguments {
     /// Should the program act as a GDB server
     #[arg(short, long)]
     pivot_processes: Option<usize>,
+    /// Encrypt the links between processes with TLS
+    #[arg(long)]
+    tls: bool,
+    /// Number of children of each process of the tree
+    #[arg(long, default_value_t = TopologyOptions::default().arity)]
+    arity: u64,
+    /// How processes are placed in the tree
+    #[arg(long, default_value_t = TopologyOptions::default().placement, value_parser = placement_parser())]
+    placement: Placement,
+    /// Print the tree of processes once built
+    #[arg(long)]
+    print_topology: bool,
+    /// Seconds given to the processes to answer a command, the others are reported as not responding
+    #[arg(long, value_name = "SECS", default_value_t = 120)]
+    timeout: u64,
+    /// Attach to running processes (comma separated PIDs) snapshot and detach
+    #[arg(short, long, value_delimiter = ',', num_args = 1..)]
+    attach: Option<Vec<u32>>,
+    /// Drive a gdbserver already running next to the application (host:port), running the command if it holds no process
+    #[arg(short, long)]
+    gdbserver: Option<String>,
+    /// Analyze a core file or a directory of core files produced by the command
+    #[arg(short, long)]
+    cores: Option<PathBuf>,
+    /// Capture arguments and local variables of the innermost frames
+    #[arg(short, long)]
+    locals: bool,
+    /// Number of innermost frames for which variables are captured
+    #[arg(long, default_value_t = 1)]
+    locals_frames: u32,
+    /// Nesting depth of aggregates in captured values
+    #[arg(long, default_value_t = 2)]
+    locals_depth: u32,
+    /// Maximum length of a captured value
+    #[arg(long, default_value_t = 64)]
+    locals_length: usize,
+    /// List the functions matching a regex on all processes once stopped
+    #[arg(long, value_name = "REGEX", value_parser = function_pattern)]
+    find_function: Option<String>,
+    /// Split the stacks by thread name (e.g. to tell MPI progress threads from OpenMP workers)
+    #[arg(long)]
+    group_by_thread_name: bool,
+    /// Set a breakpoint before starting (e.g. file.c:42 or a function name)
+    #[arg(short, long = "break", value_name = "LOCATION")]
+    breakpoint: Vec<String>,
+    /// Set a watchpoint on an expression before starting
+    #[arg(short, long, value_name = "EXPR")]
+    watch: Vec<String>,
+    /// Process to debug after a fork
+    #[arg(long, value_parser = ["parent", "child"])]
+    follow_fork: Option<String>,
+    /// Keep debugging both processes after a fork (each one is an inferior)
+    #[arg(long)]
+    keep_forks: bool,
+    /// Inferior to use after an exec (new creates a new inferior)
+    #[arg(long, value_parser = ["same", "new"])]
+    follow_exec: Option<String>,
+    /// Debugger driving the processes
+    #[arg(long, value_parser = ["gdb", "lldb"])]
+    debugger: Option<String>,
+    /// GDB executable to use on all processes
+    #[arg(long, value_name = "PATH")]
+    gdb: Option<String>,
+    /// LLDB executable to use on all processes (with --debugger lldb)
+    #[arg(long, value_name = "PATH")]
+    lldb: Option<String>,
+    /// Debugger script sourced at startup on all processes
+    #[arg(long, value_name = "SCRIPT")]
+    gdb_init: Option<String>,
+    /// GDB sysroot (target libraries prefix)
+    #[arg(long, value_name = "PATH")]
+    sysroot: Option<String>,
+    /// GDB solib-search-path (colon separated directories)
+    #[arg(long, value_name = "PATHS")]
+    solib_search_path: Option<String>,
+    /// GDB debug-file-directory (separate debug information)
+    #[arg(long, value_name = "PATH")]
+    debug_file_directory: Option<String>,
+    /// Source path substitution rule (can be repeated)
+    #[arg(long, value_name = "FROM=TO", value_parser = substitution_rule)]
+    substitute_path: Vec<(String, String)>,
     #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
     command: Option<Vec<String>>,
 }
@@ -102,7 +216,178 @@ fn timer_print(text: &str, start: Instant) {
     ));
 }
 
-fn run_in_snapshot_mode(dbg: &mut impl Debugger) -> Result<()> {
+fn gdbserver_target(server: &str, program: Option<Vec<String>>) -> Result<GdbMiRemote> {
+    if let Some((host, port)) = server.rsplit_once(':') {
+        if let Ok(port) = port.parse::<u32>() {
+            return Ok(GdbMiRemote::Server(host.to_string(), port, program));
+        }
+    }
+
+    Err(anyhow!(
+        "gdbserver should be given as host:port got {}",
+        server
+    ))
+}
+
+/// Root debugger to join, from the arguments or the environment
+fn root_server(args: &Arguments) -> Option<String> {
+    args.root_server
+        .clone()
+        .or_else(|| env::var("GDBW_ROOT_SERVER").ok())
+}
+
+/// Target to debug when running locally or as a leaf
+fn debug_target(args: &Arguments) -> Result<Option<GdbMiRemote>> {
+    if let Some(server) = &args.gdbserver {
+        /* The command is run by the gdbserver if it holds no process */
+        return Ok(Some(gdbserver_target(server, args.command.clone())?));
+    }
+
+    Ok(args
+        .command
+        .as_ref()
+        .map(|cmd| GdbMiRemote::Command(cmd.clone())))
+}
+
+fn snapshot_options(args: &Arguments) -> SnapshotOptions {
+    SnapshotOptions {
+        locals: args.locals,
+        max_frames: args.locals_frames,
+        max_depth: args.locals_depth,
+        max_string: args.locals_length,
+        group_by_thread_name: args.group_by_thread_name,
+    }
+}
+
+/// Placements as named by the topology
+fn placement_parser() -> impl TypedValueParser<Value = Placement> {
+    PossibleValuesParser::new(Placement::ALL.map(|p| p.name())).try_map(|name| name.parse())
+}
+
+fn topology_options(args: &Arguments) -> TopologyOptions {
+    TopologyOptions {
+        placement: args.placement,
+        arity: args.arity,
+        size: args.pivot_processes.unwrap_or(0) as u64,
+    }
+}
+
+fn substitution_rule(rule: &str) -> Result<(String, String)> {
+    parse_substitution(rule).ok_or(anyhow!(
+        "substitution should be given as FROM=TO got {}",
+        rule
+    ))
+}
+
+fn function_pattern(pattern: &str) -> Result<String> {
+    Regex::new(pattern)?;
+    Ok(pattern.to_string())
+}
+
+/// Debugger configuration, leaves inherit the one exported by the root
+fn debugger_config(args: &Arguments) -> DebuggerConfig {
+    let mut config = DebuggerConfig::from_env();
+
+    if let Some(mode) = &args.follow_fork {
+        config.follow_fork = Some(mode.clone());
+    }
+
+    if args.keep_forks {
+        config.keep_forks = true;
+    }
+
+    if let Some(mode) = &args.follow_exec {
+        config.follow_exec = Some(mode.clone());
+    }
+
+    if let Some(debugger) = &args.debugger {
+        config.debugger = Some(debugger.clone());
+    }
+
+    if let Some(gdb) = &args.gdb {
+        config.gdb = Some(gdb.clone());
+    }
+
+    if let Some(lldb) = &args.lldb {
+        config.lldb = Some(lldb.clone());
+    }
+
+    if let Some(script) = &args.gdb_init {
+        config.init_script = Some(script.clone());
+    }
+
+    if let Some(path) = &args.sysroot {
+        config.sysroot = Some(path.clone());
+    }
+
+    if let Some(paths) = &args.solib_search_path {
+        config.solib_search_path = Some(paths.clone());
+    }
+
+    if let Some(path) = &args.debug_file_directory {
+        config.debug_file_directory = Some(path.clone());
+    }
+
+    if !args.substitute_path.is_empty() {
+        config.substitute_path = args.substitute_path.clone();
+    }
+
+    config
+}
+
+fn set_breakpoints(dbg: &mut impl Debugger, args: &Arguments) -> Result<()> {
+    for location in args.breakpoint.iter() {
+        let num = dbg.set_breakpoint(location)?;
+        snap_log(&format!("Breakpoint {} at {}", num, location));
+    }
+
+    for expr in args.watch.iter() {
+        let num = dbg.set_watchpoint(expr, WatchKind::Write)?;
+        snap_log(&format!("Watchpoint {} on {}", num, expr));
+    }
+
+    Ok(())
+}
+
+/// Search the functions of all processes (which must be stopped)
+fn print_functions(dbg: &mut impl Debugger, pattern: Option<&str>) {
+    let Some(pattern) = pattern else {
+        return;
+    };
+
+    let bsym = Instant::now();
+    match dbg.find_functions(pattern) {
+        Ok(table) => {
+            timer_print("Searched functions", bsym);
+            Renderer::print_functions(pattern, &table);
+        }
+        Err(e) => log::warn!("Could not search functions: {}", e),
+    }
+}
+
+/// Snapshot the stopped processes and print the tree (and the functions found)
+fn print_snapshot(
+    dbg: &mut impl Debugger,
+    opts: &SnapshotOptions,
+    find: Option<&str>,
+) -> Result<()> {
+    let bsnap = Instant::now();
+    let snap = dbg.snapshot(opts)?;
+    timer_print("Collected backtraces", bsnap);
+
+    let render = Renderer::new(snap);
+    render.print_tree()?;
+
+    print_functions(dbg, find);
+
+    Ok(())
+}
+
+fn run_in_snapshot_mode(
+    dbg: &mut impl Debugger,
+    opts: &SnapshotOptions,
+    find: Option<&str>,
+) -> Result<()> {
     let bstart = Instant::now();
     dbg.start()?;
     timer_print("Started processes", bstart);
@@ -111,7 +396,10 @@ fn run_in_snapshot_mode(dbg: &mut impl Debugger) -> Result<()> {
         if !dbg.all_running()? || interrupted() {
             /* Stop all  */
             let bstop = Instant::now();
-            dbg.stop()?;
+            /* The processes which did not answer are listed in the snapshot */
+            if let Err(e) = dbg.stop() {
+                snap_log(&format!("Failed to stop all processes: {}", e));
+            }
             timer_print("Stopped processes", bstop);
 
             break;
@@ -119,23 +407,83 @@ fn run_in_snapshot_mode(dbg: &mut impl Debugger) -> Result<()> {
         thread::sleep(Duration::from_millis(500));
     }
 
-    let bsnap = Instant::now();
-    let snap = dbg.snapshot()?;
-    timer_print("Collected backtraces", bsnap);
+    /* The last output of the processes is shown under their stacks */
+    print_snapshot(dbg, opts, find)
+}
 
-    let render = Renderer::new(snap);
-    render.print_tree()?;
+fn run_in_attach_mode(
+    dbg: &mut impl Debugger,
+    opts: &SnapshotOptions,
+    find: Option<&str>,
+) -> Result<()> {
+    /* Processes are stopped by the attach, leave them running even if the snapshot failed */
+    let ret = print_snapshot(dbg, opts, find);
 
-    Ok(())
+    let bdetach = Instant::now();
+    dbg.detach()?;
+    timer_print("Detached from processes", bdetach);
+
+    ret
+}
+
+fn run_in_postmortem_mode(
+    dbg: &mut impl Debugger,
+    opts: &SnapshotOptions,
+    find: Option<&str>,
+) -> Result<()> {
+    print_snapshot(dbg, opts, find)
 }
 
-fn be_root_server(child_count: usize, cmd: &Option<Vec<String>>) -> Result<RootDebugger> {
-    let (srv, mut rdbg) = GdbMachine::run_as_root()?;
+/// Sort key of a core file, its name then its trailing number (pid or rank)
+fn core_order(core: &str) -> (String, Option<u64>) {
+    let digits = core.len() - core.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+    let (name, num) = core.split_at(core.len() - digits);
+
+    (name.to_string(), num.parse().ok())
+}
+
+fn core_files(path: &Path) -> Result<Vec<String>> {
+    if path.is_file() {
+        return Ok(vec![path.to_string_lossy().to_string()]);
+    }
+
+    let mut ret: Vec<String> = std::fs::read_dir(path)?
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| p.is_file())
+        .map(|p| p.to_string_lossy().to_string())
+        .collect();
+
+    if ret.is_empty() {
+        return Err(anyhow!("No core file found in {}", path.display()));
+    }
+
+    /* core.10 comes after core.2 */
+    ret.sort_by_key(|c| core_order(c));
+
+    Ok(ret)
+}
+
+fn be_root_server(
+    child_count: usize,
+    cmd: &Option<Vec<String>>,
+    config: &DebuggerConfig,
+    topology: &TopologyOptions,
+    tls: bool,
+) -> Result<RootDebugger> {
+    if tls {
+        /* Before the server starts as it reads it from the environment */
+        tls::export_session_ca()?;
+    }
+
+    let (srv, mut rdbg) = GdbMachine::run_as_root(topology)?;
 
     snap_log(&format!("root server is running on {}", srv.url()?));
 
     if let Some(command) = cmd {
         env::set_var("GDBW_ROOT_SERVER", srv.url()?);
+        auth::export_token()?;
+        config.export_env();
 
         let child = Command::new(&command[0])
             .args(&command[1..])
@@ -143,6 +491,23 @@ fn be_root_server(child_count: usize, cmd: &Option<Vec<String>>) -> Result<RootD
             .stdout(Stdio::inherit())
             .spawn()?;
         rdbg.set_child(child);
+    } else {
+        /* Processes started by hand must share the token and the CA */
+        auth::export_token()?;
+
+        let mut vars = vec![auth::ENV_TOKEN];
+
+        /* The CA key stays here, joining processes have their certificate signed */
+        if tls {
+            vars.push(tls::ENV_TLS_CA);
+        }
+
+        snap_log("join with the following environment:");
+
+        /* PEM blocks hold no quotes, they are kept on several lines */
+        for var in vars {
+            println!("export {}='{}'", var, env::var(var)?);
+        }
     }
     let bstart = Instant::now();
     srv.wait_for_child(child_count)?;
@@ -171,34 +536,82 @@ fn main() -> Result<()> {
 
     //if let Some(target) = args.target_server {}
 
-    /* Get root server either from env */
-    let root_server = if let Some(root) = args.root_server {
-        Some(root)
-    } else if let Ok(env) = env::var("GDBW_ROOT_SERVER") {
-        Some(env)
-    } else {
-        None
-    };
+    let root_server = root_server(&args);
+
+    let config = debugger_config(&args);
+
+    if let Some(pids) = &args.attach {
+        let targets = pids.iter().map(|p| GdbMiRemote::Attach(*p)).collect();
+        let mut dbg = GdbMachine::local(targets, &config)?;
+        run_in_attach_mode(
+            &mut dbg,
+            &snapshot_options(&args),
+            args.find_function.as_deref(),
+        )?;
+        return Ok(());
+    }
+
+    if let Some(cores) = &args.cores {
+        let exe = match args.command.as_ref().and_then(|c| c.first()) {
+            Some(exe) => exe,
+            None => {
+                return Err(anyhow!(
+                    "You need to pass the executable which produced the core files"
+                ))
+            }
+        };
+
+        let targets = core_files(cores)?
+            .into_iter()
+            .map(|c| GdbMiRemote::Core(exe.to_string(), c))
+            .collect();
+        let mut dbg = GdbMachine::local(targets, &config)?;
+        run_in_postmortem_mode(
+            &mut dbg,
+            &snapshot_options(&args),
+            args.find_function.as_deref(),
+        )?;
+        return Ok(());
+    }
 
     if let Some(root) = root_server {
-        if let Some(command) = &args.command {
-            GdbMachine::run_as_leaf(root, command)?;
+        if let Some(target) = debug_target(&args)? {
+            GdbMachine::run_as_leaf(root, target, &config)?;
         } else {
             return Err(anyhow!(
-                "You need to pass a command when running as non-root server"
+                "You need to pass a command or a gdbserver when running as non-root server"
             ));
         }
     }
 
     if let Some(count_proc) = args.pivot_processes {
         /* Server MODE */
-        let mut srv = be_root_server(count_proc, &args.command)?;
-        run_in_snapshot_mode(&mut srv)?;
+        let topology = topology_options(&args);
+        let mut srv = be_root_server(count_proc, &args.command, &config, &topology, args.tls)?;
+
+        if args.print_topology {
+            if let Some((nodes, shape)) = srv.topology() {
+                Renderer::print_topology(&nodes, shape)?;
+            }
+        }
+
+        srv.set_timeout(Duration::from_secs(args.timeout));
+        set_breakpoints(&mut srv, &args)?;
+        run_in_snapshot_mode(
+            &mut srv,
+            &snapshot_options(&args),
+            args.find_function.as_deref(),
+        )?;
         srv.kill_child();
-    } else if let Some(cmd) = &args.command {
+    } else if let Some(target) = debug_target(&args)? {
         /* If we are here we are not doing Client / Server we launch locally */
-        let mut dbg = GdbMachine::local(cmd)?;
-        run_in_snapshot_mode(&mut dbg)?;
+        let mut dbg = GdbMachine::local(vec![target], &config)?;
+        set_breakpoints(&mut dbg, &args)?;
+        run_in_snapshot_mode(
+            &mut dbg,
+            &snapshot_options(&args),
+            args.find_function.as_deref(),
+        )?;
         dbg.kill_child();
     }
 
@@ -1,9 +1,23 @@
-use std::{collections::HashMap, io::Read, path::PathBuf, str::FromStr};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io::Read,
+    path::PathBuf,
+    str::FromStr,
+};
 
 use anyhow::Result;
 use ascii_tree::{write_tree, Tree};
 use colored::*;
-use gdb_machine::metadata::{BacktraceState, DisplayFrame, DisplayState};
+use gdb_machine::metadata::{
+    BacktraceState, DisplayFrame, DisplayInferior, DisplayMissing, DisplayOutput, DisplayState,
+    DisplayThread, DisplayVariable, FaultContext, SymbolTable,
+};
+use gdb_machine::topology::TopologyNode;
+
+/// Registers printed on each line of a fault context
+const REGISTERS_PER_LINE: usize = 4;
+/// Processes listed under a missing answer
+const MAX_MISSING_TAGS: usize = 16;
 
 fn line_from_src(spath: &Option<String>, line: &Option<u32>) -> Option<String> {
     if let (Some(spath), Some(line)) = (spath, line) {
@@ -81,17 +95,141 @@ impl FrameTree {
         format!("{}{}{}", func_str, loc_str, line)
     }
 
+    fn descriptor_variable(v: &DisplayVariable) -> String {
+        let kind = if v.arg { "arg" } else { "local" };
+
+        let values = if v.values.len() == 1 {
+            v.values[0].0.clone()
+        } else {
+            let mut values = v.values.clone();
+            values.sort_by_key(|v| std::cmp::Reverse(v.1));
+            values
+                .iter()
+                .map(|(val, cnt)| format!("{} (x{})", val, cnt))
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+
+        format!(
+            "{} {} = {}",
+            kind.truecolor(100, 100, 100),
+            v.name.yellow(),
+            values.truecolor(180, 180, 180)
+        )
+    }
+
+    fn descriptor_fault(f: &FaultContext) -> Vec<String> {
+        let mut ret: Vec<String> = f
+            .registers
+            .chunks(REGISTERS_PER_LINE)
+            .map(|regs| {
+                regs.iter()
+                    .map(|(name, value)| {
+                        format!(
+                            "{} {}",
+                            name.yellow(),
+                            format!("{:<18}", value).truecolor(180, 180, 180)
+                        )
+                    })
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            })
+            .collect();
+
+        for i in f.disassembly.iter() {
+            let location = match (&i.func, i.offset) {
+                (Some(func), Some(off)) => format!(" <{}+{}>", func, off),
+                _ => "".to_string(),
+            };
+
+            let line = format!("{}{}: {}", i.address, location, i.inst);
+
+            ret.push(match i.current {
+                true => format!("=> {}", line).bold().red().to_string(),
+                false => format!("   {}", line).truecolor(150, 150, 150).to_string(),
+            });
+        }
+
+        ret
+    }
+
     fn descriptor_stopstate(s: &DisplayState, _allow_code: bool) -> String {
+        let num = s.breakpoint_num.map(|v| v.to_string()).unwrap_or_default();
+
         let reason = match s.reason.as_str() {
+            "breakpoint-hit" => format!("Hit Breakpoint {}", num).bright_magenta(),
+            "watchpoint-trigger" | "read-watchpoint-trigger" | "access-watchpoint-trigger" => {
+                format!("Hit Watchpoint {}", num).bright_magenta()
+            }
             "exited" => "Exited Badly".bright_yellow(),
             "exited-normally" => "Exited Normally".green(),
             "signal-received" => "Received a Signal".red(),
+            "debugger-died" => "Debugger Died".bright_red(),
+            "no-response" => "No Response".bright_red(),
             other => other.red(),
         };
 
         format!("{}", reason.bold())
     }
 
+    fn descriptor_inferior(i: &DisplayInferior) -> String {
+        let exe = match &i.executable {
+            Some(exe) => format!(" {}", exe).magenta(),
+            None => "".to_string().normal(),
+        };
+
+        format!("{}{}", format!("Inferior {}", i.num).bold().yellow(), exe)
+    }
+
+    fn descriptor_missing(m: &DisplayMissing) -> String {
+        format!("{}", m.error.red())
+    }
+
+    fn descriptor_missing_processes(m: &DisplayMissing) -> String {
+        let mut names: Vec<&str> = m
+            .processes
+            .iter()
+            .take(MAX_MISSING_TAGS)
+            .map(|p| p.as_str())
+            .collect();
+
+        if m.processes.len() > MAX_MISSING_TAGS {
+            names.push("...");
+        }
+
+        names.join(", ")
+    }
+
+    fn descriptor_output(o: &DisplayOutput) -> String {
+        let last = match o.outputs.len() {
+            1 => "Last Output".to_string(),
+            n => format!("Last Outputs ({} distinct)", n),
+        };
+
+        format!("{}", last.bold().blue())
+    }
+
+    fn descriptor_output_lines(o: &DisplayOutput) -> Vec<String> {
+        let mut ret = Vec::new();
+
+        for (lines, cnt) in o.outputs.iter() {
+            if o.outputs.len() > 1 {
+                ret.push(format!("[{}]", cnt).truecolor(150, 150, 150).to_string());
+            }
+            for l in lines {
+                ret.push(format!("  {}", l));
+            }
+        }
+
+        ret
+    }
+
+    fn descriptor_thread(t: &DisplayThread) -> String {
+        let name = t.name.clone().unwrap_or("(unnamed)".to_string());
+
+        format!("{} {}", "Thread".bold().cyan(), name.cyan())
+    }
+
     fn descriptor(&self, max_counter: u64, allow_code: bool) -> String {
         let intensity = if max_counter != 0 {
             let normalized = self.counter as f32 / max_counter as f32;
@@ -126,6 +264,10 @@ impl FrameTree {
         let content = match &self.frame {
             BacktraceState::Frame(f) => FrameTree::descriptor_frame(f, allow_code),
             BacktraceState::State(s) => FrameTree::descriptor_stopstate(s, allow_code),
+            BacktraceState::Inferior(i) => FrameTree::descriptor_inferior(i),
+            BacktraceState::Thread(t) => FrameTree::descriptor_thread(t),
+            BacktraceState::Missing(m) => FrameTree::descriptor_missing(m),
+            BacktraceState::Output(o) => FrameTree::descriptor_output(o),
         };
 
         format!("{} {}", counter_str, content)
@@ -162,6 +304,13 @@ impl FrameTree {
                             line.truecolor(180, 180, 180).bold()
                         ))
                     }
+                    for v in f.vars.iter() {
+                        content.push(format!(
+                            "{}{}",
+                            " ".repeat(cnt_len),
+                            FrameTree::descriptor_variable(v)
+                        ))
+                    }
                 }
                 BacktraceState::State(s) => {
                     if let Some(sig) = &s.signal_name {
@@ -171,6 +320,18 @@ impl FrameTree {
                             sig.truecolor(180, 180, 180).bold()
                         ))
                     }
+                    if let Some(fault) = &s.fault {
+                        for l in FrameTree::descriptor_fault(fault) {
+                            content.push(format!("{}{}", " ".repeat(cnt_len), l))
+                        }
+                    }
+                    if let Some(detail) = &s.detail {
+                        content.push(format!(
+                            "{}{}",
+                            " ".repeat(cnt_len),
+                            detail.truecolor(180, 180, 180).bold()
+                        ))
+                    }
                     if let Some(exit_code) = &s.exit_code {
                         let exit = format!("Exit Code {}", exit_code);
                         content.push(format!(
@@ -180,6 +341,19 @@ impl FrameTree {
                         ))
                     }
                 }
+                BacktraceState::Missing(m) => content.push(format!(
+                    "{}{}",
+                    " ".repeat(cnt_len),
+                    FrameTree::descriptor_missing_processes(m)
+                        .truecolor(180, 180, 180)
+                        .bold()
+                )),
+                BacktraceState::Output(o) => {
+                    for l in FrameTree::descriptor_output_lines(o) {
+                        content.push(format!("{}{}", " ".repeat(cnt_len), l))
+                    }
+                }
+                BacktraceState::Inferior(_) | BacktraceState::Thread(_) => {}
             }
 
             return Tree::Leaf(content);
@@ -222,10 +396,14 @@ impl From<&HashMap<u64, (u64, Vec<BacktraceState>)>> for FrameTree {
 
         for (counter, backtraces) in components.values() {
             for frame in backtraces.iter().rev() {
-                current_node = current_node
-                    .child
-                    .entry(frame.get_hash())
-                    .or_insert(FrameTree::from(frame));
+                current_node = match current_node.child.entry(frame.get_hash()) {
+                    Entry::Occupied(e) => {
+                        let node = e.into_mut();
+                        node.frame.merge(frame);
+                        node
+                    }
+                    Entry::Vacant(e) => e.insert(FrameTree::from(frame)),
+                };
                 current_node.counter += counter;
             }
             /* Return to root */
@@ -260,4 +438,133 @@ impl Renderer {
 
         Ok(())
     }
+
+    /// Processes placed under the node `idx` of the topology
+    fn topology_subtree(nodes: &[TopologyNode], children: &[Vec<usize>], idx: usize) -> Tree {
+        let node = &nodes[idx];
+
+        let mut label = node.tag.bold().to_string();
+
+        if !node.hostname.is_empty() && !node.tag.starts_with(&node.hostname) {
+            label = format!("{} {}", label, node.hostname.truecolor(180, 180, 180));
+        }
+
+        if node.aggregator {
+            label = format!("{} {}", label, "(host aggregator)".cyan());
+        }
+
+        if children[idx].is_empty() {
+            return Tree::Leaf(vec![label]);
+        }
+
+        let sub = children[idx]
+            .iter()
+            .map(|c| Renderer::topology_subtree(nodes, children, *c))
+            .collect();
+
+        Tree::Node(label, sub)
+    }
+
+    /// Print the processes of the tree under their parent
+    pub fn print_topology(nodes: &[TopologyNode], (depth, fan_out): (usize, u64)) -> Result<()> {
+        let mut children = vec![Vec::new(); nodes.len()];
+
+        for (i, n) in nodes.iter().enumerate() {
+            if let Some(p) = n.parent {
+                children[p].push(i);
+            }
+        }
+
+        let mut out = String::new();
+        write_tree(&mut out, &Renderer::topology_subtree(nodes, &children, 0))?;
+
+        println!("{}", out);
+        println!(
+            "{} processes, depth {}, at most {} children per process\n",
+            nodes.len() - 1,
+            depth,
+            fan_out
+        );
+
+        Ok(())
+    }
+
+    /// Functions found on the processes grouped by file
+    pub fn print_functions(pattern: &str, table: &SymbolTable) {
+        println!(
+            "{} {} ({})",
+            "Functions matching".bold(),
+            pattern.bold(),
+            table.len()
+        );
+
+        let mut files: Vec<_> = table.symbols_per_file.iter().collect();
+        files.sort_by_key(|(f, _)| *f);
+
+        for (file, symbols) in files {
+            println!("  {}", file.blue());
+
+            let mut symbols: Vec<_> = symbols.iter().collect();
+            symbols.sort_by_key(|s| (s.line, &s.name));
+
+            for s in symbols {
+                let decl = s.description.as_ref().unwrap_or(&s.name);
+
+                match s.line {
+                    Some(line) => println!("    {:>6}: {}", line, decl),
+                    None => println!("    {}", decl),
+                }
+            }
+        }
+        println!();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gdb_machine::metadata::{ProgramSnapshot, SnapshotOptions};
+
+    #[test]
+    fn debugger_death_reason_is_shown() -> Result<()> {
+        let snapshots = HashMap::from([(0, ProgramSnapshot::dead("GDB was killed by SIGKILL"))]);
+        let components =
+            ProgramSnapshot::generate_components(snapshots, &SnapshotOptions::default());
+
+        let mut out = String::new();
+        write_tree(
+            &mut out,
+            &Renderer::new(components).astree().to_ascii_tree(),
+        )?;
+
+        assert!(out.contains("Debugger Died"));
+        assert!(out.contains("GDB was killed by SIGKILL"));
+        Ok(())
+    }
+
+    #[test]
+    fn outputs_are_shown_under_their_stack_group() -> Result<()> {
+        let opts = SnapshotOptions::default();
+        let process = |output: &str| {
+            let snapshots = HashMap::from([(0, ProgramSnapshot::dead("GDB crashed"))]);
+            let components = ProgramSnapshot::generate_components(snapshots, &opts);
+            ProgramSnapshot::attach_output(components, vec![output.to_string()])
+        };
+
+        /* Different outputs do not split the group */
+        let components =
+            ProgramSnapshot::components_merge(vec![process("iteration 7"), process("iteration 9")]);
+        assert_eq!(components.len(), 1);
+
+        let mut out = String::new();
+        write_tree(
+            &mut out,
+            &Renderer::new(components).astree().to_ascii_tree(),
+        )?;
+
+        assert!(out.contains("Last Outputs (2 distinct)"));
+        assert!(out.contains("iteration 7"));
+        assert!(out.contains("iteration 9"));
+        Ok(())
+    }
 }